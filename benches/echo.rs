@@ -0,0 +1,187 @@
+// Round trips against a local echo server over plain TCP and TLS. Criterion
+// reports the mean latency and messages/sec, the p50 and p99 latencies are
+// printed after each benchmark.
+//
+//   cargo bench --bench echo
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use mayuri::{
+    Context, StreamBuilder, Transport, WebSocket,
+    core::{
+        enums::Opcode,
+        testing::{Forward, MockConnection, MockServer},
+        utils::get_uri,
+    },
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpListener, runtime::Runtime, sync::mpsc::UnboundedReceiver};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ClientConfig, RootCertStore, ServerConfig,
+        crypto::aws_lc_rs::default_provider,
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    },
+};
+
+const PAYLOAD_SIZE: usize = 128;
+
+struct Client {
+    transport: Transport,
+    echoes: UnboundedReceiver<Context>,
+}
+
+impl Client {
+    async fn connect(builder: StreamBuilder) -> Self {
+        let (protocol, echoes) = Forward::new();
+        let mut ws = WebSocket::connect_with(builder, protocol).await.unwrap();
+        let transport = ws.transport();
+        tokio::spawn(async move { ws.run().await });
+        Self { transport, echoes }
+    }
+
+    async fn send(&mut self, payload: &[u8]) {
+        self.transport.write_binary(payload).await.unwrap();
+    }
+
+    async fn echoed(&mut self) {
+        self.echoes.recv().await.unwrap();
+    }
+}
+
+// Sends every data frame back until the client closes.
+async fn echo(mut connection: MockConnection) {
+    while let Ok(frame) = connection.recv().await {
+        if frame.opcode == Opcode::Close {
+            break;
+        }
+        if connection
+            .send_frame(frame.fin, frame.opcode as u8, &frame.payload)
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+async fn plain() -> Client {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None).unwrap();
+    tokio::spawn(async move { echo(server.accept().await.unwrap()).await });
+    Client::connect(builder).await
+}
+
+async fn tls() -> Client {
+    let key = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert = key.cert.der().clone();
+    let private_key =
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.signing_key.serialize_der()));
+
+    let provider = Arc::new(default_provider());
+    let server_config = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], private_key)
+        .unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let client_config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.unwrap();
+        echo(MockConnection::accept(stream).await.unwrap()).await;
+    });
+
+    let uri = get_uri(format!("wss://localhost:{port}/")).unwrap();
+    let builder = StreamBuilder::new(uri, None)
+        .unwrap()
+        .tls_config(Arc::new(client_config));
+    Client::connect(builder).await
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn echo_benches(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let payload = vec![0x2a; PAYLOAD_SIZE];
+
+    let mut group = c.benchmark_group("echo");
+    group.throughput(Throughput::Elements(1));
+    for transport in ["tcp", "tls"] {
+        let mut client = rt.block_on(async {
+            if transport == "tls" {
+                tls().await
+            } else {
+                plain().await
+            }
+        });
+
+        // One message in flight at a time.
+        let mut round_trips = Vec::new();
+        group.bench_function(BenchmarkId::new("latency", transport), |b| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        client.send(&payload).await;
+                        client.echoed().await;
+                        let elapsed = start.elapsed();
+                        round_trips.push(elapsed);
+                        total += elapsed;
+                    }
+                    total
+                })
+            });
+        });
+        round_trips.sort_unstable();
+        println!(
+            "echo/latency/{transport}: p50 {:?}, p99 {:?} over {} round trips",
+            percentile(&round_trips, 0.50),
+            percentile(&round_trips, 0.99),
+            round_trips.len()
+        );
+
+        // Sends every message before waiting for the echoes.
+        group.bench_function(BenchmarkId::new("throughput", transport), |b| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let start = Instant::now();
+                    let mut transport = client.transport.clone();
+                    let send = async {
+                        for _ in 0..iters {
+                            transport.write_binary(&payload).await.unwrap();
+                        }
+                    };
+                    let receive = async {
+                        for _ in 0..iters {
+                            client.echoed().await;
+                        }
+                    };
+                    tokio::join!(send, receive);
+                    start.elapsed()
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, echo_benches);
+criterion_main!(benches);
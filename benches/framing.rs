@@ -0,0 +1,125 @@
+// Encoding and decoding cost at payload sizes from empty to 16 MiB.
+//
+//   cargo bench --bench framing
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use mayuri::{
+    Context, Transport, WebSocket, WebSocketProtocol,
+    async_trait::async_trait,
+    core::{
+        enums::Opcode,
+        frame::{Frame, Headers},
+        testing::{MockConnection, MockServer, encode_frame},
+    },
+};
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+
+const SIZES: [usize; 7] = [0, 125, 126, 1 << 10, 64 << 10, 1 << 20, 16 << 20];
+
+fn payload(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8).collect()
+}
+
+// Fewer samples for the larger payloads, they take long enough on their own.
+fn sample_size(size: usize) -> usize {
+    if size >= 1 << 20 { 10 } else { 100 }
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for size in SIZES {
+        let frame = Frame::set_defaults(Opcode::Binary, &payload(size));
+        group.sample_size(sample_size(size));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &frame, |b, frame| {
+            b.iter(|| frame.encode().unwrap());
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for size in SIZES {
+        let data = payload(size);
+        let headers = Frame::set_defaults(Opcode::Binary, &data)
+            .headers
+            .encode()
+            .unwrap();
+        group.sample_size(sample_size(size));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| {
+                let mut decoded = Headers::decode(black_box(&headers)).unwrap();
+                decoded.payload_len_ext = data.len() as u64;
+                Frame::decode(data, decoded).unwrap()
+            });
+        });
+    }
+    group.finish();
+}
+
+// Sends the length of every message it receives.
+struct Count(UnboundedSender<usize>);
+
+#[async_trait]
+impl WebSocketProtocol for Count {
+    async fn on_connect(&mut self, _: Transport) {}
+
+    async fn on_message(&mut self, ctx: Context) {
+        let _ = self.0.send(ctx.frame.payload_data.len());
+    }
+
+    async fn on_close(&mut self, _: Context) {}
+}
+
+async fn connect() -> (MockConnection, UnboundedReceiver<usize>) {
+    let (io, acceptor) = MockServer::duplex_with_capacity(1 << 20);
+    let (tx, rx) = unbounded_channel();
+    let server = tokio::spawn(acceptor.accept());
+    let mut ws = WebSocket::from_stream(io, "ws://localhost/", Count(tx))
+        .await
+        .unwrap();
+    tokio::spawn(async move { ws.run().await });
+    (server.await.unwrap().unwrap(), rx)
+}
+
+// Whole read path: socket, header parsing, payload and handler dispatch.
+fn read(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (mut server, mut received) = rt.block_on(connect());
+
+    let mut group = c.benchmark_group("read");
+    for size in SIZES {
+        let frame = encode_frame(true, Opcode::Binary as u8, &payload(size));
+        group.sample_size(sample_size(size));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        server.send_raw(&frame).await.unwrap();
+                        assert_eq!(received.recv().await, Some(size));
+                    }
+                    start.elapsed()
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(5));
+    targets = encode, decode, read
+}
+criterion_main!(benches);
@@ -0,0 +1,137 @@
+// Runs the Autobahn TestSuite client cases against a local fuzzingserver.
+//
+// Start the fuzzingserver from the repository root:
+//
+//   docker run -it --rm -v "${PWD}/autobahn:/config" -v "${PWD}/autobahn/reports:/reports" \
+//       -p 9001:9001 crossbario/autobahn-testsuite \
+//       wstest -m fuzzingserver -s /config/fuzzingserver.json
+//
+// or with `wstest -m fuzzingserver -s autobahn/fuzzingserver.json`, then run
+// `cargo run --release --example autobahn`. Set `AUTOBAHN_SERVER` to use another
+// address. The HTML report ends up in `autobahn/reports/clients`.
+
+use async_trait::async_trait;
+use mayuri::{
+    Context, Transport, WebSocket, WebSocketProtocol,
+    core::{enums::Opcode, frame::Frame},
+};
+use std::{collections::BTreeMap, process::ExitCode};
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+
+const AGENT: &str = "mayuri";
+
+// Sends every message back as it was received.
+struct Echo {
+    transport: Option<Transport>,
+}
+
+#[async_trait]
+impl WebSocketProtocol for Echo {
+    async fn on_connect(&mut self, transport: Transport) {
+        self.transport = Some(transport);
+    }
+
+    async fn on_message(&mut self, ctx: Context) {
+        let opcode = ctx.frame.headers.opcode;
+        if !matches!(opcode, Opcode::Text | Opcode::Binary) {
+            return;
+        }
+        let mut frame = Frame::with_payload(opcode, ctx.frame.payload_data);
+        if let Some(transport) = &mut self.transport {
+            let _ = transport.write(&mut frame).await;
+        }
+    }
+
+    async fn on_close(&mut self, _: Context) {}
+}
+
+// Forwards text messages, used for the fuzzingserver's control endpoints.
+struct Collect(UnboundedSender<String>);
+
+#[async_trait]
+impl WebSocketProtocol for Collect {
+    async fn on_connect(&mut self, _: Transport) {}
+
+    async fn on_message(&mut self, ctx: Context) {
+        if ctx.frame.headers.opcode == Opcode::Text {
+            let _ = self.0.send(ctx.read_text());
+        }
+    }
+
+    async fn on_close(&mut self, _: Context) {}
+}
+
+async fn fetch(uri: &str) -> Option<String> {
+    let (tx, mut rx) = unbounded_channel();
+    let mut ws = WebSocket::connect(uri, Collect(tx)).await.ok()?;
+    let _ = ws.run().await;
+    drop(ws);
+    rx.recv().await
+}
+
+// Pulls `"behavior": "..."` out of a case status without a JSON parser.
+fn behavior(status: &str) -> String {
+    status
+        .split_once("\"behavior\"")
+        .and_then(|(_, rest)| rest.split('"').nth(1))
+        .unwrap_or("UNKNOWN")
+        .to_string()
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let server = std::env::var("AUTOBAHN_SERVER").unwrap_or_else(|_| "ws://127.0.0.1:9001".into());
+
+    let Some(count) = fetch(&format!("{server}/getCaseCount"))
+        .await
+        .and_then(|count| count.trim().parse::<u32>().ok())
+    else {
+        eprintln!("Couldn't get the case count from {server}, is the fuzzingserver running?");
+        return ExitCode::FAILURE;
+    };
+    println!("Running {count} cases against {server}");
+
+    for case in 1..=count {
+        let uri = format!("{server}/runCase?case={case}&agent={AGENT}");
+        match WebSocket::connect(&uri, Echo { transport: None }).await {
+            Ok(mut ws) => {
+                if let Err(err) = ws.run().await {
+                    log::info!("Case {case} ended with: {err}");
+                }
+            }
+            Err(err) => log::info!("Case {case} couldn't connect: {err}"),
+        }
+    }
+
+    let mut results: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for case in 1..=count {
+        let status = fetch(&format!("{server}/getCaseStatus?case={case}&agent={AGENT}"))
+            .await
+            .unwrap_or_default();
+        results.entry(behavior(&status)).or_default().push(case);
+    }
+    let _ = fetch(&format!("{server}/updateReports?agent={AGENT}")).await;
+
+    println!();
+    for (behavior, cases) in &results {
+        println!("{behavior:>14}: {}", cases.len());
+    }
+    let failed: Vec<_> = results
+        .iter()
+        .filter(|(behavior, _)| {
+            !matches!(
+                behavior.as_str(),
+                "OK" | "NON-STRICT" | "INFORMATIONAL" | "UNIMPLEMENTED"
+            )
+        })
+        .flat_map(|(_, cases)| cases)
+        .collect();
+    if failed.is_empty() {
+        println!("\nAll cases passed");
+        ExitCode::SUCCESS
+    } else {
+        println!("\nFailed cases: {failed:?}");
+        ExitCode::FAILURE
+    }
+}
@@ -1,6 +1,5 @@
 use async_trait::async_trait;
 use mayuri::{Context, Transport, WebSocket, WebSocketProtocol};
-use tokio;
 use tokio::io::{AsyncBufReadExt, BufReader, stdin};
 use tokio::sync::OnceCell;
 
@@ -50,6 +49,7 @@ impl WebSocketProtocol for App {
 
 #[tokio::main]
 async fn main() {
+    env_logger::init();
     let uri = "wss://ws.ifelse.io";
     let app = App {
         _transport: OnceCell::new(),
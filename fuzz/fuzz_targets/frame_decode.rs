@@ -0,0 +1,23 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mayuri::core::frame::{Frame, Headers};
+
+// The first two bytes are the header, the next eight the extended payload
+// length, the rest is the payload.
+fuzz_target!(|data: &[u8]| {
+    let Some((header, rest)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let Ok(mut headers) = Headers::decode(header) else {
+        return;
+    };
+    let Some((extended, payload)) = rest.split_first_chunk::<8>() else {
+        return;
+    };
+    headers.payload_len_ext = u64::from_be_bytes(*extended);
+    if let Ok(frame) = Frame::decode(payload, headers) {
+        let _ = frame.close_code();
+        let _ = frame.encode();
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mayuri::core::frame::HandshakeHeaders;
+
+fuzz_target!(|data: &str| {
+    let _ = HandshakeHeaders::new(data);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mayuri::core::frame::Headers;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(headers) = Headers::decode(data) {
+        let _ = headers.encode();
+    }
+});
@@ -0,0 +1,338 @@
+//! A wscat-style command-line client.
+//!
+//!   mayuri wss://echo.example.com -H "Authorization: Bearer ..." -s chat
+//!
+//! Lines read from stdin are sent as text messages and `--file` sends a file as
+//! one binary message. Received messages are printed with a timestamp and their
+//! opcode. `--once` sends a single message and prints the first reply, for scripts.
+
+use clap::Parser;
+use mayuri::{
+    Context, StreamBuilder, Transport, WebSocket, WebSocketError, WebSocketProtocol,
+    async_trait::async_trait,
+    core::{
+        enums::{NORMAL_CLOSURE, Opcode},
+        errors::ConnectionError,
+        frame::Frame,
+        utils::get_uri,
+    },
+};
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader, stdin},
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+
+// How long to wait for the server's Close reply before giving up on it.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "mayuri",
+    version,
+    about = "Talk to a WebSocket server from the terminal"
+)]
+struct Args {
+    /// `ws://`, `wss://` or `ws+unix://` URL to connect to.
+    url: String,
+
+    /// Extra handshake header, e.g. `-H "Origin: https://example.com"`.
+    #[arg(short = 'H', long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Subprotocol to offer, can be given more than once.
+    #[arg(short, long = "subprotocol", value_name = "PROTOCOL")]
+    subprotocols: Vec<String>,
+
+    /// PEM file with the certificates to trust instead of the bundled roots.
+    #[arg(long, value_name = "PATH")]
+    cafile: Option<PathBuf>,
+
+    /// Skips certificate validation.
+    #[arg(short = 'k', long)]
+    insecure: bool,
+
+    /// Sends the file as a binary message instead of reading stdin.
+    #[arg(short, long, value_name = "PATH")]
+    file: Option<PathBuf>,
+
+    /// Sends this text instead of reading stdin.
+    #[arg(short, long, value_name = "TEXT", conflicts_with = "file")]
+    message: Option<String>,
+
+    /// Sends one message, prints the first reply and exits.
+    #[arg(long)]
+    once: bool,
+
+    /// Pretty-prints text messages that are valid JSON.
+    #[arg(long)]
+    json: bool,
+
+    /// Treats stdin lines starting with `/ping` or `/close [code] [reason]` as
+    /// commands.
+    #[arg(long)]
+    slash: bool,
+
+    /// Gives up on each step of connecting (TCP, TLS, WebSocket handshake), or on
+    /// waiting for the reply with `--once`, after this many seconds.
+    #[arg(short, long, value_name = "SECONDS")]
+    timeout: Option<f64>,
+}
+
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    header
+        .split_once(':')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .ok_or_else(|| format!("`{header}` isn't in the `NAME: VALUE` form"))
+}
+
+// Forwards every received frame to `main`, which does the printing.
+struct Forward(UnboundedSender<Frame>);
+
+#[async_trait]
+impl WebSocketProtocol for Forward {
+    async fn on_connect(&mut self, _: Transport) {}
+
+    async fn on_message(&mut self, ctx: Context) {
+        let _ = self.0.send(ctx.frame);
+    }
+
+    async fn on_close(&mut self, ctx: Context) {
+        let _ = self.0.send(ctx.frame);
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), String> {
+    let timeout = args
+        .timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|err| format!("invalid --timeout: {err}"))?;
+
+    let mut builder = StreamBuilder::new(get_uri(args.url.clone()).map_err(error)?, None)
+        .map_err(error)?
+        .danger_accept_invalid_certs(args.insecure);
+    if let Some(limit) = timeout {
+        builder = builder
+            .connect_timeout(limit)
+            .tls_timeout(limit)
+            .handshake_timeout(limit);
+    }
+    if let Some(path) = &args.cafile {
+        builder = builder.cafile(path);
+    }
+    for (name, value) in &args.headers {
+        builder = builder.header(name, value);
+    }
+    for protocol in &args.subprotocols {
+        builder = builder.subprotocol(protocol);
+    }
+
+    let (frames_tx, frames) = unbounded_channel();
+    let mut ws = WebSocket::connect_with(builder, Forward(frames_tx))
+        .await
+        .map_err(error)?;
+    if !args.once {
+        match ws.subprotocol() {
+            Some(protocol) => eprintln!("Connected to {} ({protocol})", args.url),
+            None => eprintln!("Connected to {}", args.url),
+        }
+    }
+    let transport = ws.transport();
+    let connection = tokio::spawn(async move { ws.run().await });
+
+    if args.once {
+        once(&args, transport, frames, timeout).await?;
+        // The reply is already printed, a server that doesn't finish the closing
+        // handshake isn't worth failing over.
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, connection).await;
+        return Ok(());
+    }
+
+    tokio::spawn(send_input(
+        args.file.clone(),
+        args.message.clone(),
+        args.slash,
+        transport,
+    ));
+    print_frames(frames, args.json).await;
+    connection.await.map_err(error)?.map_err(error)
+}
+
+// Sends the message, prints the first data message received and closes.
+async fn once(
+    args: &Args,
+    mut transport: Transport,
+    mut frames: UnboundedReceiver<Frame>,
+    timeout: Option<Duration>,
+) -> Result<(), String> {
+    match (&args.file, &args.message) {
+        (Some(path), _) => {
+            let data = tokio::fs::read(path).await.map_err(error)?;
+            transport.write_binary(&data).await.map_err(error)?;
+        }
+        (None, Some(message)) => transport
+            .write_text(message.as_bytes())
+            .await
+            .map_err(error)?,
+        (None, None) => {
+            let line = BufReader::new(stdin())
+                .lines()
+                .next_line()
+                .await
+                .map_err(error)?
+                .unwrap_or_default();
+            transport.write_text(line.as_bytes()).await.map_err(error)?;
+        }
+    }
+
+    let reply = async {
+        while let Some(frame) = frames.recv().await {
+            match frame.headers.opcode {
+                Opcode::Text | Opcode::Binary => return Ok(frame),
+                Opcode::Close => break,
+                _ => {}
+            }
+        }
+        Err("connection closed before a reply arrived".to_string())
+    };
+    let reply = match timeout {
+        Some(limit) => tokio::time::timeout(limit, reply)
+            .await
+            .map_err(|_| format!("no reply within {limit:?}"))??,
+        None => reply.await?,
+    };
+
+    match reply.headers.opcode {
+        Opcode::Text => println!("{}", format_text(&reply.payload_data, args.json)),
+        _ => println!("{}", format_binary(&reply.payload_data)),
+    }
+    let _ = transport.close(NORMAL_CLOSURE, "").await;
+    Ok(())
+}
+
+// Sends the file or message given on the command line, or else every stdin line,
+// then closes the connection.
+async fn send_input(
+    file: Option<PathBuf>,
+    message: Option<String>,
+    slash: bool,
+    mut transport: Transport,
+) {
+    let sent = match (file, message) {
+        (Some(path), _) => match tokio::fs::read(&path).await {
+            Ok(data) => transport.write_binary(&data).await,
+            Err(err) => {
+                eprintln!("error: couldn't read {}: {err}", path.display());
+                Ok(())
+            }
+        },
+        (None, Some(message)) => transport.write_text(message.as_bytes()).await,
+        (None, None) => send_lines(slash, &mut transport).await,
+    };
+    if let Err(err) = sent {
+        eprintln!("error: {err}");
+        return;
+    }
+    let _ = transport.close(NORMAL_CLOSURE, "").await;
+}
+
+async fn send_lines(slash: bool, transport: &mut Transport) -> Result<(), WebSocketError> {
+    let mut lines = BufReader::new(stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        let command = if slash { line.strip_prefix('/') } else { None };
+        match command.map(|command| command.split_once(' ').unwrap_or((command, ""))) {
+            Some(("ping", payload)) => transport.ping(payload.as_bytes()).await?,
+            Some(("close", rest)) => {
+                let (code, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+                let code = if code.is_empty() {
+                    NORMAL_CLOSURE
+                } else if let Ok(code) = code.parse() {
+                    code
+                } else {
+                    eprintln!("invalid close code `{code}`");
+                    continue;
+                };
+                // A code or reason that can't be sent leaves the connection open.
+                match transport.close(code, reason).await {
+                    Err(WebSocketError::Stream(err @ ConnectionError::InvalidClose(_))) => {
+                        eprintln!("error: {err}");
+                    }
+                    result => return result,
+                }
+            }
+            Some((command, _)) => eprintln!("unknown command `/{command}`"),
+            None => transport.write_text(line.as_bytes()).await?,
+        }
+    }
+    Ok(())
+}
+
+async fn print_frames(mut frames: UnboundedReceiver<Frame>, json: bool) {
+    while let Some(frame) = frames.recv().await {
+        let payload = &frame.payload_data;
+        let line = match frame.headers.opcode {
+            Opcode::Text => format!("text: {}", format_text(payload, json)),
+            Opcode::Binary => format!("binary: {}", format_binary(payload)),
+            Opcode::Continuation => format!("continuation: {}", format_binary(payload)),
+            Opcode::Ping => format!("ping: {}", String::from_utf8_lossy(payload)),
+            Opcode::Pong => format!("pong: {}", String::from_utf8_lossy(payload)),
+            Opcode::Close => match frame.close_code() {
+                Some(code) => format!(
+                    "close: {code} {}",
+                    String::from_utf8_lossy(payload.get(2..).unwrap_or_default())
+                ),
+                None => "close".to_string(),
+            },
+        };
+        println!("[{}] < {line}", timestamp());
+    }
+}
+
+fn format_text(payload: &[u8], json: bool) -> String {
+    let text = String::from_utf8_lossy(payload);
+    if json && let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) {
+        return serde_json::to_string_pretty(&value).unwrap_or_else(|_| text.to_string());
+    }
+    text.to_string()
+}
+
+fn format_binary(payload: &[u8]) -> String {
+    let hex: Vec<String> = payload.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("({} bytes) {}", payload.len(), hex.join(" "))
+}
+
+// UTC time of day, e.g. `14:03:07.215`.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = now.as_secs() % 86_400;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        now.subsec_millis()
+    )
+}
+
+fn error(err: impl std::fmt::Display) -> String {
+    err.to_string()
+}
@@ -0,0 +1,104 @@
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::mpsc,
+};
+
+// Size of the chunks a streamed payload is read in, and of the frames
+// `Transport::send_stream` sends.
+pub(crate) const CHUNK_SIZE: usize = 64 << 10;
+
+// Chunks buffered between the connection and a slow reader of the body. Once
+// they're all taken the connection stops reading until the body is read.
+const BUFFERED_CHUNKS: usize = 4;
+
+/// Payload of a message received with `StreamBuilder::stream_messages`, read as
+/// its frames arrive.
+///
+/// Reading fails with `UnexpectedEof` when the connection ends before the
+/// message does.
+pub struct MessageBody {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    read: usize,
+    done: bool,
+}
+
+impl AsyncRead for MessageBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let body = &mut *self;
+        loop {
+            let rest = body.chunk.get(body.read..).unwrap_or_default();
+            if !rest.is_empty() {
+                let len = rest.len().min(buf.remaining());
+                buf.put_slice(rest.get(..len).unwrap_or_default());
+                body.read += len;
+                return Poll::Ready(Ok(()));
+            }
+            if body.done {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(body.chunks.poll_recv(cx)) {
+                // An empty chunk marks the end of the message.
+                Some(chunk) if chunk.is_empty() => body.done = true,
+                Some(chunk) => {
+                    body.chunk = chunk;
+                    body.read = 0;
+                }
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection ended before the message did",
+                    )));
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for MessageBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageBody")
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+// Connection side of a message being streamed.
+pub(crate) struct StreamedMessage {
+    chunks: mpsc::Sender<Vec<u8>>,
+    pub len: usize,
+}
+
+impl StreamedMessage {
+    pub fn new() -> (Self, MessageBody) {
+        let (chunks, rx) = mpsc::channel(BUFFERED_CHUNKS);
+        let body = MessageBody {
+            chunks: rx,
+            chunk: Vec::new(),
+            read: 0,
+            done: false,
+        };
+        (Self { chunks, len: 0 }, body)
+    }
+
+    // Waits for room in the body. Chunks of a body that was dropped are
+    // discarded.
+    pub async fn send(&self, chunk: Vec<u8>) {
+        if !chunk.is_empty() {
+            let _ = self.chunks.send(chunk).await;
+        }
+    }
+
+    pub async fn finish(self) {
+        let _ = self.chunks.send(Vec::new()).await;
+    }
+}
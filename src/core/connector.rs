@@ -0,0 +1,308 @@
+use super::errors::{ConnectAttempt, ConnectionError};
+use async_trait::async_trait;
+use log::debug;
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpSocket, TcpStream, lookup_host},
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+
+// RFC 8305 recommends 250ms between connection attempts.
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolves a host name to the socket addresses a connection should be attempted on.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves through the operating system's resolver.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(lookup_host((host, port)).await?.collect())
+    }
+}
+
+/// Which address families are tried, and in what order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    /// Alternates between families, starting with IPv6.
+    #[default]
+    PreferIpv6,
+
+    /// Alternates between families, starting with IPv4.
+    PreferIpv4,
+
+    Ipv4Only,
+    Ipv6Only,
+}
+
+/// OS-level TCP keepalive settings. Unset fields keep the system defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe is sent.
+    pub time: Option<Duration>,
+
+    /// Time between probes. Ignored on platforms that can't set it.
+    pub interval: Option<Duration>,
+
+    /// Unanswered probes before the connection is dropped. Ignored on platforms
+    /// that can't set it.
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    const fn to_socket2(self) -> TcpKeepalive {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(time) = self.time {
+            keepalive = keepalive.with_time(time);
+        }
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+        ))]
+        {
+            if let Some(interval) = self.interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(retries) = self.retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+        }
+        keepalive
+    }
+}
+
+/// Options applied to every TCP socket before it connects.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<Keepalive>,
+    pub send_buffer_size: Option<u32>,
+    pub recv_buffer_size: Option<u32>,
+
+    /// Local address to bind to. Only addresses of the same family are tried.
+    pub local_address: Option<IpAddr>,
+
+    /// Network interface to bind to (`SO_BINDTODEVICE`). Linux only.
+    pub interface: Option<String>,
+}
+
+impl SocketOptions {
+    fn open(&self, address: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = if address.is_ipv6() {
+            TcpSocket::new_v6()?
+        } else {
+            TcpSocket::new_v4()?
+        };
+
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            SockRef::from(&socket).set_tcp_keepalive(&keepalive.to_socket2())?;
+        }
+        if let Some(interface) = &self.interface {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            SockRef::from(&socket).bind_device(Some(interface.as_bytes()))?;
+
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Binding to interface {interface} is not supported on this platform"),
+            ));
+        }
+        if let Some(local) = self.local_address {
+            socket.bind(SocketAddr::new(local, 0))?;
+        }
+        Ok(socket)
+    }
+}
+
+#[derive(Clone)]
+pub struct Connector {
+    resolver: Arc<dyn Resolver>,
+    preference: IpPreference,
+    attempt_delay: Duration,
+    attempt_timeout: Option<Duration>,
+    socket: Arc<SocketOptions>,
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        Self {
+            resolver: Arc::new(SystemResolver),
+            preference: IpPreference::default(),
+            attempt_delay: DEFAULT_ATTEMPT_DELAY,
+            attempt_timeout: None,
+            socket: Arc::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connector")
+            .field("preference", &self.preference)
+            .field("attempt_delay", &self.attempt_delay)
+            .field("attempt_timeout", &self.attempt_timeout)
+            .field("socket", &self.socket)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Connector {
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolver>) {
+        self.resolver = resolver;
+    }
+
+    pub const fn set_preference(&mut self, preference: IpPreference) {
+        self.preference = preference;
+    }
+
+    pub const fn set_attempt_delay(&mut self, delay: Duration) {
+        self.attempt_delay = delay;
+    }
+
+    pub const fn set_attempt_timeout(&mut self, attempt_timeout: Option<Duration>) {
+        self.attempt_timeout = attempt_timeout;
+    }
+
+    pub fn socket_options_mut(&mut self) -> &mut SocketOptions {
+        Arc::make_mut(&mut self.socket)
+    }
+
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, ConnectionError> {
+        // IPv6 literals keep their brackets in the URI authority.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let resolved = if let Ok(ip) = host.parse::<IpAddr>() {
+            vec![SocketAddr::new(ip, port)]
+        } else {
+            self.resolver
+                .resolve(host, port)
+                .await
+                .map_err(|e| ConnectionError::ResolveError(format!("{host}: {e}")))?
+        };
+
+        let addrs = self.sort_addresses(resolved);
+        if addrs.is_empty() {
+            return Err(ConnectionError::ResolveError(format!(
+                "{host}: no usable addresses for {:?}",
+                self.preference
+            )));
+        }
+        debug!("Resolved {host} to {addrs:?}");
+        Ok(addrs)
+    }
+
+    // Interleaves the address families as described in RFC 8305 section 4.
+    fn sort_addresses(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v6, v4): (VecDeque<_>, VecDeque<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+        let preference = match self.socket.local_address {
+            Some(IpAddr::V4(_)) => IpPreference::Ipv4Only,
+            Some(IpAddr::V6(_)) => IpPreference::Ipv6Only,
+            None => self.preference,
+        };
+        let (mut first, mut second) = match preference {
+            IpPreference::PreferIpv6 => (v6, v4),
+            IpPreference::PreferIpv4 => (v4, v6),
+            IpPreference::Ipv4Only => (v4, VecDeque::new()),
+            IpPreference::Ipv6Only => (v6, VecDeque::new()),
+        };
+
+        let mut sorted = Vec::with_capacity(first.len() + second.len());
+        while !first.is_empty() || !second.is_empty() {
+            sorted.extend(first.pop_front());
+            sorted.extend(second.pop_front());
+        }
+        sorted
+    }
+
+    /// Connects to `host` using happy eyeballs (RFC 8305): a new attempt is started
+    /// every `attempt_delay` or as soon as the previous one fails, and the first
+    /// connection to succeed wins.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, ConnectionError> {
+        let mut pending: VecDeque<SocketAddr> = self.resolve(host, port).await?.into();
+        let mut attempts = JoinSet::new();
+        let mut failures = Vec::new();
+
+        loop {
+            if attempts.is_empty() {
+                match pending.pop_front() {
+                    Some(address) => attempts.spawn(self.attempt(address)),
+                    None => break,
+                };
+            }
+
+            tokio::select! {
+                Some(joined) = attempts.join_next() => match joined {
+                    Ok((address, Ok(stream))) => {
+                        debug!("Connected to {address}");
+                        return Ok(stream);
+                    }
+                    Ok((address, Err(error))) => {
+                        debug!("Connection attempt to {address} failed: {error}");
+                        failures.push(ConnectAttempt { address, error });
+                        if let Some(next) = pending.pop_front() {
+                            attempts.spawn(self.attempt(next));
+                        }
+                    }
+                    Err(err) => return Err(ConnectionError::ConnectorError(err.to_string())),
+                },
+                () = sleep(self.attempt_delay), if !pending.is_empty() => {
+                    if let Some(next) = pending.pop_front() {
+                        attempts.spawn(self.attempt(next));
+                    }
+                }
+            }
+        }
+
+        Err(ConnectionError::ConnectFailed {
+            host: host.to_string(),
+            attempts: failures,
+        })
+    }
+
+    fn attempt(
+        &self,
+        address: SocketAddr,
+    ) -> impl Future<Output = (SocketAddr, Result<TcpStream, String>)> + Send + 'static {
+        let attempt_timeout = self.attempt_timeout;
+        let options = Arc::clone(&self.socket);
+        async move {
+            let connect = async {
+                let socket = options.open(address)?;
+                socket.connect(address).await
+            };
+            let result = match attempt_timeout {
+                Some(limit) => timeout(limit, connect).await.map_or_else(
+                    |_| Err(format!("timed out after {limit:?}")),
+                    |r| r.map_err(|e| e.to_string()),
+                ),
+                None => connect.await.map_err(|e| e.to_string()),
+            };
+            (address, result)
+        }
+    }
+}
@@ -1,18 +1,62 @@
 use std::io;
 
-use super::frame::Frame;
+#[cfg(feature = "serde")]
+use super::errors::ParseError;
+use super::{body::MessageBody, frame::Frame};
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
 
 pub struct Context {
     pub frame: Frame,
+    body: Option<MessageBody>,
 }
 
 impl Context {
     pub const fn new(frame: Frame) -> Result<Self, io::Error> {
-        Ok(Self { frame })
+        Ok(Self { frame, body: None })
+    }
+
+    pub(crate) const fn streamed(frame: Frame, body: MessageBody) -> Self {
+        Self {
+            frame,
+            body: Some(body),
+        }
+    }
+
+    /// Takes the body of a message received with `StreamBuilder::stream_messages`,
+    /// whose `frame` has an empty payload. `None` for any other message, and once
+    /// taken.
+    pub const fn body(&mut self) -> Option<MessageBody> {
+        self.body.take()
     }
 
     #[must_use]
     pub fn read_text(&self) -> String {
         String::from_utf8_lossy(&self.frame.payload_data).to_string()
     }
+
+    /// Deserializes the payload as JSON.
+    #[cfg(feature = "serde")]
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, ParseError> {
+        Ok(serde_json::from_slice(&self.frame.payload_data)?)
+    }
+
+    /// Deserializes the payload as `MessagePack`.
+    #[cfg(feature = "msgpack")]
+    pub fn msgpack<T: DeserializeOwned>(&self) -> Result<T, ParseError> {
+        Ok(rmp_serde::from_slice(&self.frame.payload_data)?)
+    }
+
+    /// Deserializes the payload as CBOR.
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: DeserializeOwned>(&self) -> Result<T, ParseError> {
+        ciborium::from_reader(self.frame.payload_data.as_slice())
+            .map_err(|e| ParseError::CborError(e.to_string()))
+    }
+}
+
+impl From<Frame> for Context {
+    fn from(frame: Frame) -> Self {
+        Self { frame, body: None }
+    }
 }
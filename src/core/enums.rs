@@ -1,5 +1,8 @@
 use std::io;
-use strum::FromRepr;
+use strum::{Display, EnumString, FromRepr};
+
+/// Close code of a normal closure, see RFC 6455 section 7.4.1.
+pub const NORMAL_CLOSURE: u16 = 1000;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, FromRepr)]
@@ -18,7 +21,8 @@ impl State {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
 pub enum Opcode {
     Continuation = 0x0,
     Text = 0x1,
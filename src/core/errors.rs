@@ -1,6 +1,6 @@
 use fluent_uri::error;
 use rustls_pki_types::InvalidDnsNameError;
-use std::{io, num::ParseIntError, string::FromUtf8Error};
+use std::{fmt, io, net::SocketAddr, num::ParseIntError, string::FromUtf8Error, time::Duration};
 use strum;
 use thiserror::Error;
 
@@ -41,6 +41,60 @@ pub enum ConnectionError {
 
     #[error("Bad Connector: {0}")]
     ConnectorError(String),
+
+    #[error("Outbound queue is full ({0} messages)")]
+    QueueFull(usize),
+
+    #[error("Invalid rate limit: {0}")]
+    InvalidRateLimit(String),
+
+    #[error("Invalid outbound queue: {0}")]
+    InvalidQueue(String),
+
+    #[error("Invalid close frame: {0}")]
+    InvalidClose(String),
+
+    #[error("Sending would exceed the rate limit, capacity frees up in {0:?}")]
+    WouldExceedRateLimit(Duration),
+
+    #[cfg(feature = "testing")]
+    #[error("Unexpected Frame: {0}")]
+    UnexpectedFrame(String),
+
+    #[error("A connection named `{0}` already exists")]
+    DuplicateName(String),
+
+    #[error("Couldn't resolve {0}")]
+    ResolveError(String),
+
+    #[error("Extension `{name}` failed: {reason}")]
+    ExtensionError { name: String, reason: String },
+
+    #[error("Couldn't connect to {host}: {}", join_attempts(.attempts))]
+    ConnectFailed {
+        host: String,
+        attempts: Vec<ConnectAttempt>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectAttempt {
+    pub address: SocketAddr,
+    pub error: String,
+}
+
+impl fmt::Display for ConnectAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.address, self.error)
+    }
+}
+
+fn join_attempts(attempts: &[ConnectAttempt]) -> String {
+    attempts
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 #[derive(Error, Debug)]
@@ -58,6 +112,64 @@ pub enum ParseError {
         #[source]
         source: strum::ParseError,
     },
+
+    #[cfg(feature = "serde")]
+    #[error("JSON Error: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    #[cfg(feature = "msgpack")]
+    #[error("MessagePack Decode Error: {0}")]
+    MsgPackDecodeError(#[from] rmp_serde::decode::Error),
+
+    #[cfg(feature = "msgpack")]
+    #[error("MessagePack Encode Error: {0}")]
+    MsgPackEncodeError(#[from] rmp_serde::encode::Error),
+
+    #[cfg(feature = "cbor")]
+    #[error("CBOR Error: {0}")]
+    CborError(String),
+
+    #[cfg(feature = "record")]
+    #[error("Invalid recording at line {line}: {reason}")]
+    RecordingError { line: usize, reason: String },
+}
+
+#[derive(Error, Debug)]
+pub enum TimeoutError {
+    #[error("Connecting took longer than {0:?}")]
+    Connect(Duration),
+
+    #[error("TLS handshake took longer than {0:?}")]
+    Tls(Duration),
+
+    #[error("WebSocket handshake took longer than {0:?}")]
+    Handshake(Duration),
+
+    #[error("Nothing was received for {0:?}")]
+    ReadIdle(Duration),
+}
+
+#[cfg(feature = "rpc")]
+#[derive(Error, Debug)]
+pub enum RpcError {
+    #[error("No response within {0:?}")]
+    Timeout(Duration),
+
+    #[error("Connection closed before a response arrived")]
+    Closed,
+
+    #[error("Server returned error {code}: {message}")]
+    Remote {
+        code: i64,
+        message: String,
+        data: Option<serde_json::Value>,
+    },
+
+    #[error("Couldn't (de)serialize: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("{0}")]
+    WebSocket(#[from] WebSocketError),
 }
 
 #[derive(Error, Debug)]
@@ -76,4 +188,7 @@ pub enum WebSocketError {
 
     #[error("[IO Error] {0}")]
     Io(#[from] io::Error),
+
+    #[error("[Timeout] {0}")]
+    Timeout(#[from] TimeoutError),
 }
@@ -0,0 +1,279 @@
+//! WebSocket extensions (RFC 6455 section 9), offered in the handshake and
+//! applied to every data frame sent and received once the server accepts them.
+
+use super::{
+    errors::{ConnectionError, HandshakeFailureError},
+    frame::Frame,
+    handshake::is_token,
+    transport::is_data,
+};
+use log::debug;
+use std::{
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// Frame header bits an extension can claim, see [`Extension::rsv_bits`].
+pub const RSV1: u8 = 0x40;
+pub const RSV2: u8 = 0x20;
+pub const RSV3: u8 = 0x10;
+
+/// A parameter of an extension offer or response, e.g. `server_max_window_bits=10`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionParam {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl ExtensionParam {
+    pub fn new(name: impl Into<String>, value: Option<&str>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.map(String::from),
+        }
+    }
+}
+
+impl fmt::Display for ExtensionParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={value}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// An extension offered with `StreamBuilder::extension`.
+///
+/// The same instance is offered on every connection a builder opens. Each
+/// handshake that negotiates it starts a new [`ExtensionSession`], which
+/// transforms that connection's frames and owns whatever state it needs.
+pub trait Extension: Send + Sync {
+    /// Extension token sent in `Sec-WebSocket-Extensions`.
+    fn name(&self) -> &str;
+
+    /// Parameters offered along with the name.
+    fn offer(&self) -> Vec<ExtensionParam> {
+        Vec::new()
+    }
+
+    /// RSV bits the extension owns, e.g. `RSV2`. Received frames may only set
+    /// bits owned by a negotiated extension, and two negotiated extensions can't
+    /// own the same bit.
+    fn rsv_bits(&self) -> u8 {
+        0
+    }
+
+    /// Starts the extension on a connection whose server accepted it with
+    /// `params`. An error fails the handshake.
+    fn session(&self, params: &[ExtensionParam]) -> Result<Box<dyn ExtensionSession>, String>;
+}
+
+/// An extension negotiated on one connection, see [`Extension::session`].
+///
+/// Only data frames go through it, in the order they're sent or received.
+/// Control frames are sent and received as they are.
+pub trait ExtensionSession: Send {
+    /// Transforms a frame before it's sent. Payload lengths are updated
+    /// afterwards.
+    fn outgoing(&mut self, _frame: &mut Frame) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Transforms a received frame before it's handed on, clearing the RSV bits
+    /// it handled. An error fails the connection with close code 1002.
+    fn incoming(&mut self, _frame: &mut Frame) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Offered extensions, in the builder.
+#[derive(Clone, Default)]
+pub(crate) struct Extensions(Vec<Arc<dyn Extension>>);
+
+impl Extensions {
+    pub fn push(&mut self, extension: Arc<dyn Extension>) {
+        self.0.push(extension);
+    }
+
+    // Value of the `Sec-WebSocket-Extensions` request header. Names must be
+    // tokens and values tokens or quoted strings, see RFC 6455 section 9.1.
+    pub fn offer(&self) -> Result<Option<String>, HandshakeFailureError> {
+        let mut offers = Vec::new();
+        for extension in &self.0 {
+            let name = extension.name();
+            if !is_token(name) {
+                return Err(invalid_offer(name, "isn't a valid extension name"));
+            }
+            let mut offer = vec![name.to_string()];
+            for param in extension.offer() {
+                if !is_token(&param.name) {
+                    return Err(invalid_offer(&param.name, "isn't a valid parameter name"));
+                }
+                if let Some(value) = &param.value
+                    && !is_token(value)
+                    && !is_quoted_string(value)
+                {
+                    return Err(invalid_offer(value, "isn't a valid parameter value"));
+                }
+                offer.push(param.to_string());
+            }
+            offers.push(offer.join("; "));
+        }
+        Ok((!offers.is_empty()).then(|| offers.join(", ")))
+    }
+
+    // Starts a session of each offered extension the server accepted in
+    // `response`. The server may only accept offered extensions, each at most once.
+    pub fn negotiate(&self, response: Option<&str>) -> Result<Sessions, HandshakeFailureError> {
+        let fail = |reason: String| HandshakeFailureError::HeaderError(reason);
+        let mut negotiated = Sessions::default();
+        for (name, params) in response.map(parse).unwrap_or_default() {
+            let extension = self
+                .0
+                .iter()
+                .find(|extension| extension.name().eq_ignore_ascii_case(&name))
+                .ok_or_else(|| fail(format!("Server accepted `{name}`, which wasn't offered")))?;
+            if negotiated.names().contains(&extension.name()) {
+                return Err(fail(format!("Server accepted `{name}` twice")));
+            }
+            if negotiated.rsv_bits() & extension.rsv_bits() != 0 {
+                return Err(fail(format!(
+                    "`{name}` claims RSV bits another extension owns"
+                )));
+            }
+            let session = extension.session(&params).map_err(|reason| {
+                fail(format!(
+                    "`{name}` rejected the server's parameters: {reason}"
+                ))
+            })?;
+            debug!("Server accepted the extension `{name}`");
+            negotiated.0.push(Session {
+                name: extension.name().into(),
+                rsv_bits: extension.rsv_bits(),
+                state: Arc::new(Mutex::new(session)),
+            });
+        }
+        Ok(negotiated)
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|extension| extension.name()))
+            .finish()
+    }
+}
+
+#[derive(Clone)]
+struct Session {
+    name: String,
+    rsv_bits: u8,
+    state: Arc<Mutex<Box<dyn ExtensionSession>>>,
+}
+
+// Negotiated extensions, shared by the reading and writing halves of one
+// connection. Data frames go through them in the order the server listed them,
+// and back in reverse.
+#[derive(Clone, Default)]
+pub(crate) struct Sessions(Vec<Session>);
+
+impl Sessions {
+    pub const fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|session| session.name.as_str()).collect()
+    }
+
+    pub fn rsv_bits(&self) -> u8 {
+        self.0
+            .iter()
+            .fold(0, |bits, session| bits | session.rsv_bits)
+    }
+
+    pub fn outgoing(&self, frame: &mut Frame) -> Result<(), ConnectionError> {
+        if !is_data(frame.headers.opcode) {
+            return Ok(());
+        }
+        for session in &self.0 {
+            lock(&session.state).outgoing(frame).map_err(|reason| {
+                ConnectionError::ExtensionError {
+                    name: session.name.clone(),
+                    reason,
+                }
+            })?;
+        }
+        frame.update_payload_len();
+        Ok(())
+    }
+
+    pub fn incoming(&self, frame: &mut Frame) -> Result<(), String> {
+        if !is_data(frame.headers.opcode) {
+            return Ok(());
+        }
+        for session in self.0.iter().rev() {
+            lock(&session.state)
+                .incoming(frame)
+                .map_err(|reason| format!("Extension `{}` failed: {reason}", session.name))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+fn lock(session: &Mutex<Box<dyn ExtensionSession>>) -> MutexGuard<'_, Box<dyn ExtensionSession>> {
+    session.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn invalid_offer(value: &str, problem: &str) -> HandshakeFailureError {
+    HandshakeFailureError::HeaderError(format!("`{}` {problem}", value.escape_debug()))
+}
+
+// Whether `value` is a quoted string, see RFC 7230 section 3.2.6.
+fn is_quoted_string(value: &str) -> bool {
+    let Some(inner) = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    else {
+        return false;
+    };
+    let mut escaped = false;
+    for byte in inner.bytes() {
+        let visible = byte == b'\t' || byte == b' ' || byte.is_ascii_graphic() || byte >= 0x80;
+        if !visible || (!escaped && byte == b'"') {
+            return false;
+        }
+        escaped = !escaped && byte == b'\\';
+    }
+    !escaped
+}
+
+// Splits a `Sec-WebSocket-Extensions` value into names and parameters. Quoted
+// values lose their quotes.
+fn parse(header: &str) -> Vec<(String, Vec<ExtensionParam>)> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            let mut parts = element.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let params = parts
+                .filter(|param| !param.is_empty())
+                .map(|param| match param.split_once('=') {
+                    Some((name, value)) => {
+                        ExtensionParam::new(name.trim(), Some(value.trim().trim_matches('"')))
+                    }
+                    None => ExtensionParam::new(param, None),
+                })
+                .collect();
+            Some((name.to_string(), params))
+        })
+        .collect()
+}
@@ -90,10 +90,10 @@ impl Headers {
         let byte0 = cursor.read_u8()?;
         let byte1 = cursor.read_u8()?;
 
-        let fin = (byte0 >> 7) != 0;
-        let rsv1 = (byte0 >> 6) != 0;
-        let rsv2 = (byte0 >> 5) != 0;
-        let rsv3 = (byte0 >> 4) != 0;
+        let fin = (byte0 >> 7) & 1 != 0;
+        let rsv1 = (byte0 >> 6) & 1 != 0;
+        let rsv2 = (byte0 >> 5) & 1 != 0;
+        let rsv3 = (byte0 >> 4) & 1 != 0;
         let opcode = Opcode::from_u8(byte0 & 0x0F)?;
 
         let mask = (byte1 >> 7) != 0;
@@ -133,7 +133,13 @@ impl Headers {
         cursor.write_u8(byte1)?;
 
         if self.payload_len == MIN_VAL_FOR_16_BIT_UPGRADE {
-            cursor.write_u16::<BigEndian>(self.payload_len_ext as u16)?;
+            let payload_len_ext = u16::try_from(self.payload_len_ext).map_err(|_| {
+                io::Error::other(format!(
+                    "Payload length {} doesn't fit in 16 bits",
+                    self.payload_len_ext
+                ))
+            })?;
+            cursor.write_u16::<BigEndian>(payload_len_ext)?;
         } else if self.payload_len == MIN_VAL_FOR_64_BIT_UPGRADE {
             cursor.write_u64::<BigEndian>(self.payload_len_ext)?;
         }
@@ -141,6 +147,24 @@ impl Headers {
         Ok(cursor.into_inner())
     }
 
+    /// RSV bits set in these headers, as `extension::RSV1` etc.
+    #[must_use]
+    pub fn rsv_bits(&self) -> u8 {
+        (u8::from(self.rsv1) << 6) | (u8::from(self.rsv2) << 5) | (u8::from(self.rsv3) << 4)
+    }
+
+    /// Size of these headers on the wire, masking key included.
+    #[must_use]
+    pub const fn encoded_len(&self) -> usize {
+        let extended = match self.payload_len {
+            MIN_VAL_FOR_16_BIT_UPGRADE => 2,
+            MIN_VAL_FOR_64_BIT_UPGRADE => 8,
+            _ => 0,
+        };
+        let masking_key = if self.mask { 4 } else { 0 };
+        2 + extended + masking_key
+    }
+
     #[must_use]
     pub const fn set_defaults(opcode: Opcode, payload_len: u8, payload_len_ext: u64) -> Self {
         Self {
@@ -173,7 +197,21 @@ impl Frame {
             u64::from(headers.payload_len)
         };
 
-        let mut payload_data = vec![0u8; final_payload_len as usize];
+        // Checked before allocating, the length comes straight from the network.
+        let final_payload_len = usize::try_from(final_payload_len)
+            .ok()
+            .filter(|len| *len <= data.len())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "Payload length {final_payload_len} exceeds the {} bytes available",
+                        data.len()
+                    ),
+                )
+            })?;
+
+        let mut payload_data = vec![0u8; final_payload_len];
         cursor.read_exact(&mut payload_data)?;
 
         Ok(Self {
@@ -182,31 +220,58 @@ impl Frame {
         })
     }
 
-    pub fn encode(&mut self) -> Result<Vec<u8>, io::Error> {
+    /// Encodes the frame with a fresh masking key. The frame itself is left
+    /// unmasked, so it can be encoded again.
+    pub fn encode(&self) -> Result<Vec<u8>, io::Error> {
         let mut cursor = Cursor::new(Vec::new());
         let masking_key = Self::get_masking_key();
 
-        for (i, byte) in self.payload_data.iter_mut().enumerate() {
-            let key = 3 - (i % 4);
-            *byte ^= ((masking_key >> (8 * key)) & 0xFF) as u8;
-        }
-
         cursor.write_all(&self.headers.encode()?)?;
         cursor.write_u32::<BigEndian>(masking_key)?;
-        cursor.write_all(&self.payload_data)?;
+        let key = masking_key.to_be_bytes();
+        let masked: Vec<u8> = self
+            .payload_data
+            .iter()
+            .zip(key.iter().cycle())
+            .map(|(byte, key)| byte ^ key)
+            .collect();
+        cursor.write_all(&masked)?;
         Ok(cursor.into_inner())
     }
 
+    /// Status code carried by a Close frame, if any.
+    #[must_use]
+    pub fn close_code(&self) -> Option<u16> {
+        if self.headers.opcode != Opcode::Close {
+            return None;
+        }
+        let code = self.payload_data.get(..2)?;
+        Some(u16::from_be_bytes([*code.first()?, *code.get(1)?]))
+    }
+
     #[must_use]
     pub fn set_defaults(opcode: Opcode, data: &[u8]) -> Self {
-        let (payload_len, payload_len_ext) = Self::get_payload_len(data.len());
+        Self::with_payload(opcode, data.to_vec())
+    }
+
+    /// Like `set_defaults`, but takes the payload instead of copying it.
+    #[must_use]
+    pub const fn with_payload(opcode: Opcode, payload_data: Vec<u8>) -> Self {
+        let (payload_len, payload_len_ext) = Self::get_payload_len(payload_data.len());
         let headers = Headers::set_defaults(opcode, payload_len, payload_len_ext);
         Self {
             headers,
-            payload_data: data.to_vec(),
+            payload_data,
         }
     }
 
+    // Makes the headers match the payload again after it was replaced.
+    pub(crate) const fn update_payload_len(&mut self) {
+        let (payload_len, payload_len_ext) = Self::get_payload_len(self.payload_data.len());
+        self.headers.payload_len = payload_len;
+        self.headers.payload_len_ext = payload_len_ext;
+    }
+
     const fn get_payload_len(len: usize) -> (u8, u64) {
         if len < MIN_VAL_FOR_16_BIT_UPGRADE as usize {
             (len as u8, 0u64)
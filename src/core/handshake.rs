@@ -1,7 +1,12 @@
 use super::{
-    errors::{HandshakeFailureError, URIError, WebSocketError},
+    errors::{HandshakeFailureError, WebSocketError},
+    extension::{Extensions, Sessions},
     frame::HandshakeHeaders,
-    utils::{ACCEPT_KEY_NAME, CRLF, get_host, get_resource_target},
+    trace::handshake_event,
+    utils::{
+        ACCEPT_KEY_NAME, CRLF, EXTENSIONS_KEY_NAME, PROTOCOL_KEY_NAME, get_host_header,
+        get_resource_target,
+    },
 };
 use crate::safe_get_handshake_item;
 use base64::{Engine, engine::general_purpose::STANDARD};
@@ -9,55 +14,155 @@ use fluent_uri::Uri;
 use log::debug;
 use rand::RngCore;
 use sha1::{Digest, Sha1};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
 
 const __GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_RESPONSE_SIZE: usize = 8192;
+const END_OF_HEADERS: &[u8] = b"\r\n\r\n";
+
+// Headers the handshake writes itself, which can't be set again by the caller.
+const RESERVED_HEADERS: [&str; 7] = [
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+];
+
+/// Extra request headers, offered subprotocols and extensions, see
+/// `StreamBuilder::header`, `StreamBuilder::subprotocol` and
+/// `StreamBuilder::extension`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HandshakeOptions {
+    pub headers: Vec<(String, String)>,
+    pub subprotocols: Vec<String>,
+    pub extensions: Extensions,
+}
+
+/// What the server agreed to in its handshake response.
+#[derive(Debug, Default)]
+pub(crate) struct Negotiated {
+    pub subprotocol: Option<String>,
+    pub extensions: Sessions,
+}
 
 pub struct Handshake<'a, R, W>
 where
     W: AsyncWrite + Unpin,
-    R: AsyncRead + Unpin,
+    R: AsyncBufRead + Unpin,
 {
     reader: &'a mut R,
     pub writer: &'a mut W,
     uri: &'a Uri<String>,
+    options: &'a HandshakeOptions,
 }
 
-impl<'a, R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Handshake<'a, R, W> {
-    pub const fn new(reader: &'a mut R, writer: &'a mut W, uri: &'a Uri<String>) -> Self {
+impl<'a, R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin> Handshake<'a, R, W> {
+    pub(crate) const fn new(
+        reader: &'a mut R,
+        writer: &'a mut W,
+        uri: &'a Uri<String>,
+        options: &'a HandshakeOptions,
+    ) -> Self {
         Self {
             reader,
             writer,
             uri,
+            options,
         }
     }
-    pub async fn run(&mut self) -> Result<(), WebSocketError> {
+
+    /// Returns the subprotocol and extensions the server selected.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "handshake", skip_all))]
+    pub(crate) async fn run(&mut self) -> Result<Negotiated, WebSocketError> {
         let security_key = Self::generate_security_key();
-        let handshake_payload = Self::get_handshake_payload(self.uri, security_key.as_str())?;
+        let handshake_payload =
+            Self::get_handshake_payload(self.uri, security_key.as_str(), self.options)?;
         self.writer.write_all(handshake_payload.as_bytes()).await?;
+        self.writer.flush().await?;
 
         debug!("Handshake Bytes sent to the server");
 
-        let mut buf: [u8; 4096] = [0; 4096];
-
-        self.reader.read(&mut buf).await?;
-        let resp = String::from_utf8_lossy(&buf).to_string();
+        let resp = self.read_response().await?;
 
         debug!("Handshake Response received from the server");
         let handshake_headers = HandshakeHeaders::new(&resp)?;
 
-        debug!(
-            "Handshake Status: Version: {} | Status Code: {} | {}",
-            handshake_headers.http_version,
-            handshake_headers.http_status_code,
-            handshake_headers.http_status_text
-        );
+        handshake_event!(handshake_headers);
 
         let accept =
             safe_get_handshake_item!(handshake_headers.headers, ACCEPT_KEY_NAME, ACCEPT_KEY_NAME)?;
 
-        Self::validate_accept(accept.as_str(), security_key)?;
-        Ok(())
+        Self::validate_accept(accept.as_str(), &security_key)?;
+        let subprotocol = handshake_headers.headers.get(PROTOCOL_KEY_NAME);
+        let extensions = handshake_headers.headers.get(EXTENSIONS_KEY_NAME);
+        Ok(Negotiated {
+            subprotocol: Self::validate_subprotocol(subprotocol, &self.options.subprotocols)?,
+            extensions: self
+                .options
+                .extensions
+                .negotiate(extensions.map(String::as_str))?,
+        })
+    }
+
+    // The server may only pick one of the offered subprotocols, or none.
+    fn validate_subprotocol(
+        selected: Option<&String>,
+        offered: &[String],
+    ) -> Result<Option<String>, HandshakeFailureError> {
+        match selected {
+            Some(protocol) if !offered.contains(protocol) => {
+                Err(HandshakeFailureError::HeaderError(format!(
+                    "Server selected the subprotocol `{protocol}`, which wasn't offered"
+                )))
+            }
+            Some(protocol) => {
+                debug!("Server selected the subprotocol `{protocol}`");
+                Ok(Some(protocol.clone()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Reads the response a buffered chunk at a time. Only the bytes up to the end
+    // of the headers are consumed, frames the server sends right after them stay
+    // in the reader's buffer for `Stream` to pick up.
+    async fn read_response(&mut self) -> Result<String, WebSocketError> {
+        let mut buf = Vec::with_capacity(1024);
+        loop {
+            let chunk = self.reader.fill_buf().await?;
+            if chunk.is_empty() {
+                return Err(WebSocketError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            // The end of the headers may start in the previous chunk.
+            let searched = buf.len().saturating_sub(END_OF_HEADERS.len() - 1);
+            let read = buf.len();
+            buf.extend_from_slice(chunk);
+            let end = buf
+                .get(searched..)
+                .and_then(|tail| {
+                    tail.windows(END_OF_HEADERS.len())
+                        .position(|window| window == END_OF_HEADERS)
+                })
+                .map(|position| searched + position + END_OF_HEADERS.len());
+            if let Some(end) = end {
+                buf.truncate(end);
+                self.reader.consume(end - read);
+                return Ok(String::from_utf8_lossy(&buf).to_string());
+            }
+            let consumed = buf.len() - read;
+            self.reader.consume(consumed);
+            if buf.len() >= MAX_RESPONSE_SIZE {
+                return Err(WebSocketError::Handshake(
+                    HandshakeFailureError::HeaderError(format!(
+                        "Handshake response is larger than {MAX_RESPONSE_SIZE} bytes"
+                    )),
+                ));
+            }
+        }
     }
 
     fn generate_security_key() -> String {
@@ -66,19 +171,11 @@ impl<'a, R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Handshake<'a, R, W> {
         STANDARD.encode(&bytes)
     }
 
-    fn generate_valid_accept(security_key: String) -> String {
-        let accept = security_key + __GUID;
-        let mut hasher = Sha1::new();
-        hasher.update(accept.as_bytes());
-        let result = hasher.finalize();
-        STANDARD.encode(result)
-    }
-
     pub fn validate_accept(
         accept_key: &str,
-        security_key: String,
+        security_key: &str,
     ) -> Result<(), HandshakeFailureError> {
-        let valid_accept_key = Self::generate_valid_accept(security_key);
+        let valid_accept_key = generate_valid_accept(security_key);
         if accept_key == valid_accept_key {
             debug!("{ACCEPT_KEY_NAME} from Server's Handshake Bytes has been validated");
             Ok(())
@@ -86,27 +183,74 @@ impl<'a, R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Handshake<'a, R, W> {
             Err(HandshakeFailureError::ValidationError)
         }
     }
-    fn get_handshake_payload(uri: &Uri<String>, security_key: &str) -> Result<String, URIError> {
-        let maybe_auth = uri.authority();
-        let auth = maybe_auth.map_or_else(
-            || {
-                Err(URIError::IncompleteURIError(
-                    "Authority for the URI is not found".into(),
-                ))
-            },
-            Ok,
-        )?;
-
-        let host = get_host(&auth);
+    fn get_handshake_payload(
+        uri: &Uri<String>,
+        security_key: &str,
+        options: &HandshakeOptions,
+    ) -> Result<String, WebSocketError> {
+        let host = get_host_header(uri)?;
         let target = get_resource_target(uri)?;
 
-        Ok(format!(
-            "GET {target} HTTP/1.1{CRLF}\
-        Host: {host}{CRLF}\
-        Connection: Upgrade{CRLF}\
-        Upgrade: websocket{CRLF}\
-        Sec-WebSocket-Key: {security_key}{CRLF}\
-        Sec-WebSocket-Version: 13{CRLF}{CRLF}"
-        ))
+        let mut lines = vec![
+            format!("GET {target} HTTP/1.1"),
+            format!("Host: {host}"),
+            "Connection: Upgrade".into(),
+            "Upgrade: websocket".into(),
+            format!("Sec-WebSocket-Key: {security_key}"),
+            "Sec-WebSocket-Version: 13".into(),
+        ];
+        if let Some(subprotocol) = options.subprotocols.iter().find(|name| !is_token(name)) {
+            return Err(HandshakeFailureError::HeaderError(format!(
+                "`{}` isn't a valid subprotocol",
+                subprotocol.escape_debug()
+            ))
+            .into());
+        }
+        if !options.subprotocols.is_empty() {
+            let subprotocols = options.subprotocols.join(", ");
+            lines.push(format!("Sec-WebSocket-Protocol: {subprotocols}"));
+        }
+        if let Some(extensions) = options.extensions.offer()? {
+            lines.push(format!("Sec-WebSocket-Extensions: {extensions}"));
+        }
+        for (name, value) in &options.headers {
+            validate_header(name, value)?;
+            lines.push(format!("{name}: {value}"));
+        }
+        Ok(format!("{}{CRLF}{CRLF}", lines.join(CRLF)))
+    }
+}
+
+// Whether `value` is a token, see RFC 7230 section 3.2.6.
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&byte))
+}
+
+fn validate_header(name: &str, value: &str) -> Result<(), HandshakeFailureError> {
+    if !is_token(name) {
+        return Err(HandshakeFailureError::HeaderError(format!(
+            "`{name}` isn't a valid header name"
+        )));
+    }
+    if value.contains(['\r', '\n']) {
+        return Err(HandshakeFailureError::HeaderError(format!(
+            "Value of `{name}` contains a line break"
+        )));
     }
+    if RESERVED_HEADERS.contains(&name.to_lowercase().as_str()) {
+        return Err(HandshakeFailureError::HeaderError(format!(
+            "`{name}` is set by the handshake itself"
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn generate_valid_accept(security_key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(security_key.as_bytes());
+    hasher.update(__GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
 }
@@ -0,0 +1,229 @@
+use super::{
+    enums::{NORMAL_CLOSURE, State},
+    errors::{ConnectionError, WebSocketError},
+    protocol::WebSocketProtocol,
+    stats,
+    stream::StreamBuilder,
+    transport::Transport,
+};
+use crate::WebSocket;
+use log::{debug, info, warn};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::task::{AbortHandle, Id, JoinSet};
+
+// How long a removed connection gets to finish the closing handshake before its
+// task is aborted.
+const CLOSE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Point in time view of one managed connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionHealth {
+    pub state: State,
+    pub connected_since: Instant,
+
+    /// Set once the connection's event loop stopped with an error.
+    pub last_error: Option<String>,
+}
+
+/// Aggregate view over every managed connection.
+#[derive(Debug, Clone, Default)]
+pub struct HealthReport {
+    pub connections: HashMap<String, ConnectionHealth>,
+    pub open: usize,
+    pub closed: usize,
+    pub errored: usize,
+}
+
+impl HealthReport {
+    /// `true` when every managed connection is open.
+    #[must_use]
+    pub fn is_healthy(&self) -> bool {
+        self.open == self.connections.len()
+    }
+}
+
+struct ManagedConnection {
+    transport: Transport,
+    abort: AbortHandle,
+    connected_since: Instant,
+    last_error: Option<String>,
+}
+
+/// Owns many named connections and runs their event loops on one task set.
+///
+/// Every connection keeps its own `WebSocketProtocol` handler, so messages are
+/// routed to the handler the connection was added with.
+#[derive(Default)]
+pub struct ConnectionManager {
+    tasks: JoinSet<Result<(), WebSocketError>>,
+    connections: HashMap<String, ManagedConnection>,
+    names: HashMap<Id, String>,
+}
+
+impl ConnectionManager {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects with `builder` and starts running the connection under `name`.
+    pub async fn add<P: WebSocketProtocol + Send + Sync + 'static>(
+        &mut self,
+        name: impl Into<String>,
+        builder: StreamBuilder,
+        protocol: P,
+    ) -> Result<(), WebSocketError> {
+        let name = name.into();
+        self.ensure_unique(&name)?;
+        let ws = WebSocket::connect_with(builder, protocol).await?;
+        self.insert(name, ws)
+    }
+
+    /// Starts running an already connected `WebSocket` under `name`.
+    pub fn insert<P: WebSocketProtocol + Send + Sync + 'static>(
+        &mut self,
+        name: impl Into<String>,
+        mut ws: WebSocket<P>,
+    ) -> Result<(), WebSocketError> {
+        let name = name.into();
+        self.ensure_unique(&name)?;
+
+        let transport = ws.transport();
+        let abort = self.tasks.spawn(async move { ws.run().await });
+        self.names.insert(abort.id(), name.clone());
+        self.connections.insert(
+            name.clone(),
+            ManagedConnection {
+                transport,
+                abort,
+                connected_since: Instant::now(),
+                last_error: None,
+            },
+        );
+        info!("Managing connection `{name}`");
+        Ok(())
+    }
+
+    /// Closes the connection named `name` and stops managing it. Returns `false`
+    /// when there is no such connection.
+    pub async fn remove(&mut self, name: &str) -> bool {
+        let Some(mut connection) = self.connections.remove(name) else {
+            return false;
+        };
+        self.names.remove(&connection.abort.id());
+
+        if connection.transport.state() == State::OPEN {
+            if let Err(err) = connection.transport.close(NORMAL_CLOSURE, "").await {
+                warn!("Couldn't close `{name}` cleanly: {err}");
+                connection.abort.abort();
+            } else {
+                let abort = connection.abort;
+                tokio::spawn(async move {
+                    tokio::time::sleep(CLOSE_GRACE_PERIOD).await;
+                    abort.abort();
+                });
+            }
+        } else {
+            connection.abort.abort();
+        }
+        info!("Stopped managing connection `{name}`");
+        true
+    }
+
+    #[must_use]
+    pub fn transport(&self, name: &str) -> Option<Transport> {
+        self.connections.get(name).map(|c| c.transport.clone())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.connections.keys().map(String::as_str)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    #[must_use]
+    pub fn health(&self) -> HealthReport {
+        let mut report = HealthReport::default();
+        for (name, connection) in &self.connections {
+            let state = connection.transport.state();
+            match state {
+                State::OPEN => report.open += 1,
+                State::ERROR => report.errored += 1,
+                _ if connection.last_error.is_some() => report.errored += 1,
+                _ => report.closed += 1,
+            }
+            report.connections.insert(
+                name.clone(),
+                ConnectionHealth {
+                    state,
+                    connected_since: connection.connected_since,
+                    last_error: connection.last_error.clone(),
+                },
+            );
+        }
+        report
+    }
+
+    /// Renders the counters of every managed connection in the Prometheus text
+    /// exposition format, labelled with the connection names.
+    #[must_use]
+    pub fn to_prometheus(&self) -> String {
+        let mut snapshots: Vec<_> = self
+            .connections
+            .iter()
+            .map(|(name, connection)| (name.as_str(), connection.transport.stats()))
+            .collect();
+        snapshots.sort_by(|a, b| a.0.cmp(b.0));
+        stats::to_prometheus(snapshots.iter().map(|(name, stats)| (*name, stats)))
+    }
+
+    /// Waits for the next managed connection to stop and returns its name and
+    /// outcome. Connections stay listed in `health` until they are removed.
+    /// Returns `None` once no connection is running.
+    pub async fn next_finished(&mut self) -> Option<(String, Result<(), WebSocketError>)> {
+        loop {
+            let (id, result) = match self.tasks.join_next_with_id().await? {
+                Ok((id, result)) => (id, result),
+                Err(err) => {
+                    self.names.remove(&err.id());
+                    debug!("Connection task stopped: {err}");
+                    continue;
+                }
+            };
+            let Some(name) = self.names.remove(&id) else {
+                continue;
+            };
+
+            if let (Err(err), Some(connection)) = (&result, self.connections.get_mut(&name)) {
+                warn!("Connection `{name}` failed: {err}");
+                connection.last_error = Some(err.to_string());
+            }
+            return Some((name, result));
+        }
+    }
+
+    /// Drives every managed connection until all of them have stopped.
+    pub async fn run(&mut self) {
+        while self.next_finished().await.is_some() {}
+    }
+
+    fn ensure_unique(&self, name: &str) -> Result<(), WebSocketError> {
+        if self.connections.contains_key(name) {
+            return Err(WebSocketError::Stream(ConnectionError::DuplicateName(
+                name.to_string(),
+            )));
+        }
+        Ok(())
+    }
+}
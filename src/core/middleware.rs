@@ -0,0 +1,75 @@
+use super::{errors::WebSocketError, frame::Frame, transport::Transport};
+use async_trait::async_trait;
+use std::{fmt, sync::Arc};
+
+/// A step messages go through between the connection and `WebSocketProtocol`,
+/// added with `StreamBuilder::middleware`.
+///
+/// Received messages go through middleware in the order it was added, written
+/// ones in reverse, so the first middleware is the one closest to the connection.
+/// Both methods return the messages to hand on to the next step: the message,
+/// changed or not, to pass it on, none to drop it, or more to inject messages
+/// after it.
+///
+/// Control frames, fragments sent through `Transport::fragments` and messages
+/// received with `StreamBuilder::stream_messages` skip middleware.
+///
+/// Inbound middleware runs on the task reading the connection: until it returns
+/// no more frames are read, so Pings go unanswered meanwhile.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Runs on every received message before `on_message`. Replies written to
+    /// `transport` go through the whole outbound chain.
+    async fn inbound(&self, frame: Frame, _transport: &Transport) -> Vec<Frame> {
+        vec![frame]
+    }
+
+    /// Runs on every message written through `Transport` before it's sent. An
+    /// error fails the write.
+    async fn outbound(&self, frame: Frame) -> Result<Vec<Frame>, WebSocketError> {
+        Ok(vec![frame])
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct MiddlewareChain(Vec<Arc<dyn Middleware>>);
+
+impl MiddlewareChain {
+    pub fn push(&mut self, middleware: Arc<dyn Middleware>) {
+        self.0.push(middleware);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub async fn inbound(&self, frame: Frame, transport: &Transport) -> Vec<Frame> {
+        let mut frames = vec![frame];
+        for middleware in &self.0 {
+            let mut next = Vec::with_capacity(frames.len());
+            for frame in frames {
+                next.extend(middleware.inbound(frame, transport).await);
+            }
+            frames = next;
+        }
+        frames
+    }
+
+    pub async fn outbound(&self, frame: Frame) -> Result<Vec<Frame>, WebSocketError> {
+        let mut frames = vec![frame];
+        for middleware in self.0.iter().rev() {
+            let mut next = Vec::with_capacity(frames.len());
+            for frame in frames {
+                next.extend(middleware.outbound(frame).await?);
+            }
+            frames = next;
+        }
+        Ok(frames)
+    }
+}
+
+impl fmt::Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MiddlewareChain [{} steps]", self.0.len())
+    }
+}
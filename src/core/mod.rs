@@ -1,9 +1,27 @@
+pub mod body;
+pub mod connector;
 pub mod context;
 pub mod enums;
 pub mod errors;
+pub mod extension;
 pub mod frame;
 pub mod handshake;
+pub mod manager;
+pub mod middleware;
 pub mod protocol;
+pub mod queue;
+pub mod ratelimit;
+#[cfg(feature = "record")]
+pub mod record;
+pub mod router;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod stats;
 pub mod stream;
+pub mod subscriptions;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tls;
+pub(crate) mod trace;
 pub mod transport;
 pub mod utils;
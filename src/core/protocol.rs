@@ -1,9 +1,19 @@
 use super::{context::Context, transport::Transport};
 use async_trait::async_trait;
 
+/// Callbacks of a connection.
+///
+/// They run one at a time, in the order their frames arrived, apart from the task
+/// reading the connection, which goes on answering Pings and Close frames while a
+/// callback is busy.
 #[async_trait]
 pub trait WebSocketProtocol {
     async fn on_connect(&mut self, transport: Transport);
     async fn on_message(&mut self, ctx: Context);
     async fn on_close(&mut self, ctx: Context);
+
+    /// Called with every frame as it arrives, before fragments are put back
+    /// together into the message `on_message` gets. Only called once enabled with
+    /// `StreamBuilder::frame_hook`.
+    async fn on_frame(&mut self, _ctx: Context) {}
 }
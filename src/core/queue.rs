@@ -0,0 +1,92 @@
+use super::{errors::ConnectionError, frame::Frame};
+use log::debug;
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// What happens to a message written while the outbound queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the oldest queued message to make room.
+    DropOldest,
+
+    /// Drops the message being written.
+    DropNewest,
+
+    /// Fails the write with `ConnectionError::QueueFull`.
+    #[default]
+    Error,
+}
+
+// Holds data frames while the connection isn't open. They're encoded when
+// written, each with a fresh masking key.
+pub(crate) struct OutboundQueue {
+    capacity: usize,
+    policy: OverflowPolicy,
+    frames: Mutex<VecDeque<Frame>>,
+}
+
+impl OutboundQueue {
+    pub const fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            capacity,
+            policy,
+            frames: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn frames(&self) -> MutexGuard<'_, VecDeque<Frame>> {
+        self.frames.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames().len()
+    }
+
+    // Queues a copy of `frame` unless `is_open` says it can be written right away,
+    // which is when `false` is returned. The check happens under the queue lock so
+    // it can't race with `pop_or_else` draining the queue.
+    pub fn hold(
+        &self,
+        frame: &Frame,
+        is_open: impl FnOnce() -> bool,
+    ) -> Result<bool, ConnectionError> {
+        let mut frames = self.frames();
+        if is_open() {
+            return Ok(false);
+        }
+        if frames.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    debug!("Outbound queue is full, dropping the oldest message");
+                    frames.pop_front();
+                }
+                OverflowPolicy::DropNewest => {
+                    debug!("Outbound queue is full, dropping the new message");
+                    return Ok(true);
+                }
+                OverflowPolicy::Error => return Err(ConnectionError::QueueFull(self.capacity)),
+            }
+        }
+        frames.push_back(frame.clone());
+        drop(frames);
+        Ok(true)
+    }
+
+    // Pops the next queued frame, or runs `on_empty` under the queue lock when
+    // there is none left.
+    pub fn pop_or_else(&self, on_empty: impl FnOnce()) -> Option<Frame> {
+        let mut frames = self.frames();
+        let frame = frames.pop_front();
+        if frame.is_none() {
+            on_empty();
+        }
+        drop(frames);
+        frame
+    }
+
+    pub fn push_front(&self, frame: Frame) {
+        self.frames().push_front(frame);
+    }
+}
@@ -0,0 +1,144 @@
+use super::{errors::ConnectionError, frame::Frame};
+use log::debug;
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Token bucket limit on outgoing data messages.
+///
+/// A fragmented message is charged once, by its first frame, so it's never cut
+/// off partway. Control frames are never limited.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    messages: u32,
+    per: Duration,
+    burst: u32,
+    fail_fast: bool,
+    cost: fn(&Frame) -> u32,
+}
+
+impl RateLimit {
+    /// Allows `messages` per `per`, with bursts of up to `messages`. Fails when
+    /// either is zero, which would never let a message through.
+    pub fn new(messages: u32, per: Duration) -> Result<Self, ConnectionError> {
+        if messages == 0 {
+            return Err(ConnectionError::InvalidRateLimit(
+                "`messages` must be at least 1".into(),
+            ));
+        }
+        if per.is_zero() {
+            return Err(ConnectionError::InvalidRateLimit(
+                "`per` must be longer than zero".into(),
+            ));
+        }
+        Ok(Self {
+            messages,
+            per,
+            burst: messages,
+            fail_fast: false,
+            cost: |_| 1,
+        })
+    }
+
+    pub fn per_second(messages: u32) -> Result<Self, ConnectionError> {
+        Self::new(messages, Duration::from_secs(1))
+    }
+
+    /// Size of the bucket, how many messages can be sent back to back. At least 1.
+    #[must_use]
+    pub const fn burst(mut self, burst: u32) -> Self {
+        self.burst = if burst == 0 { 1 } else { burst };
+        self
+    }
+
+    /// Fails writes with `ConnectionError::WouldExceedRateLimit` instead of
+    /// waiting for capacity. Messages already held by the outbound queue still
+    /// wait for it when they're flushed.
+    #[must_use]
+    pub const fn fail_fast(mut self) -> Self {
+        self.fail_fast = true;
+        self
+    }
+
+    /// How many tokens a message takes, 1 by default. Costs above `burst` are
+    /// capped to it.
+    #[must_use]
+    pub const fn cost(mut self, cost: fn(&Frame) -> u32) -> Self {
+        self.cost = cost;
+        self
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            bucket: Mutex::new(Bucket {
+                tokens: f64::from(limit.burst),
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    // Tokens added per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.limit.messages) / self.limit.per.as_secs_f64()
+    }
+
+    // Takes the tokens `frame` costs, or returns how long until they're available.
+    fn try_acquire(&self, cost: f64) -> Result<(), Duration> {
+        let rate = self.rate();
+        let burst = f64::from(self.limit.burst);
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = elapsed.mul_add(rate, bucket.tokens).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            return Ok(());
+        }
+        let missing = cost - bucket.tokens;
+        drop(bucket);
+        Err(Duration::try_from_secs_f64(missing / rate).unwrap_or(Duration::MAX))
+    }
+
+    pub async fn acquire(&self, frame: &Frame) -> Result<(), ConnectionError> {
+        self.take(frame, self.limit.fail_fast).await
+    }
+
+    // Waits for capacity even with `fail_fast`, for messages that were already
+    // accepted, e.g. by the outbound queue.
+    pub async fn wait_for(&self, frame: &Frame) {
+        let _ = self.take(frame, false).await;
+    }
+
+    async fn take(&self, frame: &Frame, fail_fast: bool) -> Result<(), ConnectionError> {
+        let cost = f64::from((self.limit.cost)(frame).min(self.limit.burst));
+        loop {
+            match self.try_acquire(cost) {
+                Ok(()) => return Ok(()),
+                Err(wait) if fail_fast => {
+                    return Err(ConnectionError::WouldExceedRateLimit(wait));
+                }
+                Err(wait) => {
+                    debug!("Rate limited, waiting {wait:?}");
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+}
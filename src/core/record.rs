@@ -0,0 +1,303 @@
+//! Recording of every frame a connection sends and receives, and offline replay
+//! of recordings through a `WebSocketProtocol`.
+//!
+//! Recordings are JSON Lines, one frame per line:
+//!
+//! ```text
+//! {"direction":"received","fin":true,"opcode":"text","text":"hello","timestamp":1760825521323104}
+//! {"base64":"A+g=","direction":"sent","fin":true,"opcode":"close","timestamp":1760825521324020}
+//! ```
+//!
+//! - `timestamp`: microseconds since the Unix epoch.
+//! - `direction`: `sent` or `received`.
+//! - `opcode`: `continuation`, `text`, `binary`, `close`, `ping` or `pong`.
+//! - `fin`: whether the frame is the last one of its message.
+//! - `text`: the payload of a Text frame that is valid UTF-8.
+//! - `base64`: the payload of any other frame.
+
+use super::{
+    context::Context,
+    enums::{Opcode, State},
+    errors::{ParseError, WebSocketError},
+    frame::Frame,
+    protocol::WebSocketProtocol,
+    stream::Reassembler,
+    transport::{Transport, is_data},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use log::warn;
+use serde_json::{Map, Value};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, mpsc},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use strum::{Display, EnumString};
+use tokio::{sync::oneshot, time::Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Writes every frame of a connection to a recording, see `StreamBuilder::recorder`.
+///
+/// Lines are written from a thread of their own and flushed whenever it has
+/// caught up, so a slow disk doesn't slow the connection down. `flush` waits for
+/// the frames recorded so far; the rest is written once every clone is dropped.
+#[derive(Clone)]
+pub struct Recorder {
+    lines: mpsc::Sender<Line>,
+}
+
+enum Line {
+    Frame(String),
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+impl Recorder {
+    /// Records to `path`, replacing the file if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        let (lines, received) = mpsc::channel();
+        thread::spawn(move || write_lines(out, &received));
+        Self { lines }
+    }
+
+    // Failing to record is logged rather than failing the connection.
+    pub(crate) fn record(&self, direction: Direction, frame: &Frame) {
+        let line = encode(SystemTime::now(), direction, frame);
+        if self.lines.send(Line::Frame(line)).is_err() {
+            warn!("Couldn't record a frame: the recorder stopped");
+        }
+    }
+
+    /// Waits until every frame recorded so far is written and flushed.
+    pub async fn flush(&self) -> io::Result<()> {
+        let stopped = || io::Error::other("the recorder stopped");
+        let (done, flushed) = oneshot::channel();
+        self.lines.send(Line::Flush(done)).map_err(|_| stopped())?;
+        flushed.await.map_err(|_| stopped())?
+    }
+}
+
+// Writes lines as they come and flushes once there are none waiting.
+fn write_lines(mut out: impl Write, lines: &mpsc::Receiver<Line>) {
+    let mut written = Ok(());
+    while let Ok(mut line) = lines.recv() {
+        loop {
+            match line {
+                Line::Frame(frame) => {
+                    if let Err(err) = writeln!(out, "{frame}") {
+                        warn!("Couldn't record a frame: {err}");
+                        written = Err(err);
+                    }
+                }
+                Line::Flush(done) => {
+                    let flushed =
+                        std::mem::replace(&mut written, Ok(())).and_then(|()| out.flush());
+                    let _ = done.send(flushed);
+                }
+            }
+            match lines.try_recv() {
+                Ok(next) => line = next,
+                Err(_) => break,
+            }
+        }
+        if let Err(err) = out.flush() {
+            warn!("Couldn't flush the recording: {err}");
+        }
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder").finish_non_exhaustive()
+    }
+}
+
+/// One line of a recording.
+#[derive(Debug, Clone)]
+pub struct RecordedFrame {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub frame: Frame,
+}
+
+/// A recording read back for replaying.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    frames: Vec<RecordedFrame>,
+    realtime: bool,
+    frame_hook: bool,
+}
+
+impl Replay {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, WebSocketError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader(reader: impl BufRead) -> Result<Self, WebSocketError> {
+        let mut frames = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let frame = decode(&line).map_err(|reason| ParseError::RecordingError {
+                line: index + 1,
+                reason,
+            })?;
+            frames.push(frame);
+        }
+        Ok(Self {
+            frames,
+            realtime: false,
+            frame_hook: false,
+        })
+    }
+
+    /// Waits between frames as long as the recorded connection did. Frames are
+    /// replayed back to back by default.
+    #[must_use]
+    pub const fn realtime(mut self, enabled: bool) -> Self {
+        self.realtime = enabled;
+        self
+    }
+
+    /// Calls `WebSocketProtocol::on_frame` with every received frame, like
+    /// `StreamBuilder::frame_hook`.
+    #[must_use]
+    pub const fn frame_hook(mut self, enabled: bool) -> Self {
+        self.frame_hook = enabled;
+        self
+    }
+
+    #[must_use]
+    pub fn frames(&self) -> &[RecordedFrame] {
+        &self.frames
+    }
+
+    /// Feeds the received frames to `protocol` like a live connection would:
+    /// `on_connect` first, then `on_message` for every message up to a Close
+    /// frame, which goes to `on_close`. Fragmented messages are put back together
+    /// and a recording that breaks the protocol ends the replay. Sent frames are
+    /// skipped and whatever `protocol` writes is discarded.
+    pub async fn run<P: WebSocketProtocol + Send>(&self, protocol: &mut P) {
+        let transport = Transport::new(
+            Arc::new(tokio::sync::Mutex::new(Box::new(tokio::io::sink()))),
+            State::OPEN,
+        );
+        protocol.on_connect(transport.clone()).await;
+
+        let started = Instant::now();
+        let first = self.frames.first().map(|recorded| recorded.timestamp);
+        let received = self
+            .frames
+            .iter()
+            .filter(|recorded| recorded.direction == Direction::Received);
+        let mut messages = Reassembler::new(usize::MAX);
+        for recorded in received {
+            if self.realtime
+                && let Some(first) = first
+            {
+                let offset = recorded.timestamp.duration_since(first).unwrap_or_default();
+                tokio::time::sleep_until(started + offset).await;
+            }
+            let frame = recorded.frame.clone();
+            if self.frame_hook {
+                protocol.on_frame(Context::from(frame.clone())).await;
+            }
+            let opcode = frame.headers.opcode;
+            let frame = if is_data(opcode) {
+                match messages.push(frame) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => continue,
+                    Err((_, reason)) => {
+                        warn!("Stopping the replay: {reason}");
+                        break;
+                    }
+                }
+            } else {
+                frame
+            };
+            let ctx = Context::from(frame);
+            if opcode == Opcode::Close {
+                transport.set_state(State::CLOSED);
+                protocol.on_close(ctx).await;
+                return;
+            }
+            protocol.on_message(ctx).await;
+        }
+        transport.set_state(State::CLOSED);
+    }
+}
+
+fn unix_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_micros()).unwrap_or(u64::MAX))
+}
+
+fn encode(timestamp: SystemTime, direction: Direction, frame: &Frame) -> String {
+    let headers = &frame.headers;
+    let mut line = Map::new();
+    line.insert("timestamp".into(), unix_micros(timestamp).into());
+    line.insert("direction".into(), direction.to_string().into());
+    line.insert("opcode".into(), headers.opcode.to_string().into());
+    line.insert("fin".into(), headers.fin.into());
+    match std::str::from_utf8(&frame.payload_data) {
+        Ok(text) if headers.opcode == Opcode::Text => line.insert("text".into(), text.into()),
+        _ => line.insert("base64".into(), STANDARD.encode(&frame.payload_data).into()),
+    };
+    Value::Object(line).to_string()
+}
+
+fn decode(line: &str) -> Result<RecordedFrame, String> {
+    let line: Value = serde_json::from_str(line).map_err(|err| err.to_string())?;
+    let field = |name: &str| line.get(name).ok_or_else(|| format!("`{name}` is missing"));
+    let invalid = |name: &str| format!("`{name}` is invalid");
+
+    let timestamp = field("timestamp")?
+        .as_u64()
+        .map(|micros| UNIX_EPOCH + Duration::from_micros(micros))
+        .ok_or_else(|| invalid("timestamp"))?;
+    let direction: Direction = field("direction")?
+        .as_str()
+        .and_then(|direction| direction.parse().ok())
+        .ok_or_else(|| invalid("direction"))?;
+    let opcode: Opcode = field("opcode")?
+        .as_str()
+        .and_then(|opcode| opcode.parse().ok())
+        .ok_or_else(|| invalid("opcode"))?;
+    let fin = field("fin")?.as_bool().ok_or_else(|| invalid("fin"))?;
+    let payload = match (line.get("text"), line.get("base64")) {
+        (Some(text), _) => text
+            .as_str()
+            .map(|text| text.as_bytes().to_vec())
+            .ok_or_else(|| invalid("text"))?,
+        (None, Some(data)) => data
+            .as_str()
+            .and_then(|data| STANDARD.decode(data).ok())
+            .ok_or_else(|| invalid("base64"))?,
+        (None, None) => return Err("`text` or `base64` is missing".into()),
+    };
+
+    let mut frame = Frame::set_defaults(opcode, &payload);
+    frame.headers.fin = fin;
+    // Only the client masks its frames.
+    frame.headers.mask = direction == Direction::Sent;
+    Ok(RecordedFrame {
+        timestamp,
+        direction,
+        frame,
+    })
+}
@@ -0,0 +1,243 @@
+use super::{context::Context, enums::Opcode, protocol::WebSocketProtocol, transport::Transport};
+use async_trait::async_trait;
+use log::debug;
+#[cfg(feature = "serde")]
+use std::cell::OnceCell;
+use std::{fmt, pin::Pin};
+
+type Handler =
+    Box<dyn Fn(Context, Transport) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+type Predicate = Box<dyn Fn(&Context) -> bool + Send + Sync>;
+type ConnectHandler =
+    Box<dyn Fn(Transport) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+enum Matcher {
+    Opcode(Opcode),
+    TextPrefix(String),
+    #[cfg(feature = "serde")]
+    Field {
+        name: String,
+        value: serde_json::Value,
+    },
+    Custom(Predicate),
+}
+
+// A message being routed. It's parsed as JSON at most once, however many field
+// routes look at it.
+struct Incoming<'a> {
+    ctx: &'a Context,
+    #[cfg(feature = "serde")]
+    json: OnceCell<Option<serde_json::Value>>,
+}
+
+impl Matcher {
+    fn matches(&self, message: &Incoming<'_>) -> bool {
+        let frame = &message.ctx.frame;
+        match self {
+            Self::Opcode(opcode) => frame.headers.opcode == *opcode,
+            Self::TextPrefix(prefix) => {
+                frame.headers.opcode == Opcode::Text
+                    && frame.payload_data.starts_with(prefix.as_bytes())
+            }
+            #[cfg(feature = "serde")]
+            Self::Field { name, value } => message
+                .json
+                .get_or_init(|| message.ctx.json().ok())
+                .as_ref()
+                .and_then(|json| json.get(name))
+                .is_some_and(|field| field == value),
+            Self::Custom(predicate) => predicate(message.ctx),
+        }
+    }
+}
+
+/// Hands every received message to the first handler whose route matches it,
+/// or to the fallback when none does.
+///
+/// Routes are checked in the order they were added, for every message
+/// `on_message` gets, Ping and Pong frames included. Use the router as the
+/// `WebSocketProtocol` of a connection, or call [`Router::dispatch`] from your
+/// own `on_message`.
+///
+/// Handlers run one at a time and the next message isn't routed until the
+/// current handler returns, so a slow handler holds up every message after it.
+/// Hand long work off to a task of its own.
+///
+/// Routing needs the whole message: with `StreamBuilder::stream_messages` the
+/// payload isn't read yet when routes are checked, so text prefix and field
+/// routes never match and messages go to the fallback.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(Matcher, Handler)>,
+    fallback: Option<Handler>,
+    connect: Option<ConnectHandler>,
+    close: Option<Handler>,
+    transport: Option<Transport>,
+}
+
+impl Router {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes messages sent with `opcode`.
+    #[must_use]
+    pub fn on_opcode<H, F>(self, opcode: Opcode, handler: H) -> Self
+    where
+        H: Fn(Context, Transport) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.route(Matcher::Opcode(opcode), handler)
+    }
+
+    /// Routes Text messages starting with `prefix`.
+    #[must_use]
+    pub fn on_text_prefix<H, F>(self, prefix: impl Into<String>, handler: H) -> Self
+    where
+        H: Fn(Context, Transport) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.route(Matcher::TextPrefix(prefix.into()), handler)
+    }
+
+    /// Routes JSON objects whose top-level `name` field equals `value`, e.g.
+    /// `on_field("type", "trade", ...)`.
+    #[cfg(feature = "serde")]
+    #[must_use]
+    pub fn on_field<H, F>(
+        self,
+        name: impl Into<String>,
+        value: impl Into<serde_json::Value>,
+        handler: H,
+    ) -> Self
+    where
+        H: Fn(Context, Transport) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let matcher = Matcher::Field {
+            name: name.into(),
+            value: value.into(),
+        };
+        self.route(matcher, handler)
+    }
+
+    /// Routes messages `predicate` returns `true` for.
+    #[must_use]
+    pub fn on<P, H, F>(self, predicate: P, handler: H) -> Self
+    where
+        P: Fn(&Context) -> bool + Send + Sync + 'static,
+        H: Fn(Context, Transport) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.route(Matcher::Custom(Box::new(predicate)), handler)
+    }
+
+    /// Handles messages no route matched, which are dropped otherwise.
+    #[must_use]
+    pub fn fallback<H, F>(mut self, handler: H) -> Self
+    where
+        H: Fn(Context, Transport) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.fallback = Some(boxed(handler));
+        self
+    }
+
+    /// Runs `handler` whenever the connection opens, reconnects included, before
+    /// any message is routed.
+    #[must_use]
+    pub fn on_connect<H, F>(mut self, handler: H) -> Self
+    where
+        H: Fn(Transport) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.connect = Some(Box::new(move |transport| Box::pin(handler(transport))));
+        self
+    }
+
+    /// Runs `handler` with the Close frame that ended the connection.
+    #[must_use]
+    pub fn on_close<H, F>(mut self, handler: H) -> Self
+    where
+        H: Fn(Context, Transport) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.close = Some(boxed(handler));
+        self
+    }
+
+    fn route<H, F>(mut self, matcher: Matcher, handler: H) -> Self
+    where
+        H: Fn(Context, Transport) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.routes.push((matcher, boxed(handler)));
+        self
+    }
+
+    fn handler_for(&self, ctx: &Context) -> Option<&Handler> {
+        let message = Incoming {
+            ctx,
+            #[cfg(feature = "serde")]
+            json: OnceCell::new(),
+        };
+        self.routes
+            .iter()
+            .find(|(matcher, _)| matcher.matches(&message))
+            .map(|(_, handler)| handler)
+            .or(self.fallback.as_ref())
+    }
+
+    /// Runs the handler `ctx` routes to. Returns `false` when no route matched
+    /// and there is no fallback.
+    pub async fn dispatch(&self, ctx: Context, transport: Transport) -> bool {
+        let Some(handler) = self.handler_for(&ctx) else {
+            debug!("No route for a {:?} message", ctx.frame.headers.opcode);
+            return false;
+        };
+        handler(ctx, transport).await;
+        true
+    }
+}
+
+#[async_trait]
+impl WebSocketProtocol for Router {
+    async fn on_connect(&mut self, transport: Transport) {
+        self.transport = Some(transport.clone());
+        if let Some(handler) = &self.connect {
+            handler(transport).await;
+        }
+    }
+
+    async fn on_message(&mut self, ctx: Context) {
+        if let Some(transport) = self.transport.clone() {
+            self.dispatch(ctx, transport).await;
+        }
+    }
+
+    async fn on_close(&mut self, ctx: Context) {
+        if let (Some(handler), Some(transport)) = (&self.close, self.transport.clone()) {
+            handler(ctx, transport).await;
+        }
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("routes", &self.routes.len())
+            .field("fallback", &self.fallback.is_some())
+            .field("on_connect", &self.connect.is_some())
+            .field("on_close", &self.close.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+fn boxed<H, F>(handler: H) -> Handler
+where
+    H: Fn(Context, Transport) -> F + Send + Sync + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    Box::new(move |ctx, transport| Box::pin(handler(ctx, transport)))
+}
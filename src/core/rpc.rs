@@ -0,0 +1,276 @@
+use super::{
+    context::Context,
+    enums::{Opcode, State},
+    errors::RpcError,
+    transport::Transport,
+};
+use log::debug;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{sync::oneshot, task::AbortHandle};
+
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Reply = Result<Value, RpcError>;
+type NotificationHandler = Box<dyn Fn(&str, Value) + Send + Sync>;
+
+#[derive(Default)]
+struct Shared {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Reply>>>,
+    on_notification: Mutex<Option<NotificationHandler>>,
+    // Runs `fail_pending_on_close`, stopped with the last clone of the client.
+    watcher: OnceLock<AbortHandle>,
+}
+
+impl Shared {
+    fn pending(&self) -> MutexGuard<'_, HashMap<u64, oneshot::Sender<Reply>>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn fail_pending(&self) {
+        let pending: Vec<_> = self.pending().drain().collect();
+        if !pending.is_empty() {
+            debug!("Failing {} pending RPC calls", pending.len());
+        }
+        for (_, reply) in pending {
+            let _ = reply.send(Err(RpcError::Closed));
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(watcher) = self.watcher.get() {
+            watcher.abort();
+        }
+    }
+}
+
+/// JSON-RPC 2.0 client running over a connection's `Transport`.
+///
+/// Create it in `on_connect` and pass every received message to [`RpcClient::handle`]
+/// from `on_message`. Handlers run one at a time, so await `call` from a spawned
+/// task rather than from inside a handler, or the response can't be delivered.
+#[derive(Clone)]
+pub struct RpcClient {
+    transport: Transport,
+    shared: Arc<Shared>,
+    timeout: Duration,
+}
+
+impl RpcClient {
+    /// Pending calls fail with `RpcError::Closed` once the connection is closed,
+    /// and so do calls made while it is closed.
+    #[must_use]
+    pub fn new(transport: Transport) -> Self {
+        let shared = Arc::new(Shared::default());
+        let watcher = tokio::spawn(fail_pending_on_close(
+            transport.clone(),
+            Arc::downgrade(&shared),
+        ));
+        let _ = shared.watcher.set(watcher.abort_handle());
+        Self {
+            transport,
+            shared,
+            timeout: DEFAULT_CALL_TIMEOUT,
+        }
+    }
+
+    /// Timeout used by `call`, defaults to 30 seconds.
+    #[must_use]
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Calls `handler` with the method and params of every notification the
+    /// server sends. Replaces the previous handler.
+    pub fn on_notification(&self, handler: impl Fn(&str, Value) + Send + Sync + 'static) {
+        *self
+            .shared
+            .on_notification
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(handler));
+    }
+
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: impl Serialize,
+    ) -> Result<T, RpcError> {
+        self.call_with_timeout(method, params, self.timeout).await
+    }
+
+    pub async fn call_with_timeout<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: impl Serialize,
+        timeout: Duration,
+    ) -> Result<T, RpcError> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = encode(Some(id), method, params)?;
+
+        let (reply, response) = oneshot::channel();
+        self.shared.pending().insert(id, reply);
+        // Checked once the call is pending, so a close that comes later fails it
+        // through `fail_pending_on_close`. The request would otherwise sit in the
+        // outbound queue until the timeout.
+        if matches!(self.transport.state(), State::CLOSED | State::ERROR) {
+            self.shared.pending().remove(&id);
+            return Err(RpcError::Closed);
+        }
+        if let Err(err) = self.transport.clone().write_text(&request).await {
+            self.shared.pending().remove(&id);
+            return Err(err.into());
+        }
+
+        let result = match tokio::time::timeout(timeout, response).await {
+            Ok(Ok(result)) => result?,
+            Ok(Err(_)) => return Err(RpcError::Closed),
+            Err(_) => {
+                self.shared.pending().remove(&id);
+                return Err(RpcError::Timeout(timeout));
+            }
+        };
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Sends a notification, which the server doesn't answer.
+    pub async fn notify(&self, method: &str, params: impl Serialize) -> Result<(), RpcError> {
+        let notification = encode(None, method, params)?;
+        self.transport.clone().write_text(&notification).await?;
+        Ok(())
+    }
+
+    /// Routes a received message to the call waiting for it, or to the
+    /// notification handler. Returns `false` for anything that isn't a response
+    /// to a pending call or a notification, so the caller can handle it.
+    #[must_use]
+    pub fn handle(&self, ctx: &Context) -> bool {
+        if ctx.frame.headers.opcode != Opcode::Text {
+            return false;
+        }
+        match serde_json::from_slice(&ctx.frame.payload_data) {
+            Ok(Value::Array(batch)) => {
+                let mut handled = false;
+                for message in batch {
+                    handled |= self.dispatch(message);
+                }
+                handled
+            }
+            Ok(message) => self.dispatch(message),
+            Err(_) => false,
+        }
+    }
+
+    fn dispatch(&self, message: Value) -> bool {
+        let Value::Object(mut message) = message else {
+            return false;
+        };
+
+        if let Some(method) = message.get("method").and_then(Value::as_str) {
+            // Requests made by the server carry an id and are left to the caller.
+            if message.contains_key("id") {
+                return false;
+            }
+            let params = message.get("params").cloned().unwrap_or(Value::Null);
+            self.notification(method, params);
+            return true;
+        }
+
+        let Some(id) = message.get("id").and_then(Value::as_u64) else {
+            return false;
+        };
+        let Some(reply) = self.shared.pending().remove(&id) else {
+            debug!("Response for unknown or expired call {id}");
+            return false;
+        };
+        let result = message.remove("error").map_or_else(
+            || Ok(message.remove("result").unwrap_or(Value::Null)),
+            |error| Err(remote_error(&error)),
+        );
+        let _ = reply.send(result);
+        true
+    }
+
+    fn notification(&self, method: &str, params: Value) {
+        let handler = self
+            .shared
+            .on_notification
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match handler.as_ref() {
+            Some(handler) => handler(method, params),
+            None => debug!("Ignoring notification `{method}`"),
+        }
+    }
+}
+
+fn remote_error(error: &Value) -> RpcError {
+    RpcError::Remote {
+        code: error
+            .get("code")
+            .and_then(Value::as_i64)
+            .unwrap_or_default(),
+        message: error
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        data: error.get("data").cloned(),
+    }
+}
+
+impl fmt::Debug for RpcClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcClient")
+            .field("transport", &self.transport)
+            .field("pending", &self.shared.pending().len())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+fn encode(id: Option<u64>, method: &str, params: impl Serialize) -> Result<Vec<u8>, RpcError> {
+    let mut message = Map::new();
+    message.insert("jsonrpc".into(), "2.0".into());
+    if let Some(id) = id {
+        message.insert("id".into(), id.into());
+    }
+    message.insert("method".into(), method.into());
+    // `params` may be omitted, which is how `()` is sent.
+    let params = serde_json::to_value(params)?;
+    if !params.is_null() {
+        message.insert("params".into(), params);
+    }
+    Ok(serde_json::to_vec(&message)?)
+}
+
+// Runs until the connection or the client goes away.
+async fn fail_pending_on_close(transport: Transport, shared: Weak<Shared>) {
+    let mut states = transport.subscribe();
+    drop(transport);
+    loop {
+        let state = *states.borrow_and_update();
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if matches!(state, State::CLOSED | State::ERROR) {
+            shared.fail_pending();
+        }
+        drop(shared);
+        if states.changed().await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use super::enums::Opcode;
+use std::{
+    fmt::{Display, Write},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const OPCODES: [Opcode; 6] = [
+    Opcode::Continuation,
+    Opcode::Text,
+    Opcode::Binary,
+    Opcode::Close,
+    Opcode::Ping,
+    Opcode::Pong,
+];
+
+const fn opcode_index(opcode: Opcode) -> usize {
+    match opcode {
+        Opcode::Continuation => 0,
+        Opcode::Text => 1,
+        Opcode::Binary => 2,
+        Opcode::Close => 3,
+        Opcode::Ping => 4,
+        Opcode::Pong => 5,
+    }
+}
+
+// Messages are counted on the frame that completes them.
+const fn completes_message(opcode: Opcode, fin: bool) -> bool {
+    fin && matches!(opcode, Opcode::Text | Opcode::Binary | Opcode::Continuation)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
+
+#[cfg(feature = "metrics")]
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+fn from_unix_millis(millis: u64) -> Option<SystemTime> {
+    (millis > 0).then(|| UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// Frame counters, one per opcode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameCounts {
+    pub continuation: u64,
+    pub text: u64,
+    pub binary: u64,
+    pub close: u64,
+    pub ping: u64,
+    pub pong: u64,
+}
+
+impl FrameCounts {
+    fn from_counters(counters: &[AtomicU64; 6]) -> Self {
+        let load = |opcode| {
+            counters
+                .get(opcode_index(opcode))
+                .map_or(0, |c| c.load(Ordering::Relaxed))
+        };
+        Self {
+            continuation: load(Opcode::Continuation),
+            text: load(Opcode::Text),
+            binary: load(Opcode::Binary),
+            close: load(Opcode::Close),
+            ping: load(Opcode::Ping),
+            pong: load(Opcode::Pong),
+        }
+    }
+
+    #[must_use]
+    pub const fn get(&self, opcode: Opcode) -> u64 {
+        match opcode {
+            Opcode::Continuation => self.continuation,
+            Opcode::Text => self.text,
+            Opcode::Binary => self.binary,
+            Opcode::Close => self.close,
+            Opcode::Ping => self.ping,
+            Opcode::Pong => self.pong,
+        }
+    }
+
+    #[must_use]
+    pub const fn total(&self) -> u64 {
+        self.continuation + self.text + self.binary + self.close + self.ping + self.pong
+    }
+}
+
+/// Live counters for one connection. They keep counting across reconnects.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_sent: [AtomicU64; 6],
+    frames_received: [AtomicU64; 6],
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    reconnects: AtomicU64,
+    ping_rtt_micros: AtomicU64,
+    connected_since: AtomicU64,
+    last_activity: AtomicU64,
+    ping_sent_at: Mutex<Option<Instant>>,
+}
+
+impl ConnectionStats {
+    pub fn record_sent(&self, opcode: Opcode, fin: bool, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(counter) = self.frames_sent.get(opcode_index(opcode)) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if completes_message(opcode, fin) {
+            self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        }
+        self.touch();
+    }
+
+    pub fn record_received(&self, opcode: Opcode, fin: bool, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(counter) = self.frames_received.get(opcode_index(opcode)) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if completes_message(opcode, fin) {
+            self.messages_received.fetch_add(1, Ordering::Relaxed);
+        }
+        self.touch();
+    }
+
+    pub fn record_connected(&self) {
+        self.connected_since
+            .store(unix_millis(SystemTime::now()), Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_ping_sent(&self) {
+        if let Ok(mut sent_at) = self.ping_sent_at.lock() {
+            *sent_at = Some(Instant::now());
+        }
+    }
+
+    // Called when the Ping couldn't be written, no Pong will answer it.
+    pub fn record_ping_failed(&self) {
+        if let Ok(mut sent_at) = self.ping_sent_at.lock() {
+            *sent_at = None;
+        }
+    }
+
+    // Matches a Pong with the last Ping sent, unsolicited Pongs are ignored.
+    pub fn record_pong_received(&self) {
+        let sent_at = self.ping_sent_at.lock().ok().and_then(|mut s| s.take());
+        if let Some(sent_at) = sent_at {
+            let rtt = u64::try_from(sent_at.elapsed().as_micros()).unwrap_or(u64::MAX);
+            self.ping_rtt_micros.store(rtt, Ordering::Relaxed);
+        }
+    }
+
+    fn touch(&self) {
+        self.last_activity
+            .store(unix_millis(SystemTime::now()), Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let rtt = self.ping_rtt_micros.load(Ordering::Relaxed);
+        StatsSnapshot {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            frames_sent: FrameCounts::from_counters(&self.frames_sent),
+            frames_received: FrameCounts::from_counters(&self.frames_received),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            ping_rtt: (rtt > 0).then(|| Duration::from_micros(rtt)),
+            connected_since: from_unix_millis(self.connected_since.load(Ordering::Relaxed)),
+            last_activity: from_unix_millis(self.last_activity.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Copy of a connection's counters at one point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Bytes written to the socket, frame headers included.
+    pub bytes_sent: u64,
+
+    /// Bytes read from the socket, frame headers included.
+    pub bytes_received: u64,
+
+    pub frames_sent: FrameCounts,
+    pub frames_received: FrameCounts,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub reconnects: u64,
+
+    /// Round-trip time of the last answered `Transport::ping`.
+    pub ping_rtt: Option<Duration>,
+
+    pub connected_since: Option<SystemTime>,
+    pub last_activity: Option<SystemTime>,
+}
+
+impl StatsSnapshot {
+    /// Renders the counters in the Prometheus text exposition format, labelled
+    /// with `connection`. See [`to_prometheus`] for several connections.
+    #[must_use]
+    pub fn to_prometheus(&self, connection: &str) -> String {
+        to_prometheus([(connection, self)])
+    }
+
+    /// Publishes the counters through the `metrics` crate, labelled with `connection`.
+    #[cfg(feature = "metrics")]
+    pub fn record_metrics(&self, connection: &str) {
+        let labels = [("connection", connection.to_string())];
+        metrics::counter!("mayuri_bytes_sent_total", &labels).absolute(self.bytes_sent);
+        metrics::counter!("mayuri_bytes_received_total", &labels).absolute(self.bytes_received);
+        for opcode in OPCODES {
+            let opcode_labels = [
+                ("connection", connection.to_string()),
+                ("opcode", format!("{opcode:?}")),
+            ];
+            metrics::counter!("mayuri_frames_sent_total", &opcode_labels)
+                .absolute(self.frames_sent.get(opcode));
+            metrics::counter!("mayuri_frames_received_total", &opcode_labels)
+                .absolute(self.frames_received.get(opcode));
+        }
+        metrics::counter!("mayuri_messages_sent_total", &labels).absolute(self.messages_sent);
+        metrics::counter!("mayuri_messages_received_total", &labels)
+            .absolute(self.messages_received);
+        metrics::counter!("mayuri_reconnects_total", &labels).absolute(self.reconnects);
+        if let Some(rtt) = self.ping_rtt {
+            metrics::gauge!("mayuri_ping_rtt_seconds", &labels).set(rtt.as_secs_f64());
+        }
+        if let Some(since) = self.connected_since {
+            metrics::gauge!("mayuri_connected_since_seconds", &labels).set(unix_seconds(since));
+        }
+        if let Some(last) = self.last_activity {
+            metrics::gauge!("mayuri_last_activity_seconds", &labels).set(unix_seconds(last));
+        }
+    }
+}
+
+// Extra labels and value of one sample.
+type Samples = Vec<(String, String)>;
+
+// A metric family of the text exporter.
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: fn(&StatsSnapshot) -> Samples,
+}
+
+fn single(value: &impl Display) -> Samples {
+    vec![(String::new(), value.to_string())]
+}
+
+fn per_opcode(counts: &FrameCounts) -> Samples {
+    OPCODES
+        .iter()
+        .map(|&opcode| {
+            let labels = format!(",opcode=\"{opcode:?}\"");
+            (labels, counts.get(opcode).to_string())
+        })
+        .collect()
+}
+
+const FAMILIES: [Family; 10] = [
+    Family {
+        name: "mayuri_bytes_sent_total",
+        kind: "counter",
+        help: "Bytes written to the socket, frame headers included.",
+        samples: |stats| single(&stats.bytes_sent),
+    },
+    Family {
+        name: "mayuri_bytes_received_total",
+        kind: "counter",
+        help: "Bytes read from the socket, frame headers included.",
+        samples: |stats| single(&stats.bytes_received),
+    },
+    Family {
+        name: "mayuri_frames_sent_total",
+        kind: "counter",
+        help: "Frames sent, by opcode.",
+        samples: |stats| per_opcode(&stats.frames_sent),
+    },
+    Family {
+        name: "mayuri_frames_received_total",
+        kind: "counter",
+        help: "Frames received, by opcode.",
+        samples: |stats| per_opcode(&stats.frames_received),
+    },
+    Family {
+        name: "mayuri_messages_sent_total",
+        kind: "counter",
+        help: "Data messages sent.",
+        samples: |stats| single(&stats.messages_sent),
+    },
+    Family {
+        name: "mayuri_messages_received_total",
+        kind: "counter",
+        help: "Data messages received.",
+        samples: |stats| single(&stats.messages_received),
+    },
+    Family {
+        name: "mayuri_reconnects_total",
+        kind: "counter",
+        help: "Reconnects made.",
+        samples: |stats| single(&stats.reconnects),
+    },
+    Family {
+        name: "mayuri_ping_rtt_seconds",
+        kind: "gauge",
+        help: "Round-trip time of the last answered ping.",
+        samples: |stats| {
+            stats
+                .ping_rtt
+                .map(|rtt| single(&rtt.as_secs_f64()))
+                .unwrap_or_default()
+        },
+    },
+    Family {
+        name: "mayuri_connected_since_seconds",
+        kind: "gauge",
+        help: "Unix time the current connection was opened.",
+        samples: |stats| {
+            stats
+                .connected_since
+                .map(|since| single(&(unix_millis(since) / 1000)))
+                .unwrap_or_default()
+        },
+    },
+    Family {
+        name: "mayuri_last_activity_seconds",
+        kind: "gauge",
+        help: "Unix time a frame was last sent or received.",
+        samples: |stats| {
+            stats
+                .last_activity
+                .map(|last| single(&(unix_millis(last) / 1000)))
+                .unwrap_or_default()
+        },
+    },
+];
+
+/// Renders the counters of every connection in the Prometheus text exposition
+/// format, each labelled with its name. Every metric family is declared once,
+/// followed by the samples of all connections.
+#[must_use]
+pub fn to_prometheus<'a>(
+    connections: impl IntoIterator<Item = (&'a str, &'a StatsSnapshot)>,
+) -> String {
+    let connections: Vec<(String, &StatsSnapshot)> = connections
+        .into_iter()
+        .map(|(name, stats)| (escape_label(name), stats))
+        .collect();
+    let mut out = String::new();
+    for family in &FAMILIES {
+        let mut samples = String::new();
+        for (label, stats) in &connections {
+            for (extra, value) in (family.samples)(stats) {
+                let name = family.name;
+                let _ = writeln!(samples, "{name}{{connection=\"{label}\"{extra}}} {value}");
+            }
+        }
+        if samples.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind);
+        out.push_str(&samples);
+    }
+    out
+}
+
+// Escapes a label value, see the Prometheus text exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
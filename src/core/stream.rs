@@ -1,66 +1,124 @@
-use crate::core::utils::set_connection_state;
-
+#[cfg(feature = "record")]
+use super::record::{Direction, Recorder};
 use super::{
+    body::{CHUNK_SIZE, StreamedMessage},
+    connector::{Connector, IpPreference, Keepalive, Resolver},
     context::Context,
-    enums::{Opcode, State},
+    enums::{NORMAL_CLOSURE, Opcode, State},
     errors::{
         ConnectionError::{self, ReadError},
-        ParseError, URIError, WebSocketError,
+        TimeoutError, URIError, WebSocketError,
     },
+    extension::{Extension, Sessions},
     frame::{Frame, Headers},
-    handshake::Handshake,
+    handshake::{Handshake, HandshakeOptions},
+    middleware::Middleware,
     protocol::WebSocketProtocol,
-    transport::Transport,
-    utils::{get_connection_state, get_host, get_socket_address, is_secured},
+    queue::OverflowPolicy,
+    ratelimit::RateLimit,
+    tls::{CertificatePin, TlsOptions},
+    transport::{Transport, TransportOptions, is_data},
+    utils::{
+        get_host, get_host_header, get_port, get_socket_address, get_unix_socket_path, is_secured,
+        is_unix, with_timeout,
+    },
 };
+use crate::core::trace::{close_event, current_span, frame_event, in_span};
 use fluent_uri::Uri;
 use log::{debug, info};
-use rustls_pki_types::{CertificateDer, ServerName, pem::PemObject};
+use rustls_pki_types::ServerName;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::atomic::AtomicU8;
-use tokio::sync::Mutex;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{
+    Mutex, OwnedMutexGuard,
+    mpsc::{UnboundedSender, unbounded_channel},
+};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadHalf, split},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader, ReadHalf, split},
     net::TcpStream,
     spawn,
+    task::JoinHandle,
 };
-use tokio_rustls::{
-    TlsConnector,
-    client::TlsStream,
-    rustls::{ClientConfig, RootCertStore},
-};
-use webpki_roots;
+use tokio_rustls::{TlsConnector, client::TlsStream, rustls::ClientConfig};
+
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_PAYLOAD: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Largest frame payload accepted by default, see `StreamBuilder::max_frame_size`.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 << 20;
+/// Largest reassembled message accepted by default, see
+/// `StreamBuilder::max_message_size`.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+const MAX_CONTROL_PAYLOAD: u8 = 125;
 
 pub struct Stream<P: WebSocketProtocol, R> {
-    user_protocol: Arc<Mutex<P>>,
-    reader: R,
+    handlers: Handlers<P>,
+    reader: BufReader<R>,
     transport: Transport,
-    pub state: Arc<AtomicU8>,
+    read_idle_timeout: Option<Duration>,
+    max_frame_size: usize,
+    frame_hook: bool,
+    messages: Reassembler,
+    streaming: bool,
+    // Message whose body is being streamed to its handler.
+    streamed: Option<StreamedMessage>,
+    subprotocol: Option<String>,
+    extensions: Sessions,
+}
+
+/// Limits for each phase of a connection. `None` waits forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Resolving and connecting the TCP (or Unix) socket, across all attempts.
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    pub handshake: Option<Duration>,
+
+    /// Longest time to wait for more data once the connection is open.
+    pub read_idle: Option<Duration>,
 }
 
 impl<P: WebSocketProtocol + Send + Sync + 'static, R: AsyncRead + Unpin> Stream<P, R> {
     pub async fn new<W: AsyncWrite + Unpin + Send + 'static>(
-        user_protocol: P,
-        mut reader: R,
+        user_protocol: Arc<Mutex<P>>,
+        reader: R,
         mut writer: W,
-        uri: &Uri<String>,
+        builder: &StreamBuilder,
+        transport: Transport,
     ) -> Result<Self, WebSocketError> {
+        let uri = &builder.uri;
+        let timeouts = builder.timeouts;
+        // Buffered so frame headers aren't read a few bytes per call, and so bytes
+        // the server sends right after its handshake response aren't lost.
+        let mut reader = BufReader::new(reader);
         debug!("Running handshake");
-        {
-            let mut handshake = Handshake::new(&mut reader, &mut writer, uri);
-            handshake.run().await?;
-        }
+        let negotiated = {
+            let mut handshake = Handshake::new(&mut reader, &mut writer, uri, &builder.handshake);
+            with_timeout(timeouts.handshake, TimeoutError::Handshake, handshake.run()).await??
+        };
         debug!("Handshake complete");
 
-        let state = Arc::new(AtomicU8::new(State::OPEN.as_u8()));
-        let transport = Self::get_transport(writer, Arc::clone(&state))?;
-        let user_protocol = Arc::new(Mutex::new(user_protocol));
+        transport
+            .attach(writer, negotiated.extensions.clone())
+            .await?;
         let mut stream = Self {
-            user_protocol,
+            handlers: Handlers::new(user_protocol),
             reader,
             transport,
-            state,
+            read_idle_timeout: timeouts.read_idle,
+            max_frame_size: builder.max_frame_size,
+            frame_hook: builder.frame_hook,
+            messages: Reassembler::new(builder.max_message_size),
+            streaming: builder.stream_messages,
+            streamed: None,
+            subprotocol: negotiated.subprotocol,
+            extensions: negotiated.extensions,
         };
 
         stream.post_init();
@@ -70,19 +128,10 @@ impl<P: WebSocketProtocol + Send + Sync + 'static, R: AsyncRead + Unpin> Stream<
         Ok(stream)
     }
 
-    pub fn get_transport<W: AsyncWrite + Unpin + Send + 'static>(
-        writer: W,
-        state: Arc<AtomicU8>,
-    ) -> Result<Transport, ParseError> {
-        let transport = Transport::new(Arc::new(Mutex::new(Box::new(writer))), state);
-
-        Ok(transport)
-    }
-
     pub fn post_init(&mut self) {
-        let proto = Arc::clone(&self.user_protocol);
         let transport = self.transport.clone();
-        spawn(async move { proto.lock().await.on_connect(transport).await });
+        self.handlers
+            .push(|mut proto| async move { proto.on_connect(transport).await });
     }
 
     pub async fn fetch_headers(&mut self) -> Result<Headers, WebSocketError> {
@@ -91,9 +140,7 @@ impl<P: WebSocketProtocol + Send + Sync + 'static, R: AsyncRead + Unpin> Stream<
             Ok(0) => Err(WebSocketError::Stream(ReadError(
                 "Couldn't read Frame Headers".into(),
             ))),
-            Ok(n) => {
-                debug!("Received Frame Headers of length {n}");
-
+            Ok(_) => {
                 let mut headers = Headers::decode(&buf)?;
 
                 let payload_len_ext = if headers.extend_by == 16 {
@@ -112,11 +159,237 @@ impl<P: WebSocketProtocol + Send + Sync + 'static, R: AsyncRead + Unpin> Stream<
         }
     }
 
+    async fn fetch_headers_within_idle_timeout(&mut self) -> Result<Headers, WebSocketError> {
+        let limit = self.read_idle_timeout;
+        match with_timeout(limit, TimeoutError::ReadIdle, self.fetch_headers()).await {
+            Ok(headers) => headers,
+            Err(err) => {
+                self.transport.set_state(State::ERROR);
+                Err(WebSocketError::Timeout(err))
+            }
+        }
+    }
+
+    // Fails the connection with a Protocol Error close, see RFC 6455 section 7.1.7.
+    // The state moves to `ERROR`, only a completed closing handshake is `CLOSED`.
+    async fn fail(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        debug!("Failing the connection: {reason}");
+        if self.transport.state() == State::OPEN {
+            let mut frame = Frame::set_defaults(Opcode::Close, &code.to_be_bytes());
+            let _ = self.transport.write(&mut frame).await;
+        }
+        self.transport.set_state(State::ERROR);
+        Err(WebSocketError::Stream(ReadError(reason.to_string())))
+    }
+
+    // Hands a received frame to the handler, answering Ping and Close frames and
+    // putting fragmented messages back together on the way.
+    async fn dispatch(&mut self, frame: Frame, state: State) -> Result<(), WebSocketError> {
+        if self.frame_hook {
+            let ctx = Context::new(frame.clone())?;
+            self.handlers
+                .push(|mut proto| async move { proto.on_frame(ctx).await });
+        }
+
+        let opcode = frame.headers.opcode;
+        let frame = match opcode {
+            Opcode::Text | Opcode::Binary | Opcode::Continuation => match self.messages.push(frame)
+            {
+                Ok(Some(message)) => message,
+                Ok(None) => return Ok(()),
+                Err((code, reason)) => return self.fail(code, &reason).await,
+            },
+            _ => frame,
+        };
+
+        if opcode == Opcode::Close {
+            if let Some((code, reason)) = close_violation(&frame.payload_data) {
+                return self.fail(code, reason).await;
+            }
+            let close_code = frame.close_code();
+            let ctx = Context::new(frame)?;
+            close_event!("received", close_code);
+
+            // Only answer Close frames the server started with, a reply
+            // to our own Close ends the handshake.
+            if state == State::OPEN {
+                self.transport.set_state(State::CLOSING);
+                let code = close_code.unwrap_or(NORMAL_CLOSURE);
+                let mut frame = Frame::set_defaults(Opcode::Close, &code.to_be_bytes());
+                self.transport.write(&mut frame).await?;
+            }
+            self.transport.set_state(State::CLOSED);
+            self.handlers
+                .push(|mut proto| async move { proto.on_close(ctx).await });
+        } else {
+            if opcode == Opcode::Ping && state == State::OPEN {
+                let mut pong = Frame::set_defaults(Opcode::Pong, &frame.payload_data);
+                self.transport.write(&mut pong).await?;
+            }
+            let messages = if self.transport.middleware().is_empty() || !is_data(opcode) {
+                vec![frame]
+            } else {
+                self.transport
+                    .middleware()
+                    .inbound(frame, &self.transport)
+                    .await
+            };
+            for message in messages {
+                let ctx = Context::new(message)?;
+                self.handlers
+                    .push(|mut proto| async move { proto.on_message(ctx).await });
+            }
+        }
+        Ok(())
+    }
+
+    // Reads the payload of a data frame a chunk at a time into the body of the
+    // message being streamed, starting the message and calling `on_message` on its
+    // first frame.
+    async fn stream_frame(&mut self, headers: Headers, len: usize) -> Result<(), WebSocketError> {
+        let (opcode, fin) = (headers.opcode, headers.fin);
+        if let Some(reason) = fragment_violation(self.streamed.is_some(), opcode) {
+            return self.fail(PROTOCOL_ERROR, reason).await;
+        }
+        let message_len = self.streamed.as_ref().map_or(0, |message| message.len) + len;
+        let max_message_size = self.messages.max_message_size;
+        if message_len > max_message_size {
+            let reason = format!("Message is larger than {max_message_size} bytes");
+            return self.fail(MESSAGE_TOO_BIG, &reason).await;
+        }
+
+        // Extensions need whole frames, so with any negotiated the payload is read
+        // and transformed up front. Otherwise it's streamed from the reader a chunk
+        // at a time, and the hook and the recorder see every chunk after the first
+        // as a Continuation frame, which replays as the same message.
+        let header_len = headers.encoded_len();
+        let mut frame = Frame {
+            headers,
+            payload_data: Vec::new(),
+        };
+        let mut remaining = len;
+        if self.extensions.is_empty() {
+            frame.payload_data = self.read_payload(len.min(CHUNK_SIZE)).await?;
+            remaining -= frame.payload_data.len();
+            frame.headers.fin = fin && remaining == 0;
+            frame.update_payload_len();
+        } else {
+            frame.payload_data = self.read_payload(len).await?;
+            remaining = 0;
+            if let Err(reason) = self.extensions.incoming(&mut frame) {
+                return self.fail(PROTOCOL_ERROR, &reason).await;
+            }
+        }
+        if let Some(reason) =
+            self.messages
+                .check_utf8(opcode, frame.headers.fin, &frame.payload_data)
+        {
+            return self.fail(INVALID_PAYLOAD, reason).await;
+        }
+        self.observe(&frame)?;
+        if self.streamed.is_none() {
+            let (message, body) = StreamedMessage::new();
+            let mut first = Frame::with_payload(opcode, Vec::new());
+            first.headers.rsv1 = frame.headers.rsv1;
+            first.headers.rsv2 = frame.headers.rsv2;
+            first.headers.rsv3 = frame.headers.rsv3;
+            let ctx = Context::streamed(first, body);
+            self.streamed = Some(message);
+            self.handlers
+                .push(|mut proto| async move { proto.on_message(ctx).await });
+        }
+
+        if let Some(message) = &self.streamed {
+            message.send(frame.payload_data).await;
+        }
+        while remaining > 0 {
+            let chunk = self.read_payload(remaining.min(CHUNK_SIZE)).await?;
+            remaining -= chunk.len();
+            let mut next = Frame::with_payload(Opcode::Continuation, chunk);
+            next.headers.fin = fin && remaining == 0;
+            next.headers.mask = false;
+            if let Some(reason) =
+                self.messages
+                    .check_utf8(Opcode::Continuation, next.headers.fin, &next.payload_data)
+            {
+                return self.fail(INVALID_PAYLOAD, reason).await;
+            }
+            self.observe(&next)?;
+            if let Some(message) = &self.streamed {
+                message.send(next.payload_data).await;
+            }
+        }
+
+        frame_event!("received", opcode, len);
+        self.transport
+            .stats_handle()
+            .record_received(opcode, fin, header_len + len);
+
+        if let Some(message) = &mut self.streamed {
+            message.len = message_len;
+        }
+        if fin && let Some(message) = self.streamed.take() {
+            message.finish().await;
+        }
+        Ok(())
+    }
+
+    // Hands a streamed frame to the hook and the recorder.
+    fn observe(&self, frame: &Frame) -> Result<(), WebSocketError> {
+        if self.frame_hook {
+            let ctx = Context::new(frame.clone())?;
+            self.handlers
+                .push(|mut proto| async move { proto.on_frame(ctx).await });
+        }
+        #[cfg(feature = "record")]
+        self.transport.record(Direction::Received, frame);
+        Ok(())
+    }
+
+    // Reads `len` payload bytes. The idle timeout starts over whenever some of
+    // them arrive, so a large payload only fails when the server stalls.
+    async fn read_payload(&mut self, len: usize) -> Result<Vec<u8>, WebSocketError> {
+        let mut payload = vec![0u8; len];
+        let mut filled = 0;
+        while filled < len {
+            let unfilled = payload.get_mut(filled..).unwrap_or_default();
+            let read = self.reader.read(unfilled);
+            let error = match with_timeout(self.read_idle_timeout, TimeoutError::ReadIdle, read)
+                .await
+            {
+                Ok(Ok(0)) => WebSocketError::Stream(ReadError(
+                    "Unexpected EOF while reading a frame payload".into(),
+                )),
+                Ok(Ok(read)) => {
+                    filled += read;
+                    continue;
+                }
+                Ok(Err(err)) => WebSocketError::Stream(ReadError(format!("Unexpected EOF: {err}"))),
+                Err(err) => WebSocketError::Timeout(err),
+            };
+            self.transport.set_state(State::ERROR);
+            return Err(error);
+        }
+        Ok(payload)
+    }
+
     pub async fn read(&mut self) -> Result<(), WebSocketError> {
-        let state = get_connection_state(&self.state);
+        let result = self.read_frame().await;
+        // A body still being streamed won't get the rest of its message.
+        if result.is_err() || matches!(self.transport.state(), State::CLOSED | State::ERROR) {
+            self.streamed = None;
+        }
+        result
+    }
+
+    async fn read_frame(&mut self) -> Result<(), WebSocketError> {
+        let state = self.transport.state();
         match state {
-            State::OPEN => {
-                let headers = self.fetch_headers().await?;
+            State::OPEN | State::CLOSING => {
+                let headers = self.fetch_headers_within_idle_timeout().await?;
+                if let Some(reason) = protocol_violation(&headers, self.extensions.rsv_bits()) {
+                    return self.fail(PROTOCOL_ERROR, reason).await;
+                }
 
                 let final_payload_len = {
                     if headers.extend_by > 0 {
@@ -125,91 +398,620 @@ impl<P: WebSocketProtocol + Send + Sync + 'static, R: AsyncRead + Unpin> Stream<
                         u64::from(headers.payload_len)
                     }
                 };
+                let final_payload_len = match usize::try_from(final_payload_len) {
+                    Ok(len) if len <= self.max_frame_size => len,
+                    _ => {
+                        let reason = format!(
+                            "Frame payload of {final_payload_len} bytes is larger than {}",
+                            self.max_frame_size
+                        );
+                        return self.fail(MESSAGE_TOO_BIG, &reason).await;
+                    }
+                };
 
-                debug!("Frame Headers: {headers:?}");
+                if self.streaming && is_data(headers.opcode) {
+                    return self.stream_frame(headers, final_payload_len).await;
+                }
 
-                let mut buf = vec![0u8; final_payload_len as usize];
+                // An empty payload is read right away.
+                let buf = self.read_payload(final_payload_len).await?;
+                let header_len = headers.encoded_len();
+                let mut frame = Frame::decode(&buf, headers)?;
+                let opcode = frame.headers.opcode;
+                frame_event!("received", opcode, frame.payload_data.len());
+                let counters = self.transport.stats_handle();
+                counters.record_received(
+                    opcode,
+                    frame.headers.fin,
+                    header_len + frame.payload_data.len(),
+                );
+                if opcode == Opcode::Pong {
+                    counters.record_pong_received();
+                }
+                if let Err(reason) = self.extensions.incoming(&mut frame) {
+                    return self.fail(PROTOCOL_ERROR, &reason).await;
+                }
+                #[cfg(feature = "record")]
+                self.transport.record(Direction::Received, &frame);
 
-                match self.reader.read_exact(&mut buf).await {
-                    Ok(0) => {
-                        set_connection_state(State::CLOSED, &self.state);
-                        Err(WebSocketError::Stream(ReadError("Unexpected EOF".into())))
-                    }
+                self.dispatch(frame, state).await
+            }
 
-                    Ok(n) => {
-                        debug!("Received {n} bytes of data");
+            State::CLOSED | State::ERROR => Err(WebSocketError::Stream(
+                ConnectionError::ReadError(String::from("Connection is Closed")),
+            )),
+            State::CONNECTING => Err(WebSocketError::Stream(ReadError(format!(
+                "Unknown State {state:?}"
+            )))),
+        }
+    }
+}
 
-                        let frame = Frame::decode(&buf, headers)?;
-                        let opcode = frame.headers.opcode;
-                        let ctx = Context::new(frame)?;
+// Puts received messages back together from their frames, checking their size
+// and, for Text messages, their UTF-8 as each frame arrives.
+pub(crate) struct Reassembler {
+    max_message_size: usize,
+    // First frame of the message being received, its payload grows with every
+    // Continuation frame.
+    fragmented: Option<Frame>,
+    // Set while a Text message is being received.
+    utf8: Option<Utf8Validator>,
+}
 
-                        let proto = Arc::clone(&self.user_protocol);
-                        if opcode == Opcode::Close {
-                            debug!("WebSocket Connection is closing now");
+impl Reassembler {
+    pub const fn new(max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            fragmented: None,
+            utf8: None,
+        }
+    }
 
-                            set_connection_state(State::CLOSING, &self.state);
-                            let mut frame = Frame::set_defaults(Opcode::Close, b"1000");
-                            self.transport.write(&mut frame).await?;
-                            tokio::spawn(async move { proto.lock().await.on_close(ctx).await });
-                        } else {
-                            tokio::spawn(async move { proto.lock().await.on_message(ctx).await });
-                        }
+    // Returns the complete message once its last frame arrived, or the close code
+    // and reason to fail the connection with.
+    pub fn push(&mut self, frame: Frame) -> Result<Option<Frame>, (u16, String)> {
+        let fin = frame.headers.fin;
+        let fragmented = self.fragmented.take();
+        if let Some(reason) = fragment_violation(fragmented.is_some(), frame.headers.opcode) {
+            return Err((PROTOCOL_ERROR, reason.into()));
+        }
+        if let Some(reason) = self.check_utf8(frame.headers.opcode, fin, &frame.payload_data) {
+            return Err((INVALID_PAYLOAD, reason.into()));
+        }
+        let message = match fragmented {
+            None => frame,
+            Some(mut message) => {
+                message.payload_data.extend_from_slice(&frame.payload_data);
+                message
+            }
+        };
 
-                        Ok(())
-                    }
-                    Err(err) => {
-                        set_connection_state(State::CLOSED, &self.state);
-                        Err(WebSocketError::Stream(ReadError(format!(
-                            "Unexpected EOF: {err}"
-                        ))))
-                    }
-                }
+        let len = message.payload_data.len();
+        if len > self.max_message_size {
+            let reason = format!("Message is larger than {} bytes", self.max_message_size);
+            return Err((MESSAGE_TOO_BIG, reason));
+        }
+        if !fin {
+            self.fragmented = Some(message);
+            return Ok(None);
+        }
+        if message.headers.fin {
+            return Ok(Some(message));
+        }
+        // The message keeps the opcode and RSV bits of its first frame.
+        let first = message.headers;
+        let mut complete = Frame::with_payload(first.opcode, message.payload_data);
+        complete.headers.rsv1 = first.rsv1;
+        complete.headers.rsv2 = first.rsv2;
+        complete.headers.rsv3 = first.rsv3;
+        complete.headers.mask = false;
+        Ok(Some(complete))
+    }
+
+    // Checks the payload of a data frame as the next part of a Text message, so
+    // invalid UTF-8 fails the connection as soon as it arrives.
+    pub fn check_utf8(
+        &mut self,
+        opcode: Opcode,
+        fin: bool,
+        payload: &[u8],
+    ) -> Option<&'static str> {
+        match opcode {
+            Opcode::Text => self.utf8 = Some(Utf8Validator::default()),
+            Opcode::Binary => self.utf8 = None,
+            _ => {}
+        }
+        let validator = self.utf8.as_mut()?;
+        if !validator.push(payload) || (fin && !validator.is_complete()) {
+            return Some("Text message isn't valid UTF-8");
+        }
+        if fin {
+            self.utf8 = None;
+        }
+        None
+    }
+}
+
+// Checks a data frame against the message being received, if any.
+const fn fragment_violation(in_message: bool, opcode: Opcode) -> Option<&'static str> {
+    match (in_message, opcode) {
+        (false, Opcode::Continuation) => Some("Continuation frame without a message to continue"),
+        (true, Opcode::Text | Opcode::Binary) => {
+            Some("New message started before the previous one ended")
+        }
+        _ => None,
+    }
+}
+
+type Handler<P> =
+    Box<dyn FnOnce(OwnedMutexGuard<P>) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+// Runs a connection's handlers one at a time, in the order their frames arrived,
+// on a task of its own so the read loop never waits for them. The handler of a
+// streamed message holds the queue up until it's done with the body.
+struct Handlers<P> {
+    queue: UnboundedSender<Handler<P>>,
+}
+
+impl<P: Send + 'static> Handlers<P> {
+    fn new(proto: Arc<Mutex<P>>) -> Self {
+        let (queue, mut handlers) = unbounded_channel::<Handler<P>>();
+        spawn_handler(async move {
+            while let Some(handler) = handlers.recv().await {
+                let handler = handler(Arc::clone(&proto).lock_owned().await);
+                // On a task of its own, a handler that panics only loses its call.
+                let _ = spawn_handler(handler).await;
             }
+        });
+        Self { queue }
+    }
 
-            State::CLOSED => Err(WebSocketError::Stream(ConnectionError::ReadError(
-                String::from("Connection is Closed"),
-            ))),
-            _ => Err(WebSocketError::Stream(ReadError(format!(
-                "Unknown State {:?}",
-                self.state
-            )))),
+    fn push<H, F>(&self, handler: H)
+    where
+        H: FnOnce(OwnedMutexGuard<P>) -> F + Send + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let _ = self
+            .queue
+            .send(Box::new(move |proto| Box::pin(handler(proto))));
+    }
+}
+
+// Checks the payload of a received Close frame, see RFC 6455 sections 5.5.1
+// and 7.4.
+fn close_violation(payload: &[u8]) -> Option<(u16, &'static str)> {
+    let Some((code, reason)) = payload.split_first_chunk::<2>() else {
+        return (!payload.is_empty()).then_some((PROTOCOL_ERROR, "Close payload of 1 byte"));
+    };
+    if !matches!(u16::from_be_bytes(*code), 1000..=1003 | 1007..=1014 | 3000..=4999) {
+        return Some((PROTOCOL_ERROR, "Invalid close code"));
+    }
+    if std::str::from_utf8(reason).is_err() {
+        return Some((INVALID_PAYLOAD, "Close reason isn't valid UTF-8"));
+    }
+    None
+}
+
+// Validates UTF-8 split across frames and chunks, holding back the bytes of a
+// character cut off at the end of one.
+#[derive(Default)]
+struct Utf8Validator {
+    partial: Vec<u8>,
+}
+
+impl Utf8Validator {
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        let joined;
+        let bytes = if self.partial.is_empty() {
+            bytes
+        } else {
+            joined = [self.partial.as_slice(), bytes].concat();
+            joined.as_slice()
+        };
+        match std::str::from_utf8(bytes) {
+            Ok(_) => {
+                self.partial.clear();
+                true
+            }
+            // `error_len` is `None` when the bytes end in the middle of a character.
+            Err(err) if err.error_len().is_none() => {
+                self.partial = bytes.get(err.valid_up_to()..).unwrap_or_default().to_vec();
+                true
+            }
+            Err(_) => false,
         }
     }
+
+    const fn is_complete(&self) -> bool {
+        self.partial.is_empty()
+    }
+}
+
+// Checks a frame's headers against RFC 6455 section 5. RSV bits may only be set
+// on data frames, when a negotiated extension owns them.
+fn protocol_violation(headers: &Headers, extension_rsv: u8) -> Option<&'static str> {
+    let is_control = matches!(headers.opcode, Opcode::Close | Opcode::Ping | Opcode::Pong);
+    if headers.mask {
+        Some("Server frames must not be masked")
+    } else if is_control && headers.rsv_bits() != 0 {
+        Some("Control frames must not set RSV bits")
+    } else if headers.rsv_bits() & !extension_rsv != 0 {
+        Some("RSV bits set without a negotiated extension owning them")
+    } else if is_control && !headers.fin {
+        Some("Control frames must not be fragmented")
+    } else if is_control && headers.payload_len > MAX_CONTROL_PAYLOAD {
+        Some("Control frame payload is longer than 125 bytes")
+    } else {
+        None
+    }
+}
+
+// Runs a user callback on its own task, inside the connection's span.
+fn spawn_handler(handler: impl Future<Output = ()> + Send + 'static) -> JoinHandle<()> {
+    let span = current_span();
+    spawn(async move { in_span(&span, handler).await })
 }
 
+/// Any bidirectional byte stream a WebSocket connection can run over.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
 pub enum StreamType<P: WebSocketProtocol> {
     Plain(Stream<P, ReadHalf<TcpStream>>),
     Secured(Stream<P, ReadHalf<TlsStream<TcpStream>>>),
+    #[cfg(unix)]
+    Unix(Stream<P, ReadHalf<UnixStream>>),
+    Custom(Stream<P, ReadHalf<BoxedStream>>),
 }
 
+macro_rules! each_stream {
+    ($stream_type:expr, $stream:ident => $body:expr) => {
+        match $stream_type {
+            StreamType::Plain($stream) => $body,
+            StreamType::Secured($stream) => $body,
+            #[cfg(unix)]
+            StreamType::Unix($stream) => $body,
+            StreamType::Custom($stream) => $body,
+        }
+    };
+}
+
+impl<P: WebSocketProtocol + Send + Sync + 'static> StreamType<P> {
+    pub async fn read(&mut self) -> Result<(), WebSocketError> {
+        each_stream!(self, stream => stream.read().await)
+    }
+
+    #[must_use]
+    pub fn state(&self) -> State {
+        each_stream!(self, stream => stream.transport.state())
+    }
+
+    #[must_use]
+    pub fn transport(&self) -> Transport {
+        each_stream!(self, stream => stream.transport.clone())
+    }
+
+    #[must_use]
+    pub fn subprotocol(&self) -> Option<&str> {
+        each_stream!(self, stream => stream.subprotocol.as_deref())
+    }
+
+    #[must_use]
+    pub fn extensions(&self) -> Vec<&str> {
+        each_stream!(self, stream => stream.extensions.names())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct StreamBuilder {
     uri: Uri<String>,
-    cafile: Option<PathBuf>,
+    tls: TlsOptions,
+    connector: Connector,
+    timeouts: Timeouts,
+    transport: TransportOptions,
+    max_frame_size: usize,
+    max_message_size: usize,
+    frame_hook: bool,
+    stream_messages: bool,
+    handshake: HandshakeOptions,
 }
 
 impl StreamBuilder {
     pub fn new(uri: Uri<String>, cert_path: Option<String>) -> Result<Self, WebSocketError> {
-        let cafile = cert_path.map(PathBuf::from);
+        let mut tls = TlsOptions::default();
+        if let Some(path) = cert_path {
+            tls.set_cafile(PathBuf::from(path));
+        }
 
-        Ok(Self { uri, cafile })
+        Ok(Self {
+            uri,
+            tls,
+            connector: Connector::default(),
+            timeouts: Timeouts::default(),
+            transport: TransportOptions::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            frame_hook: false,
+            stream_messages: false,
+            handshake: HandshakeOptions::default(),
+        })
     }
 
-    fn get_tls_config(&self) -> Result<ClientConfig, ConnectionError> {
-        let mut root_cert_store = RootCertStore::empty();
-        match &self.cafile {
-            Some(n) => {
-                for cert in CertificateDer::pem_file_iter(n).into_iter().flatten() {
-                    let _ = match cert {
-                        Ok(n) => Ok(root_cert_store.add(n)),
-                        Err(e) => Err(ConnectionError::ConnectorError(e.to_string())),
-                    }?;
-                }
-            }
-            None => root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    #[must_use]
+    pub const fn uri(&self) -> &Uri<String> {
+        &self.uri
+    }
+
+    /// Adds a header to the handshake request, e.g. `Authorization` or `Origin`.
+    /// Headers the handshake sets itself are rejected when connecting.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.handshake.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Offers `protocol` through `Sec-WebSocket-Protocol`. Can be called more than
+    /// once, in order of preference. The server's choice is available from
+    /// `WebSocket::subprotocol`.
+    #[must_use]
+    pub fn subprotocol(mut self, protocol: impl Into<String>) -> Self {
+        self.handshake.subprotocols.push(protocol.into());
+        self
+    }
+
+    /// Offers `extension` in the handshake. Offers are sent in the order they're
+    /// added, see [`Extension`].
+    #[must_use]
+    pub fn extension(mut self, extension: impl Extension + 'static) -> Self {
+        self.handshake.extensions.push(Arc::new(extension));
+        self
+    }
+
+    /// Uses a caller provided rustls `ClientConfig` for `wss` connections.
+    /// Every other TLS option on the builder is ignored when this is set.
+    #[must_use]
+    pub fn tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.tls.set_client_config(config);
+        self
+    }
+
+    /// Trusts the certificates in a PEM file instead of the bundled `webpki_roots`.
+    #[must_use]
+    pub fn cafile(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tls.set_cafile(path.into());
+        self
+    }
+
+    /// Trusts the bundled `webpki_roots`. Enabled by default unless a `cafile` is set.
+    #[must_use]
+    pub const fn webpki_roots(mut self, enabled: bool) -> Self {
+        self.tls.set_webpki_roots(enabled);
+        self
+    }
+
+    /// Trusts the platform's native root certificates alongside the other roots.
+    #[must_use]
+    pub const fn native_roots(mut self, enabled: bool) -> Self {
+        self.tls.set_native_roots(enabled);
+        self
+    }
+
+    /// Protocols offered through ALPN, in order of preference.
+    #[must_use]
+    pub fn alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        self.tls.set_alpn_protocols(protocols);
+        self
+    }
+
+    /// Only accepts servers whose end-entity certificate matches `pin`. Can be called
+    /// more than once, any matching pin is accepted.
+    #[must_use]
+    pub fn pin(mut self, pin: CertificatePin) -> Self {
+        self.tls.add_pin(pin);
+        self
+    }
+
+    /// Skips certificate chain and hostname validation. Configured pins are still
+    /// checked. Only meant for testing against local servers.
+    #[must_use]
+    pub const fn danger_accept_invalid_certs(mut self, enabled: bool) -> Self {
+        self.tls.set_accept_invalid_certs(enabled);
+        self
+    }
+
+    /// Resolves host names with `resolver` instead of the system resolver.
+    #[must_use]
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+        self.connector.set_resolver(Arc::new(resolver));
+        self
+    }
+
+    /// Restricts or orders the address families tried when connecting.
+    #[must_use]
+    pub const fn ip_preference(mut self, preference: IpPreference) -> Self {
+        self.connector.set_preference(preference);
+        self
+    }
+
+    /// How long to wait on a connection attempt before also trying the next
+    /// resolved address. Defaults to 250ms.
+    #[must_use]
+    pub const fn connection_attempt_delay(mut self, delay: Duration) -> Self {
+        self.connector.set_attempt_delay(delay);
+        self
+    }
+
+    /// Gives up on a single resolved address after `limit`.
+    #[must_use]
+    pub const fn attempt_timeout(mut self, limit: Duration) -> Self {
+        self.connector.set_attempt_timeout(Some(limit));
+        self
+    }
+
+    /// Sets `TCP_NODELAY`, disabling Nagle's algorithm.
+    #[must_use]
+    pub fn nodelay(mut self, enabled: bool) -> Self {
+        self.connector.socket_options_mut().nodelay = enabled;
+        self
+    }
+
+    /// Enables OS-level TCP keepalive probes.
+    #[must_use]
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.connector.socket_options_mut().keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets `SO_SNDBUF`.
+    #[must_use]
+    pub fn send_buffer_size(mut self, size: u32) -> Self {
+        self.connector.socket_options_mut().send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `SO_RCVBUF`.
+    #[must_use]
+    pub fn recv_buffer_size(mut self, size: u32) -> Self {
+        self.connector.socket_options_mut().recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Binds the socket to a local address before connecting.
+    #[must_use]
+    pub fn local_address(mut self, address: IpAddr) -> Self {
+        self.connector.socket_options_mut().local_address = Some(address);
+        self
+    }
+
+    /// Binds the socket to a network interface, e.g. `eth1`. Linux only.
+    #[must_use]
+    pub fn interface(mut self, interface: impl Into<String>) -> Self {
+        self.connector.socket_options_mut().interface = Some(interface.into());
+        self
+    }
+
+    /// Limits resolving and connecting, across every attempted address.
+    #[must_use]
+    pub const fn connect_timeout(mut self, limit: Duration) -> Self {
+        self.timeouts.connect = Some(limit);
+        self
+    }
+
+    #[must_use]
+    pub const fn tls_timeout(mut self, limit: Duration) -> Self {
+        self.timeouts.tls = Some(limit);
+        self
+    }
+
+    /// Limits the HTTP upgrade request and response.
+    #[must_use]
+    pub const fn handshake_timeout(mut self, limit: Duration) -> Self {
+        self.timeouts.handshake = Some(limit);
+        self
+    }
+
+    /// Fails `WebSocket::run` when nothing arrives for `limit`, between frames or
+    /// in the middle of one.
+    #[must_use]
+    pub const fn read_idle_timeout(mut self, limit: Duration) -> Self {
+        self.timeouts.read_idle = Some(limit);
+        self
+    }
+
+    /// Holds up to `capacity` messages written while the connection isn't open,
+    /// during the initial connect or a reconnect, and sends them in order once it
+    /// is. Control frames are never queued. A message that can't be sent fails
+    /// the connect and stays queued for the next one. Fails when `capacity` is
+    /// zero, which would drop every message or fail every write.
+    pub fn outbound_queue(
+        mut self,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> Result<Self, ConnectionError> {
+        if capacity == 0 {
+            return Err(ConnectionError::InvalidQueue(
+                "`capacity` must be at least 1".into(),
+            ));
         }
-        Ok(ClientConfig::builder()
-            .with_root_certificates(root_cert_store)
-            .with_no_client_auth())
+        self.transport.queue = Some((capacity, policy));
+        Ok(self)
+    }
+
+    /// Largest frame payload accepted from the server, 64 MiB by default. Larger
+    /// frames fail the connection with close code 1009.
+    #[must_use]
+    pub const fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Largest message accepted from the server once its fragments are put
+    /// together, 64 MiB by default. Larger messages fail the connection with close
+    /// code 1009.
+    #[must_use]
+    pub const fn max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Calls `WebSocketProtocol::on_frame` with every received frame.
+    #[must_use]
+    pub const fn frame_hook(mut self, enabled: bool) -> Self {
+        self.frame_hook = enabled;
+        self
+    }
+
+    /// Calls `on_message` as soon as the first frame of a data message arrives,
+    /// with the payload read from [`Context::body`] as it comes in, so messages of
+    /// any size take constant memory. The size limits still apply, raise them for
+    /// large transfers.
+    ///
+    /// The connection only reads on while the body is read, or dropped. Handlers
+    /// of frames received in the meantime run once the message's handler returns.
+    /// `frame_hook` and `recorder` see frames longer than 64 KiB as 64 KiB frames,
+    /// the ones after the first as Continuation frames.
+    #[must_use]
+    pub const fn stream_messages(mut self, enabled: bool) -> Self {
+        self.stream_messages = enabled;
+        self
+    }
+
+    /// Adds `middleware` to the end of the chain messages go through, see
+    /// [`Middleware`].
+    #[must_use]
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.transport.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Limits how fast data messages are sent, see [`RateLimit`].
+    #[must_use]
+    pub const fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.transport.rate_limit = Some(limit);
+        self
+    }
+
+    /// Writes every frame sent and received to `recorder`, across reconnects.
+    /// See [`Replay`](crate::Replay) for playing a recording back.
+    #[cfg(feature = "record")]
+    #[must_use]
+    pub fn recorder(mut self, recorder: Recorder) -> Self {
+        self.transport.recorder = Some(Arc::new(recorder));
+        self
+    }
+
+    pub(crate) const fn transport_options(&self) -> &TransportOptions {
+        &self.transport
+    }
+
+    async fn connect_tcp(&self, uri: &Uri<String>) -> Result<TcpStream, WebSocketError> {
+        let host = get_host_header(uri)?;
+        let port = get_port(uri)?;
+        let connect = self.connector.connect(&host, port);
+        Ok(with_timeout(self.timeouts.connect, TimeoutError::Connect, connect).await??)
     }
 
     async fn wrap_tls(
@@ -217,8 +1019,8 @@ impl StreamBuilder {
         tcp_stream: TcpStream,
         uri: &Uri<String>,
     ) -> Result<TlsStream<TcpStream>, WebSocketError> {
-        let tls_config = self.get_tls_config()?;
-        let tls_connector = TlsConnector::from(Arc::new(tls_config));
+        let tls_config = self.tls.build_client_config()?;
+        let tls_connector = TlsConnector::from(tls_config);
         let maybe_auth = uri.authority();
         let auth = maybe_auth.map_or_else(
             || {
@@ -230,43 +1032,124 @@ impl StreamBuilder {
         )?;
         let dnsname = ServerName::try_from(get_host(&auth))
             .map_err(|e| WebSocketError::Uri(super::errors::URIError::DNSError(e)))?;
-        let tls_stream = tls_connector.connect(dnsname, tcp_stream).await?;
+        let connect = tls_connector.connect(dnsname, tcp_stream);
+        let tls_stream = with_timeout(self.timeouts.tls, TimeoutError::Tls, connect).await??;
         Ok(tls_stream)
     }
 
     async fn create_secured_stream<P: WebSocketProtocol + Send + Sync + 'static>(
         &self,
-        user_protocol: P,
+        user_protocol: Arc<Mutex<P>>,
+        transport: Transport,
         uri: &Uri<String>,
     ) -> Result<Stream<P, ReadHalf<TlsStream<TcpStream>>>, WebSocketError> {
-        let addr = get_socket_address(uri)?;
-        let tcp_stream = TcpStream::connect(addr).await?;
+        let tcp_stream = self.connect_tcp(uri).await?;
         let tls_stream = self.wrap_tls(tcp_stream, uri).await?;
 
         let (tls_reader, tls_writer) = split(tls_stream);
-        Stream::new(user_protocol, tls_reader, tls_writer, uri).await
+        Stream::new(user_protocol, tls_reader, tls_writer, self, transport).await
     }
 
     async fn create_plain_stream<P: WebSocketProtocol + Send + Sync + 'static>(
         &self,
-        user_protocol: P,
+        user_protocol: Arc<Mutex<P>>,
+        transport: Transport,
         uri: &Uri<String>,
     ) -> Result<Stream<P, ReadHalf<TcpStream>>, WebSocketError> {
-        let addr = get_socket_address(uri)?;
-        let tcp_stream = TcpStream::connect(addr).await?;
+        let tcp_stream = self.connect_tcp(uri).await?;
 
         let (tcp_reader, tcp_writer) = split(tcp_stream);
-        Stream::new(user_protocol, tcp_reader, tcp_writer, uri).await
+        Stream::new(user_protocol, tcp_reader, tcp_writer, self, transport).await
+    }
+
+    #[cfg(unix)]
+    async fn create_unix_stream<P: WebSocketProtocol + Send + Sync + 'static>(
+        &self,
+        user_protocol: Arc<Mutex<P>>,
+        transport: Transport,
+        uri: &Uri<String>,
+    ) -> Result<Stream<P, ReadHalf<UnixStream>>, WebSocketError> {
+        let path = get_unix_socket_path(uri)?;
+        let connect = UnixStream::connect(path);
+        let unix_stream =
+            with_timeout(self.timeouts.connect, TimeoutError::Connect, connect).await??;
+
+        let (unix_reader, unix_writer) = split(unix_stream);
+        Stream::new(user_protocol, unix_reader, unix_writer, self, transport).await
+    }
+
+    /// Runs the handshake over an already established `io` stream instead of
+    /// opening a new connection. The URI is only used for the handshake request.
+    pub async fn build_stream_from<P: WebSocketProtocol + Send + Sync + 'static>(
+        &self,
+        io: impl AsyncStream + 'static,
+        user_protocol: P,
+    ) -> Result<StreamType<P>, WebSocketError> {
+        self.open_from(
+            io,
+            Arc::new(Mutex::new(user_protocol)),
+            Transport::detached(&self.transport),
+        )
+        .await
     }
 
     pub async fn build_stream<P: WebSocketProtocol + Send + Sync + 'static>(
         &self,
         user_protocol: P,
+    ) -> Result<StreamType<P>, WebSocketError> {
+        self.open(
+            Arc::new(Mutex::new(user_protocol)),
+            Transport::detached(&self.transport),
+        )
+        .await
+    }
+
+    // Like `build_stream_from`, but reuses the handler and transport of an existing
+    // connection.
+    pub(crate) async fn open_from<P: WebSocketProtocol + Send + Sync + 'static>(
+        &self,
+        io: impl AsyncStream + 'static,
+        user_protocol: Arc<Mutex<P>>,
+        transport: Transport,
+    ) -> Result<StreamType<P>, WebSocketError> {
+        let boxed: BoxedStream = Box::new(io);
+        let (reader, writer) = split(boxed);
+        Ok(StreamType::Custom(
+            Stream::new(user_protocol, reader, writer, self, transport).await?,
+        ))
+    }
+
+    // Like `build_stream`, but reuses the handler and transport of an existing
+    // connection, which is how reconnects keep their state.
+    pub(crate) async fn open<P: WebSocketProtocol + Send + Sync + 'static>(
+        &self,
+        user_protocol: Arc<Mutex<P>>,
+        transport: Transport,
     ) -> Result<StreamType<P>, WebSocketError> {
         let stream_type = if is_secured(&self.uri) {
-            StreamType::Secured(self.create_secured_stream(user_protocol, &self.uri).await?)
+            StreamType::Secured(
+                self.create_secured_stream(user_protocol, transport, &self.uri)
+                    .await?,
+            )
+        } else if is_unix(&self.uri) {
+            #[cfg(unix)]
+            {
+                StreamType::Unix(
+                    self.create_unix_stream(user_protocol, transport, &self.uri)
+                        .await?,
+                )
+            }
+            #[cfg(not(unix))]
+            {
+                return Err(WebSocketError::Uri(URIError::MalformedURIError(
+                    "`ws+unix` URIs are only supported on unix platforms".into(),
+                )));
+            }
         } else {
-            StreamType::Plain(self.create_plain_stream(user_protocol, &self.uri).await?)
+            StreamType::Plain(
+                self.create_plain_stream(user_protocol, transport, &self.uri)
+                    .await?,
+            )
         };
         Ok(stream_type)
     }
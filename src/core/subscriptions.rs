@@ -0,0 +1,195 @@
+use super::{
+    context::Context,
+    enums::{Opcode, State},
+    errors::WebSocketError,
+    frame::Frame,
+    transport::Transport,
+};
+use log::debug;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+type TopicHandler = Arc<dyn Fn(&Context) + Send + Sync>;
+
+/// Builds the messages a server expects for subscribing to a topic, and tells
+/// which topic a received message belongs to.
+pub trait SubscriptionEncoder: Send + Sync {
+    fn subscribe(&self, topic: &str) -> Vec<u8>;
+    fn unsubscribe(&self, topic: &str) -> Vec<u8>;
+
+    /// Topic of a received message, `None` when it isn't a topic message.
+    fn topic_of(&self, ctx: &Context) -> Option<String>;
+
+    /// Opcode of the frames `subscribe` and `unsubscribe` are sent in.
+    fn opcode(&self) -> Opcode {
+        Opcode::Text
+    }
+}
+
+/// Encodes subscriptions as flat JSON objects, e.g.
+/// `{"op": "subscribe", "topic": "trades"}`, and reads the topic of received
+/// messages from the same `topic` field.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone)]
+pub struct JsonEncoder {
+    pub action_field: String,
+    pub topic_field: String,
+}
+
+#[cfg(feature = "serde")]
+impl Default for JsonEncoder {
+    fn default() -> Self {
+        Self {
+            action_field: "op".into(),
+            topic_field: "topic".into(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl JsonEncoder {
+    fn encode(&self, action: &str, topic: &str) -> Vec<u8> {
+        let mut message = serde_json::Map::new();
+        message.insert(self.action_field.clone(), action.into());
+        message.insert(self.topic_field.clone(), topic.into());
+        serde_json::Value::Object(message).to_string().into_bytes()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl SubscriptionEncoder for JsonEncoder {
+    fn subscribe(&self, topic: &str) -> Vec<u8> {
+        self.encode("subscribe", topic)
+    }
+
+    fn unsubscribe(&self, topic: &str) -> Vec<u8> {
+        self.encode("unsubscribe", topic)
+    }
+
+    fn topic_of(&self, ctx: &Context) -> Option<String> {
+        let message: serde_json::Value = ctx.json().ok()?;
+        message
+            .get(&self.topic_field)
+            .and_then(serde_json::Value::as_str)
+            .map(String::from)
+    }
+}
+
+struct Shared {
+    encoder: Box<dyn SubscriptionEncoder>,
+    transport: Mutex<Option<Transport>>,
+    topics: Mutex<BTreeMap<String, TopicHandler>>,
+}
+
+/// Tracks the topics a connection is subscribed to and routes their messages
+/// to per-topic handlers.
+///
+/// Call [`Subscriptions::attach`] from `on_connect`, which also runs after every
+/// reconnect, to (re)send every active subscription, and pass received messages
+/// to [`Subscriptions::dispatch`] from `on_message`.
+#[derive(Clone)]
+pub struct Subscriptions {
+    shared: Arc<Shared>,
+}
+
+impl Subscriptions {
+    pub fn new(encoder: impl SubscriptionEncoder + 'static) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                encoder: Box::new(encoder),
+                transport: Mutex::new(None),
+                topics: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    /// Uses `transport` from now on and subscribes to every active topic on it.
+    pub async fn attach(&self, transport: Transport) -> Result<(), WebSocketError> {
+        *lock(&self.shared.transport) = Some(transport.clone());
+        let topics: Vec<String> = lock(&self.shared.topics).keys().cloned().collect();
+        debug!("Resubscribing to {} topics", topics.len());
+        for topic in topics {
+            self.send(transport.clone(), &self.shared.encoder.subscribe(&topic))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribes to `topic` and routes its messages to `handler`. Subscribing
+    /// to an active topic only replaces its handler. Before `attach` the topic
+    /// is only recorded and gets subscribed to once a transport is attached.
+    pub async fn subscribe(
+        &self,
+        topic: impl Into<String>,
+        handler: impl Fn(&Context) + Send + Sync + 'static,
+    ) -> Result<(), WebSocketError> {
+        let topic = topic.into();
+        let is_new = lock(&self.shared.topics)
+            .insert(topic.clone(), Arc::new(handler))
+            .is_none();
+        match self.open_transport() {
+            Some(transport) if is_new => {
+                self.send(transport, &self.shared.encoder.subscribe(&topic))
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Unsubscribes from `topic`. Returns `false` when it wasn't subscribed to.
+    pub async fn unsubscribe(&self, topic: &str) -> Result<bool, WebSocketError> {
+        if lock(&self.shared.topics).remove(topic).is_none() {
+            return Ok(false);
+        }
+        if let Some(transport) = self.open_transport() {
+            self.send(transport, &self.shared.encoder.unsubscribe(topic))
+                .await?;
+        }
+        Ok(true)
+    }
+
+    #[must_use]
+    pub fn topics(&self) -> Vec<String> {
+        lock(&self.shared.topics).keys().cloned().collect()
+    }
+
+    /// Calls the handler of the topic `ctx` belongs to. Returns `false` when the
+    /// message isn't for an active topic.
+    #[must_use]
+    pub fn dispatch(&self, ctx: &Context) -> bool {
+        let Some(topic) = self.shared.encoder.topic_of(ctx) else {
+            return false;
+        };
+        let handler = lock(&self.shared.topics).get(&topic).cloned();
+        handler.is_some_and(|handler| {
+            handler(ctx);
+            true
+        })
+    }
+
+    fn open_transport(&self) -> Option<Transport> {
+        lock(&self.shared.transport)
+            .clone()
+            .filter(|transport| transport.state() == State::OPEN)
+    }
+
+    async fn send(&self, mut transport: Transport, payload: &[u8]) -> Result<(), WebSocketError> {
+        let mut frame = Frame::set_defaults(self.shared.encoder.opcode(), payload);
+        transport.write(&mut frame).await
+    }
+}
+
+impl fmt::Debug for Subscriptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscriptions")
+            .field("topics", &self.topics())
+            .finish_non_exhaustive()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
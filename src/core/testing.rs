@@ -0,0 +1,510 @@
+//! An in-process WebSocket server for testing `WebSocketProtocol` implementations.
+//!
+//! The server runs on a local port ([`MockServer::bind`]) or over an in-memory
+//! `tokio::io::duplex` ([`MockServer::duplex`]), and can send any frame, valid or
+//! not, at any pace.
+
+use super::{
+    context::Context,
+    enums::Opcode,
+    errors::{ConnectionError, HandshakeFailureError, WebSocketError},
+    handshake::generate_valid_accept,
+    protocol::WebSocketProtocol,
+    stream::{AsyncStream, BoxedStream},
+    transport::Transport,
+    utils::CRLF,
+};
+use async_trait::async_trait;
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::TcpListener,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::JoinHandle,
+};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const DUPLEX_CAPACITY: usize = 64 * 1024;
+
+/// A frame sent by the client, already unmasked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientFrame {
+    pub fin: bool,
+    /// RSV bits, as `extension::RSV1` etc.
+    pub rsv: u8,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl ClientFrame {
+    #[must_use]
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.payload).to_string()
+    }
+
+    #[must_use]
+    pub fn close_code(&self) -> Option<u16> {
+        let code = self.payload.get(..2)?;
+        Some(u16::from_be_bytes([*code.first()?, *code.get(1)?]))
+    }
+}
+
+/// Encodes an unmasked server frame. `opcode` isn't checked, so reserved
+/// opcodes can be sent too.
+#[must_use]
+pub fn encode_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![(u8::from(fin) << 7) | (opcode & 0x0F)];
+    match u16::try_from(payload.len()) {
+        Ok(len) if len < 126 => frame.push(len.to_be_bytes()[1]),
+        Ok(len) => {
+            frame.push(126);
+            frame.extend_from_slice(&len.to_be_bytes());
+        }
+        Err(_) => {
+            frame.push(127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+const fn unexpected(message: String) -> WebSocketError {
+    WebSocketError::Stream(ConnectionError::UnexpectedFrame(message))
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    Send(Vec<u8>),
+    SendSlowly(Vec<u8>, Duration),
+    Expect(Option<Opcode>, Option<Vec<u8>>),
+    ExpectClose(Option<u16>),
+    Sleep(Duration),
+    Disconnect,
+}
+
+/// Steps a [`MockConnection`] plays in order, see [`MockConnection::play`].
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+impl Script {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn send_text(self, text: &str) -> Self {
+        self.send_frame(true, Opcode::Text as u8, text.as_bytes())
+    }
+
+    #[must_use]
+    pub fn send_binary(self, data: &[u8]) -> Self {
+        self.send_frame(true, Opcode::Binary as u8, data)
+    }
+
+    #[must_use]
+    pub fn send_ping(self, payload: &[u8]) -> Self {
+        self.send_frame(true, Opcode::Ping as u8, payload)
+    }
+
+    #[must_use]
+    pub fn send_close(self, code: u16, reason: &str) -> Self {
+        self.send_frame(true, Opcode::Close as u8, &close_payload(code, reason))
+    }
+
+    #[must_use]
+    pub fn send_frame(self, fin: bool, opcode: u8, payload: &[u8]) -> Self {
+        self.send_raw(&encode_frame(fin, opcode, payload))
+    }
+
+    /// Sends `bytes` as they are, e.g. a malformed frame.
+    #[must_use]
+    pub fn send_raw(mut self, bytes: &[u8]) -> Self {
+        self.steps.push(Step::Send(bytes.to_vec()));
+        self
+    }
+
+    /// Sends `bytes` one at a time, waiting `delay` between them.
+    #[must_use]
+    pub fn send_slowly(mut self, bytes: &[u8], delay: Duration) -> Self {
+        self.steps.push(Step::SendSlowly(bytes.to_vec(), delay));
+        self
+    }
+
+    #[must_use]
+    pub fn expect_text(mut self, text: &str) -> Self {
+        self.steps.push(Step::Expect(
+            Some(Opcode::Text),
+            Some(text.as_bytes().to_vec()),
+        ));
+        self
+    }
+
+    #[must_use]
+    pub fn expect_binary(mut self, data: &[u8]) -> Self {
+        self.steps
+            .push(Step::Expect(Some(Opcode::Binary), Some(data.to_vec())));
+        self
+    }
+
+    /// Expects any frame of `opcode`, or any frame at all.
+    #[must_use]
+    pub fn expect_frame(mut self, opcode: Option<Opcode>) -> Self {
+        self.steps.push(Step::Expect(opcode, None));
+        self
+    }
+
+    /// Expects a Close frame, with `code` when given.
+    #[must_use]
+    pub fn expect_close(mut self, code: Option<u16>) -> Self {
+        self.steps.push(Step::ExpectClose(code));
+        self
+    }
+
+    #[must_use]
+    pub fn sleep(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Sleep(duration));
+        self
+    }
+
+    /// Drops the connection without a closing handshake. Ends the script.
+    #[must_use]
+    pub fn disconnect(mut self) -> Self {
+        self.steps.push(Step::Disconnect);
+        self
+    }
+}
+
+/// Accepts client connections on a local port.
+#[derive(Debug)]
+pub struct MockServer {
+    listener: TcpListener,
+}
+
+impl MockServer {
+    /// Listens on a free port of `127.0.0.1`.
+    pub async fn bind() -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind("127.0.0.1:0").await?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// `ws://` URI clients should connect to.
+    pub fn uri(&self) -> io::Result<String> {
+        Ok(format!("ws://{}/", self.local_addr()?))
+    }
+
+    /// Accepts the next client and completes its handshake.
+    pub async fn accept(&self) -> Result<MockConnection, WebSocketError> {
+        let (stream, _) = self.listener.accept().await?;
+        MockConnection::handshake(Box::new(stream)).await
+    }
+
+    /// Accepts one client and plays `script` on it in the background. The task
+    /// returns the frames the script's `expect_*` steps matched, in order.
+    #[must_use]
+    pub fn serve(self, script: Script) -> JoinHandle<Result<Vec<ClientFrame>, WebSocketError>> {
+        tokio::spawn(async move { self.accept().await?.play(&script).await })
+    }
+
+    /// An in-memory connection: pass the stream to `WebSocket::from_stream` and
+    /// accept the other end with the returned [`MockAcceptor`].
+    #[must_use]
+    pub fn duplex() -> (DuplexStream, MockAcceptor) {
+        Self::duplex_with_capacity(DUPLEX_CAPACITY)
+    }
+
+    /// Like `duplex`, writes block once `capacity` bytes are unread, which makes
+    /// a server that doesn't read slow the client down.
+    #[must_use]
+    pub fn duplex_with_capacity(capacity: usize) -> (DuplexStream, MockAcceptor) {
+        let (client, server) = tokio::io::duplex(capacity);
+        (client, MockAcceptor { io: server })
+    }
+}
+
+/// Server end of a [`MockServer::duplex`] connection.
+#[derive(Debug)]
+pub struct MockAcceptor {
+    io: DuplexStream,
+}
+
+impl MockAcceptor {
+    pub async fn accept(self) -> Result<MockConnection, WebSocketError> {
+        MockConnection::handshake(Box::new(self.io)).await
+    }
+}
+
+/// One accepted client connection.
+pub struct MockConnection {
+    io: BoxedStream,
+    request: String,
+}
+
+impl MockConnection {
+    /// Completes the handshake of a client the caller accepted, e.g. over a TLS
+    /// stream.
+    pub async fn accept(io: impl AsyncStream + 'static) -> Result<Self, WebSocketError> {
+        Self::handshake(Box::new(io)).await
+    }
+
+    async fn handshake(mut io: BoxedStream) -> Result<Self, WebSocketError> {
+        let mut buf = Vec::new();
+        while !buf.ends_with(b"\r\n\r\n") {
+            if buf.len() >= MAX_REQUEST_SIZE {
+                return Err(WebSocketError::Handshake(
+                    HandshakeFailureError::HeaderError("Handshake request is too large".into()),
+                ));
+            }
+            buf.push(io.read_u8().await?);
+        }
+        let request = String::from_utf8_lossy(&buf).to_string();
+
+        let mut connection = Self { io, request };
+        let key = connection
+            .request_header("sec-websocket-key")
+            .ok_or_else(|| {
+                WebSocketError::Handshake(HandshakeFailureError::HeaderError(
+                    "Sec-WebSocket-Key is missing".into(),
+                ))
+            })?;
+        // Like most servers, picks the client's first choice of subprotocol.
+        let subprotocol = connection
+            .request_header("sec-websocket-protocol")
+            .and_then(|offered| offered.split(',').next())
+            .map(|protocol| format!("Sec-WebSocket-Protocol: {}{CRLF}", protocol.trim()))
+            .unwrap_or_default();
+        // Accepts every offered extension, with the parameters it was offered with.
+        let extensions = connection
+            .request_header("sec-websocket-extensions")
+            .map(|offered| format!("Sec-WebSocket-Extensions: {offered}{CRLF}"))
+            .unwrap_or_default();
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols{CRLF}\
+            Upgrade: websocket{CRLF}\
+            Connection: Upgrade{CRLF}\
+            Sec-WebSocket-Accept: {}{CRLF}\
+            {subprotocol}{extensions}{CRLF}",
+            generate_valid_accept(key)
+        );
+        connection.send_raw(response.as_bytes()).await?;
+        Ok(connection)
+    }
+
+    /// The client's handshake request, headers included.
+    #[must_use]
+    pub fn request(&self) -> &str {
+        &self.request
+    }
+
+    /// Value of a request header, matched case-insensitively.
+    #[must_use]
+    pub fn request_header(&self, name: &str) -> Option<&str> {
+        self.request.split(CRLF).skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_frame(true, Opcode::Text as u8, text.as_bytes())
+            .await
+    }
+
+    pub async fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_frame(true, Opcode::Binary as u8, data).await
+    }
+
+    pub async fn send_ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.send_frame(true, Opcode::Ping as u8, payload).await
+    }
+
+    pub async fn send_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.send_frame(true, Opcode::Close as u8, &close_payload(code, reason))
+            .await
+    }
+
+    pub async fn send_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        self.send_raw(&encode_frame(fin, opcode, payload)).await
+    }
+
+    /// Writes `bytes` as they are, e.g. a malformed frame.
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.io.write_all(bytes).await?;
+        self.io.flush().await
+    }
+
+    /// Writes `bytes` one at a time, waiting `delay` between them.
+    pub async fn send_slowly(&mut self, bytes: &[u8], delay: Duration) -> io::Result<()> {
+        for byte in bytes {
+            self.send_raw(&[*byte]).await?;
+            tokio::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+
+    /// Sends the first `sent` bytes of a frame and drops the connection.
+    pub async fn disconnect_mid_frame(
+        mut self,
+        opcode: u8,
+        payload: &[u8],
+        sent: usize,
+    ) -> io::Result<()> {
+        let frame = encode_frame(true, opcode, payload);
+        self.send_raw(frame.get(..sent).unwrap_or(&frame)).await?;
+        self.disconnect().await
+    }
+
+    /// Drops the connection without a closing handshake.
+    pub async fn disconnect(mut self) -> io::Result<()> {
+        self.io.shutdown().await
+    }
+
+    /// Reads the next frame the client sent. Fails on unmasked frames.
+    pub async fn recv(&mut self) -> Result<ClientFrame, WebSocketError> {
+        let byte0 = self.io.read_u8().await?;
+        let byte1 = self.io.read_u8().await?;
+        let opcode = Opcode::from_u8(byte0 & 0x0F)?;
+        if byte1 >> 7 == 0 {
+            return Err(unexpected(format!("Unmasked {opcode:?} frame")));
+        }
+
+        let len = match byte1 & 0x7F {
+            126 => u64::from(self.io.read_u16().await?),
+            127 => self.io.read_u64().await?,
+            len => u64::from(len),
+        };
+        let mut mask = [0u8; 4];
+        self.io.read_exact(&mut mask).await?;
+        let len = usize::try_from(len).map_err(|e| unexpected(e.to_string()))?;
+        let mut payload = vec![0u8; len];
+        self.io.read_exact(&mut payload).await?;
+        for (byte, key) in payload.iter_mut().zip(mask.iter().cycle()) {
+            *byte ^= key;
+        }
+
+        Ok(ClientFrame {
+            fin: byte0 >> 7 != 0,
+            rsv: byte0 & 0x70,
+            opcode,
+            payload,
+        })
+    }
+
+    /// Reads the next frame and checks it is a Text frame.
+    pub async fn expect_text(&mut self) -> Result<String, WebSocketError> {
+        let frame = self.expect(Some(Opcode::Text), None).await?;
+        Ok(frame.text())
+    }
+
+    /// Reads the next frame and checks it is a Close frame, returning its code.
+    pub async fn expect_close(&mut self) -> Result<Option<u16>, WebSocketError> {
+        let frame = self.expect(Some(Opcode::Close), None).await?;
+        Ok(frame.close_code())
+    }
+
+    async fn expect(
+        &mut self,
+        opcode: Option<Opcode>,
+        payload: Option<&[u8]>,
+    ) -> Result<ClientFrame, WebSocketError> {
+        let frame = self.recv().await?;
+        if opcode.is_some_and(|opcode| opcode != frame.opcode) {
+            return Err(unexpected(format!(
+                "Expected {opcode:?}, got {:?} `{}`",
+                frame.opcode,
+                frame.text()
+            )));
+        }
+        if payload.is_some_and(|payload| payload != frame.payload) {
+            return Err(unexpected(format!(
+                "Expected `{}`, got `{}`",
+                String::from_utf8_lossy(payload.unwrap_or_default()),
+                frame.text()
+            )));
+        }
+        Ok(frame)
+    }
+
+    /// Plays `script` and returns the frames its `expect_*` steps matched.
+    /// Frames the script doesn't expect are left unread.
+    pub async fn play(mut self, script: &Script) -> Result<Vec<ClientFrame>, WebSocketError> {
+        let mut received = Vec::new();
+        for step in &script.steps {
+            match step {
+                Step::Send(bytes) => self.send_raw(bytes).await?,
+                Step::SendSlowly(bytes, delay) => self.send_slowly(bytes, *delay).await?,
+                Step::Expect(opcode, payload) => {
+                    received.push(self.expect(*opcode, payload.as_deref()).await?);
+                }
+                Step::ExpectClose(code) => {
+                    let frame = self.expect(Some(Opcode::Close), None).await?;
+                    if code.is_some() && frame.close_code() != *code {
+                        return Err(unexpected(format!(
+                            "Expected close code {code:?}, got {:?}",
+                            frame.close_code()
+                        )));
+                    }
+                    received.push(frame);
+                }
+                Step::Sleep(duration) => tokio::time::sleep(*duration).await,
+                Step::Disconnect => {
+                    self.disconnect().await?;
+                    return Ok(received);
+                }
+            }
+        }
+        Ok(received)
+    }
+}
+
+/// A protocol that ignores every event, for tests that drive the connection
+/// through its transport.
+#[derive(Debug)]
+pub struct Idle;
+
+#[async_trait]
+impl WebSocketProtocol for Idle {
+    async fn on_connect(&mut self, _transport: Transport) {}
+
+    async fn on_message(&mut self, _ctx: Context) {}
+
+    async fn on_close(&mut self, _ctx: Context) {}
+}
+
+/// A protocol that hands every message it receives to a channel, for tests that
+/// only check what arrived.
+#[derive(Debug)]
+pub struct Forward(UnboundedSender<Context>);
+
+impl Forward {
+    #[must_use]
+    pub fn new() -> (Self, UnboundedReceiver<Context>) {
+        let (tx, rx) = unbounded_channel();
+        (Self(tx), rx)
+    }
+}
+
+#[async_trait]
+impl WebSocketProtocol for Forward {
+    async fn on_connect(&mut self, _transport: Transport) {}
+
+    async fn on_message(&mut self, ctx: Context) {
+        let _ = self.0.send(ctx);
+    }
+
+    async fn on_close(&mut self, _ctx: Context) {}
+}
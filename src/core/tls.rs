@@ -0,0 +1,254 @@
+use super::errors::ConnectionError;
+use log::{debug, warn};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject};
+use sha2::{Digest, Sha256};
+use std::{path::PathBuf, sync::Arc};
+use tokio_rustls::rustls::{
+    self, CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{self, CryptoProvider},
+};
+use webpki::EndEntityCert;
+
+/// A SHA-256 pin matched against the server's end-entity certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificatePin {
+    /// Hash of the whole DER encoded certificate.
+    Certificate([u8; 32]),
+
+    /// Hash of the DER encoded `SubjectPublicKeyInfo`, survives certificate renewals
+    /// as long as the key pair is kept.
+    Spki([u8; 32]),
+}
+
+impl CertificatePin {
+    fn matches(&self, end_entity: &CertificateDer<'_>) -> bool {
+        match self {
+            Self::Certificate(hash) => Sha256::digest(end_entity.as_ref()).as_slice() == hash,
+            Self::Spki(hash) => EndEntityCert::try_from(end_entity).is_ok_and(|cert| {
+                Sha256::digest(cert.subject_public_key_info().as_ref()).as_slice() == hash
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    client_config: Option<Arc<ClientConfig>>,
+    cafile: Option<PathBuf>,
+    webpki_roots: bool,
+    native_roots: bool,
+    alpn_protocols: Vec<Vec<u8>>,
+    pins: Vec<CertificatePin>,
+    accept_invalid_certs: bool,
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            client_config: None,
+            cafile: None,
+            webpki_roots: true,
+            native_roots: false,
+            alpn_protocols: Vec::new(),
+            pins: Vec::new(),
+            accept_invalid_certs: false,
+        }
+    }
+}
+
+impl TlsOptions {
+    /// Uses `config` as is. Every other TLS option is ignored when this is set.
+    pub fn set_client_config(&mut self, config: Arc<ClientConfig>) {
+        self.client_config = Some(config);
+    }
+
+    /// Trusts the certificates in the PEM file at `path` instead of the bundled
+    /// `webpki_roots`.
+    pub fn set_cafile(&mut self, path: PathBuf) {
+        self.cafile = Some(path);
+        self.webpki_roots = false;
+    }
+
+    pub const fn set_webpki_roots(&mut self, enabled: bool) {
+        self.webpki_roots = enabled;
+    }
+
+    pub const fn set_native_roots(&mut self, enabled: bool) {
+        self.native_roots = enabled;
+    }
+
+    pub fn set_alpn_protocols(&mut self, protocols: Vec<Vec<u8>>) {
+        self.alpn_protocols = protocols;
+    }
+
+    pub fn add_pin(&mut self, pin: CertificatePin) {
+        self.pins.push(pin);
+    }
+
+    pub const fn set_accept_invalid_certs(&mut self, enabled: bool) {
+        self.accept_invalid_certs = enabled;
+    }
+
+    pub fn build_client_config(&self) -> Result<Arc<ClientConfig>, ConnectionError> {
+        if let Some(config) = &self.client_config {
+            return Ok(Arc::clone(config));
+        }
+
+        let provider = CryptoProvider::get_default().map_or_else(
+            || Arc::new(crypto::aws_lc_rs::default_provider()),
+            Arc::clone,
+        );
+
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| ConnectionError::ConnectorError(e.to_string()))?;
+
+        let mut config = if self.accept_invalid_certs || !self.pins.is_empty() {
+            let inner = if self.accept_invalid_certs {
+                warn!("Certificate validation is disabled, do not use this outside of testing");
+                None
+            } else {
+                Some(
+                    WebPkiServerVerifier::builder_with_provider(
+                        Arc::new(self.get_root_cert_store()?),
+                        Arc::clone(&provider),
+                    )
+                    .build()
+                    .map_err(|e| ConnectionError::ConnectorError(e.to_string()))?,
+                )
+            };
+            let verifier = PinningVerifier {
+                inner,
+                pins: self.pins.clone(),
+                provider,
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        } else {
+            builder
+                .with_root_certificates(self.get_root_cert_store()?)
+                .with_no_client_auth()
+        };
+
+        config.alpn_protocols.clone_from(&self.alpn_protocols);
+        Ok(Arc::new(config))
+    }
+
+    fn get_root_cert_store(&self) -> Result<RootCertStore, ConnectionError> {
+        let mut root_cert_store = RootCertStore::empty();
+
+        if let Some(path) = &self.cafile {
+            let certs = CertificateDer::pem_file_iter(path).map_err(|e| {
+                ConnectionError::ConnectorError(format!("Couldn't read {}: {e}", path.display()))
+            })?;
+            for cert in certs {
+                let cert = cert.map_err(|e| {
+                    ConnectionError::ConnectorError(format!(
+                        "Bad certificate in {}: {e}",
+                        path.display()
+                    ))
+                })?;
+                root_cert_store
+                    .add(cert)
+                    .map_err(|e| ConnectionError::ConnectorError(e.to_string()))?;
+            }
+        }
+
+        if self.webpki_roots {
+            root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+
+        if self.native_roots {
+            let native = rustls_native_certs::load_native_certs();
+            for err in &native.errors {
+                warn!("Couldn't load a native root certificate: {err}");
+            }
+            if native.certs.is_empty() && !native.errors.is_empty() {
+                return Err(ConnectionError::ConnectorError(
+                    "Couldn't load any native root certificates".into(),
+                ));
+            }
+            let (added, ignored) = root_cert_store.add_parsable_certificates(native.certs);
+            debug!("Loaded {added} native root certificates, ignored {ignored}");
+        }
+
+        if root_cert_store.is_empty() {
+            return Err(ConnectionError::ConnectorError(
+                "No root certificates to verify the server with".into(),
+            ));
+        }
+        Ok(root_cert_store)
+    }
+}
+
+// Runs the regular webpki verification (unless invalid certificates are accepted)
+// and then checks the end-entity certificate against the configured pins.
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<CertificatePin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(inner) = &self.inner {
+            inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+
+        if self.pins.is_empty() || self.pins.iter().any(|pin| pin.matches(end_entity)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
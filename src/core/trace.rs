@@ -0,0 +1,90 @@
+// Frame and handshake events. With the `tracing` feature these are emitted as
+// `tracing` events with structured fields, otherwise as plain `log` records.
+
+macro_rules! frame_event {
+    ($direction:literal, $opcode:expr, $payload_len:expr) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            direction = $direction,
+            opcode = ?$opcode,
+            payload_len = $payload_len,
+            "frame"
+        );
+        #[cfg(not(feature = "tracing"))]
+        log::debug!(
+            "Frame {}: {:?} with {} bytes of payload",
+            $direction,
+            $opcode,
+            $payload_len
+        );
+    }};
+}
+
+macro_rules! close_event {
+    ($direction:literal, $code:expr) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!(direction = $direction, close_code = ?$code, "close");
+        #[cfg(not(feature = "tracing"))]
+        log::debug!("Close {} with code {:?}", $direction, $code);
+    }};
+}
+
+macro_rules! handshake_event {
+    ($headers:expr) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            http_version = %$headers.http_version,
+            status_code = %$headers.http_status_code,
+            status_text = %$headers.http_status_text,
+            "handshake response"
+        );
+        #[cfg(not(feature = "tracing"))]
+        log::debug!(
+            "Handshake Status: Version: {} | Status Code: {} | {}",
+            $headers.http_version,
+            $headers.http_status_code,
+            $headers.http_status_text
+        );
+    }};
+}
+
+pub(crate) use close_event;
+pub(crate) use frame_event;
+pub(crate) use handshake_event;
+
+#[cfg(feature = "tracing")]
+pub use tracing::Span;
+
+#[cfg(feature = "tracing")]
+pub fn connection_span(uri: &fluent_uri::Uri<String>) -> Span {
+    tracing::info_span!("websocket", uri = %uri)
+}
+
+#[cfg(feature = "tracing")]
+pub fn current_span() -> Span {
+    Span::current()
+}
+
+#[cfg(feature = "tracing")]
+pub async fn in_span<F: Future>(span: &Span, fut: F) -> F::Output {
+    tracing::Instrument::instrument(fut, span.clone()).await
+}
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub const fn connection_span(_uri: &fluent_uri::Uri<String>) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub const fn current_span() -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub async fn in_span<F: Future>(_span: &Span, fut: F) -> F::Output {
+    fut.await
+}
@@ -1,66 +1,515 @@
 use std::sync::Arc;
-use std::sync::atomic::AtomicU8;
+
+use super::stats::{ConnectionStats, StatsSnapshot};
 
 use crate::WebSocketError;
 
+use super::body::CHUNK_SIZE;
 use super::enums::Opcode;
 use super::enums::State;
 use super::errors::ConnectionError;
+#[cfg(feature = "serde")]
+use super::errors::ParseError;
+use super::extension::Sessions;
 use super::frame::Frame;
-use super::utils::get_connection_state;
+use super::middleware::MiddlewareChain;
+use super::queue::{OutboundQueue, OverflowPolicy};
+use super::ratelimit::{RateLimit, RateLimiter};
+#[cfg(feature = "record")]
+use super::record::{Direction, Recorder};
+
+use super::trace::{close_event, frame_event};
+use log::warn;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::{
+    fmt,
+    sync::{Mutex as StdMutex, PoisonError},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Mutex, OwnedMutexGuard, watch};
 
-use log::debug;
-use std::fmt;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
-use tokio::sync::Mutex;
+// A Close payload fits a control frame: 125 bytes, two of them the code.
+const MAX_CLOSE_REASON: usize = 123;
+
+fn check_close(code: u16, reason: &str) -> Result<(), ConnectionError> {
+    // 1004 is reserved, 1005, 1006 and 1015 must never be sent (RFC 6455 7.4.1).
+    if !(1000..5000).contains(&code) || matches!(code, 1004..=1006 | 1015) {
+        return Err(ConnectionError::InvalidClose(format!(
+            "code {code} can't be sent"
+        )));
+    }
+    if reason.len() > MAX_CLOSE_REASON {
+        return Err(ConnectionError::InvalidClose(format!(
+            "reason is {} bytes, at most {MAX_CLOSE_REASON} fit",
+            reason.len()
+        )));
+    }
+    Ok(())
+}
 
 #[derive(Clone)]
 pub struct Transport {
-    writer: Arc<Mutex<Box<dyn AsyncWrite + Unpin + Send>>>,
-    pub state: Arc<AtomicU8>,
+    writer: Arc<Mutex<Writer>>,
+    // Held while a message is sent, so data frames of other messages can't end
+    // up between its fragments.
+    message: Arc<Mutex<()>>,
+    // Negotiated on the current connection, replaced by `attach`.
+    extensions: Arc<StdMutex<Sessions>>,
+    state: Arc<watch::Sender<State>>,
+    stats: Arc<ConnectionStats>,
+    queue: Option<Arc<OutboundQueue>>,
+    limiter: Option<Arc<RateLimiter>>,
+    middleware: MiddlewareChain,
+    #[cfg(feature = "record")]
+    recorder: Option<Arc<Recorder>>,
 }
 
+// Transport settings taken from the `StreamBuilder`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TransportOptions {
+    pub queue: Option<(usize, OverflowPolicy)>,
+    pub rate_limit: Option<RateLimit>,
+    pub middleware: MiddlewareChain,
+    #[cfg(feature = "record")]
+    pub recorder: Option<Arc<Recorder>>,
+}
+
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
 impl Transport {
-    pub fn new(
-        writer: Arc<Mutex<Box<dyn AsyncWrite + Unpin + Send>>>,
-        state: Arc<AtomicU8>,
-    ) -> Self {
-        Self { writer, state }
+    pub fn new(writer: Arc<Mutex<Writer>>, state: State) -> Self {
+        Self {
+            writer,
+            message: Arc::default(),
+            extensions: Arc::default(),
+            state: Arc::new(watch::Sender::new(state)),
+            stats: Arc::default(),
+            queue: None,
+            limiter: None,
+            middleware: MiddlewareChain::default(),
+            #[cfg(feature = "record")]
+            recorder: None,
+        }
+    }
+
+    // A transport that isn't connected yet. `attach` gives it a writer once the
+    // handshake is done, and again after every reconnect.
+    pub(crate) fn detached(options: &TransportOptions) -> Self {
+        let mut transport = Self::new(
+            Arc::new(Mutex::new(Box::new(tokio::io::sink()))),
+            State::CONNECTING,
+        );
+        transport.queue = options
+            .queue
+            .map(|(capacity, policy)| Arc::new(OutboundQueue::new(capacity, policy)));
+        transport.limiter = options
+            .rate_limit
+            .map(|limit| Arc::new(RateLimiter::new(limit)));
+        transport.middleware.clone_from(&options.middleware);
+        #[cfg(feature = "record")]
+        transport.recorder.clone_from(&options.recorder);
+        transport
+    }
+
+    // Messages queued while disconnected are written before the state goes back
+    // to OPEN, so they can't be overtaken by new writes. When one can't be
+    // written the connection is failed and it stays queued, first in line.
+    pub(crate) async fn attach(
+        &self,
+        writer: impl AsyncWrite + Unpin + Send + 'static,
+        extensions: Sessions,
+    ) -> Result<(), WebSocketError> {
+        let mut current = self.writer.lock().await;
+        *current = Box::new(writer);
+        *self
+            .extensions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = extensions;
+        self.stats.record_connected();
+
+        if let Some(queue) = &self.queue {
+            while let Some(frame) = queue.pop_or_else(|| self.set_state(State::OPEN)) {
+                if let Some(limiter) = &self.limiter {
+                    limiter.wait_for(&frame).await;
+                }
+                if let Err(err) = self.send(&mut current, &frame).await {
+                    warn!("Couldn't flush the outbound queue: {err}");
+                    queue.push_front(frame);
+                    self.set_state(State::CLOSED);
+                    return Err(err);
+                }
+            }
+        }
+        drop(current);
+        self.set_state(State::OPEN);
+        Ok(())
     }
 
+    /// Number of messages waiting in the outbound queue.
+    #[must_use]
+    pub fn queued(&self) -> usize {
+        self.queue.as_ref().map_or(0, |queue| queue.len())
+    }
+
+    #[must_use]
+    pub fn state(&self) -> State {
+        *self.state.borrow()
+    }
+
+    /// Watches the connection state. The receiver sees every transition made
+    /// after it was created, `CONNECTING` → `OPEN` → `CLOSING` → `CLOSED`, or
+    /// `ERROR` when the event loop fails, and `CONNECTING` again on reconnect.
+    #[must_use]
+    pub fn subscribe(&self) -> watch::Receiver<State> {
+        self.state.subscribe()
+    }
+
+    // Only notifies subscribers when the state actually changes.
+    pub(crate) fn set_state(&self, state: State) {
+        self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    }
+
+    // Moves the state to `to` if it's still `from`, returning whether it did.
+    fn replace_state(&self, from: State, to: State) -> bool {
+        self.state.send_if_modified(|current| {
+            let replaced = *current == from;
+            if replaced {
+                *current = to;
+            }
+            replaced
+        })
+    }
+
+    pub(crate) fn stats_handle(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Counters for this connection, kept across reconnects.
+    #[must_use]
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Writes `frame` as is, RSV bits and `fin` included, after it went through
+    /// the outbound middleware. Data frames wait for a message being sent through
+    /// [`Transport::fragments`] to finish first.
+    ///
+    /// With an outbound queue configured, data frames written while the
+    /// connection isn't open are held and sent once it is open again. With a rate
+    /// limit configured, data frames wait for (or fail without) capacity.
     pub async fn write(&mut self, frame: &mut Frame) -> Result<(), WebSocketError> {
-        let data = frame.encode()?;
-        let state = get_connection_state(&self.state);
+        if self.middleware.is_empty() || !is_data(frame.headers.opcode) {
+            return self.write_message(frame).await;
+        }
+        for frame in self.middleware.outbound(frame.clone()).await? {
+            self.write_message(&frame).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) const fn middleware(&self) -> &MiddlewareChain {
+        &self.middleware
+    }
+
+    async fn write_message(&self, frame: &Frame) -> Result<(), WebSocketError> {
+        if !is_data(frame.headers.opcode) {
+            return self.write_frame(frame, false).await;
+        }
+        let message = self.message.lock().await;
+        let result = self.write_frame(frame, self.queue.is_some()).await;
+        drop(message);
+        result
+    }
+
+    // Fragments of a message are never queued, a reconnect in the middle of the
+    // message would send the rest of it on a connection that never saw its start.
+    async fn write_frame(&self, frame: &Frame, queue: bool) -> Result<(), WebSocketError> {
+        let opcode = frame.headers.opcode;
+        if queue
+            && is_data(opcode)
+            && let Some(queue) = &self.queue
+        {
+            let state = &self.state;
+            let is_open = || *state.borrow() == State::OPEN;
+            if queue.hold(frame, is_open)? {
+                return Ok(());
+            }
+        }
+
+        // No data frame may follow a Close frame, see RFC 6455 section 5.5.1.
+        let closing = || {
+            WebSocketError::Stream(ConnectionError::WriteError(String::from(
+                "Connection is Closing",
+            )))
+        };
+        let state = self.state();
         match state {
-            State::OPEN | State::CLOSING => match self.writer.lock().await.write_all(&data).await {
-                Ok(_n) => {
-                    debug!("Sent {} bytes of data", data.len());
-                    Ok(())
+            State::CLOSING if is_data(opcode) => Err(closing()),
+
+            State::OPEN | State::CLOSING => {
+                // The rest of a fragmented message was paid for by its first frame.
+                if let Some(limiter) = &self.limiter
+                    && matches!(opcode, Opcode::Text | Opcode::Binary)
+                {
+                    limiter.acquire(frame).await?;
                 }
-                Err(err) => Err(WebSocketError::Stream(ConnectionError::WriteError(
-                    format!("Couldn't Write to the Stream: {err}"),
-                ))),
-            },
+                let mut writer = self.writer.lock().await;
+                // Checked again, the Close frame may have been written meanwhile.
+                let result = if is_data(opcode) && self.state() != State::OPEN {
+                    Err(closing())
+                } else {
+                    self.send(&mut writer, frame).await
+                };
+                drop(writer);
+                result
+            }
 
-            State::CLOSED => Err(WebSocketError::Stream(ConnectionError::WriteError(
-                String::from("Connection is Closed"),
-            ))),
+            State::CLOSED | State::ERROR => Err(WebSocketError::Stream(
+                ConnectionError::WriteError(String::from("Connection is Closed")),
+            )),
 
-            _ => Err(WebSocketError::Stream(ConnectionError::WriteError(
-                format!("Unknown State {:?}", self.state),
+            State::CONNECTING => Err(WebSocketError::Stream(ConnectionError::WriteError(
+                format!("Unknown State {state:?}"),
             ))),
         }
     }
 
+    /// Starts a message that is sent in fragments as its data becomes available.
+    /// Other data messages wait until it's finished, control frames don't. The
+    /// rate limit is charged once, when the first frame is sent.
+    pub async fn fragments(&self, opcode: Opcode) -> FragmentWriter {
+        FragmentWriter {
+            transport: self.clone(),
+            opcode,
+            _message: Arc::clone(&self.message).lock_owned().await,
+        }
+    }
+
+    /// Sends a message split into one frame per chunk.
+    pub async fn send_fragmented<I>(
+        &mut self,
+        opcode: Opcode,
+        chunks: I,
+    ) -> Result<(), WebSocketError>
+    where
+        I: IntoIterator + Send,
+        I::Item: AsRef<[u8]>,
+        I::IntoIter: Send,
+    {
+        let mut writer = self.fragments(opcode).await;
+        let mut chunks = chunks.into_iter().peekable();
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                return writer.finish(chunk.as_ref()).await;
+            }
+            writer.send(chunk.as_ref()).await?;
+        }
+        writer.finish(&[]).await
+    }
+
+    /// Sends everything `body` yields as one message, in frames of 64 KiB, without
+    /// reading all of it into memory first. It's charged to the rate limit once,
+    /// like any fragmented message. When reading `body` fails the error is
+    /// returned and the message is left incomplete, see [`FragmentWriter`].
+    pub async fn send_stream(
+        &mut self,
+        opcode: Opcode,
+        mut body: impl AsyncRead + Unpin + Send,
+    ) -> Result<(), WebSocketError> {
+        self.fragments(opcode).await.send_all(&mut body).await
+    }
+
+    // Runs `frame` through the negotiated extensions, encodes and writes it, then
+    // counts (and records) it as sent.
+    async fn send(&self, writer: &mut Writer, frame: &Frame) -> Result<(), WebSocketError> {
+        let extensions = self
+            .extensions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let data = if extensions.is_empty() {
+            frame.encode()?
+        } else {
+            let mut transformed = frame.clone();
+            extensions.outgoing(&mut transformed)?;
+            transformed.encode()?
+        };
+        Self::write_all(writer, &data).await.map_err(|err| {
+            WebSocketError::Stream(ConnectionError::WriteError(format!(
+                "Couldn't Write to the Stream: {err}"
+            )))
+        })?;
+        let headers = &frame.headers;
+        frame_event!("sent", headers.opcode, frame.payload_data.len());
+        self.stats
+            .record_sent(headers.opcode, headers.fin, data.len());
+        #[cfg(feature = "record")]
+        self.record(Direction::Sent, frame);
+        Ok(())
+    }
+
+    #[cfg(feature = "record")]
+    pub(crate) fn record(&self, direction: Direction, frame: &Frame) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(direction, frame);
+        }
+    }
+
+    async fn write_all(writer: &mut Writer, data: &[u8]) -> Result<(), std::io::Error> {
+        writer.write_all(data).await?;
+        writer.flush().await
+    }
+
+    /// Starts the closing handshake. `WebSocket::run` returns once the peer
+    /// answers with its own Close frame. Fails without sending anything when
+    /// `code` can't be sent (below 1000, above 4999 or reserved for endpoints
+    /// to report locally) or `reason` is longer than 123 bytes.
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        check_close(code, reason)?;
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        let mut frame = Frame::set_defaults(Opcode::Close, &payload);
+        // Closing before the frame is written, so no data frame can follow it.
+        let started = self.replace_state(State::OPEN, State::CLOSING);
+        if let Err(err) = self.write(&mut frame).await {
+            if started {
+                self.replace_state(State::CLOSING, State::OPEN);
+            }
+            return Err(err);
+        }
+        close_event!("sent", Some(code));
+        Ok(())
+    }
+
+    /// Sends a Ping, the round-trip time of its Pong shows up in `stats`.
+    pub async fn ping(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        let mut frame = Frame::set_defaults(Opcode::Ping, payload);
+        // Stamped before writing, the Pong can be read before the write returns.
+        self.stats.record_ping_sent();
+        let result = self.write(&mut frame).await;
+        if result.is_err() {
+            self.stats.record_ping_failed();
+        }
+        result
+    }
+
     pub async fn write_text(&mut self, msg: &[u8]) -> Result<(), WebSocketError> {
         let mut frame = Frame::set_defaults(Opcode::Text, msg);
         self.write(&mut frame).await?;
         Ok(())
     }
+
+    pub async fn write_binary(&mut self, msg: &[u8]) -> Result<(), WebSocketError> {
+        let mut frame = Frame::set_defaults(Opcode::Binary, msg);
+        self.write(&mut frame).await?;
+        Ok(())
+    }
+
+    /// Serializes `value` as JSON and sends it in a Text frame.
+    #[cfg(feature = "serde")]
+    pub async fn send_json<T: Serialize + Sync + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), WebSocketError> {
+        let payload = serde_json::to_vec(value).map_err(ParseError::from)?;
+        self.write_text(&payload).await
+    }
+
+    /// Serializes `value` as `MessagePack`, structs as maps, and sends it in a
+    /// Binary frame.
+    #[cfg(feature = "msgpack")]
+    pub async fn send_msgpack<T: Serialize + Sync + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), WebSocketError> {
+        let payload = rmp_serde::to_vec_named(value).map_err(ParseError::from)?;
+        self.write_binary(&payload).await
+    }
+
+    /// Serializes `value` as CBOR and sends it in a Binary frame.
+    #[cfg(feature = "cbor")]
+    pub async fn send_cbor<T: Serialize + Sync + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), WebSocketError> {
+        let mut payload = Vec::new();
+        ciborium::into_writer(value, &mut payload)
+            .map_err(|e| ParseError::CborError(e.to_string()))?;
+        self.write_binary(&payload).await
+    }
+}
+
+/// A message being sent in fragments, see [`Transport::fragments`]. The first
+/// frame carries the message's opcode, the ones after it are Continuation frames.
+///
+/// Dropping the writer without calling `finish` leaves the message incomplete,
+/// which servers treat as a protocol error once another data message is sent.
+pub struct FragmentWriter {
+    transport: Transport,
+    opcode: Opcode,
+    _message: OwnedMutexGuard<()>,
+}
+
+impl FragmentWriter {
+    /// Sends `chunk` as the next frame of the message.
+    pub async fn send(&mut self, chunk: &[u8]) -> Result<(), WebSocketError> {
+        self.frame(chunk.to_vec(), false).await
+    }
+
+    /// Sends `chunk`, which may be empty, as the frame that ends the message.
+    pub async fn finish(mut self, chunk: &[u8]) -> Result<(), WebSocketError> {
+        self.frame(chunk.to_vec(), true).await
+    }
+
+    async fn send_all(mut self, body: &mut (impl AsyncRead + Unpin)) -> Result<(), WebSocketError> {
+        // One chunk is read ahead, to know which frame ends the message.
+        let mut chunk = read_chunk(body).await?;
+        loop {
+            let next = read_chunk(body).await?;
+            if next.is_empty() {
+                return self.frame(chunk, true).await;
+            }
+            self.frame(chunk, false).await?;
+            chunk = next;
+        }
+    }
+
+    async fn frame(&mut self, chunk: Vec<u8>, fin: bool) -> Result<(), WebSocketError> {
+        let mut frame = Frame::with_payload(self.opcode, chunk);
+        frame.headers.fin = fin;
+        self.transport.write_frame(&frame, false).await?;
+        self.opcode = Opcode::Continuation;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for FragmentWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FragmentWriter")
+            .field("opcode", &self.opcode)
+            .finish_non_exhaustive()
+    }
 }
 
 impl fmt::Debug for Transport {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Transport [{:?}]", get_connection_state(&self.state))
+        write!(f, "Transport [{:?}]", self.state())
     }
 }
+
+// Reads up to `CHUNK_SIZE` bytes, fewer only at the end of `body`.
+async fn read_chunk(body: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    let limit = u64::try_from(CHUNK_SIZE).unwrap_or(u64::MAX);
+    body.take(limit).read_to_end(&mut chunk).await?;
+    Ok(chunk)
+}
+
+pub(crate) const fn is_data(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::Text | Opcode::Binary | Opcode::Continuation)
+}
@@ -1,16 +1,16 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicU8, Ordering},
-};
-
-use super::enums::State;
-use super::errors::URIError;
-use fluent_uri::{Uri, component::Authority, encoding::EStr};
+use super::errors::{TimeoutError, URIError};
+use fluent_uri::{Uri, component::Authority};
+use std::{path::PathBuf, time::Duration};
 pub const CRLF: &str = "\r\n";
 pub const DEFAULT_PORT_SECURE: u16 = 443;
 pub const DEFAULT_PORT_INSECURE: u16 = 80;
 
 pub const ACCEPT_KEY_NAME: &str = "sec-websocket-accept";
+pub const PROTOCOL_KEY_NAME: &str = "sec-websocket-protocol";
+pub const EXTENSIONS_KEY_NAME: &str = "sec-websocket-extensions";
+
+// Host header sent for `ws+unix` URIs, which have no host of their own.
+pub const UNIX_SOCKET_HOST: &str = "localhost";
 
 pub fn get_uri(uri_string: String) -> Result<Uri<String>, URIError> {
     Uri::parse(uri_string).map_err(|e| URIError::MalformedURIError(e.to_string()))
@@ -27,6 +27,45 @@ pub fn is_secured(uri: &Uri<String>) -> bool {
     scheme == "wss"
 }
 
+#[must_use]
+pub fn is_unix(uri: &Uri<String>) -> bool {
+    uri.scheme().as_str() == "ws+unix"
+}
+
+// `ws+unix` URIs carry the socket path and the resource target in the path component,
+// separated by the first `:`, e.g. `ws+unix:///tmp/app.sock:/chat`.
+fn split_unix_path(uri: &Uri<String>) -> (String, String) {
+    let path = uri.path().to_string();
+    match path.split_once(':') {
+        Some((socket, target)) => (socket.to_string(), target.to_string()),
+        None => (path, String::new()),
+    }
+}
+
+pub fn get_unix_socket_path(uri: &Uri<String>) -> Result<PathBuf, URIError> {
+    let (socket, _) = split_unix_path(uri);
+    if socket.is_empty() {
+        return Err(URIError::IncompleteURIError(
+            "Socket path for the URI is not found".into(),
+        ));
+    }
+    Ok(PathBuf::from(socket))
+}
+
+pub fn get_host_header(uri: &Uri<String>) -> Result<String, URIError> {
+    if is_unix(uri) {
+        return Ok(UNIX_SOCKET_HOST.into());
+    }
+    uri.authority().map_or_else(
+        || {
+            Err(URIError::IncompleteURIError(
+                "Authority for the URI is not found".into(),
+            ))
+        },
+        |auth| Ok(get_host(&auth)),
+    )
+}
+
 pub fn get_port(uri: &Uri<String>) -> Result<u16, URIError> {
     let auth = uri.authority();
     match auth {
@@ -47,6 +86,10 @@ pub fn get_port(uri: &Uri<String>) -> Result<u16, URIError> {
 }
 
 pub fn get_socket_address(uri: &Uri<String>) -> Result<String, URIError> {
+    if is_unix(uri) {
+        return Ok(get_unix_socket_path(uri)?.display().to_string());
+    }
+
     let host = uri.authority().map_or_else(
         || {
             Err(URIError::IncompleteURIError(
@@ -61,14 +104,21 @@ pub fn get_socket_address(uri: &Uri<String>) -> Result<String, URIError> {
 }
 
 pub fn get_resource_target(uri: &Uri<String>) -> Result<String, URIError> {
-    let mut path = uri.path().to_string();
+    let mut path = if is_unix(uri) {
+        split_unix_path(uri).1
+    } else {
+        uri.path().to_string()
+    };
     if path.is_empty() {
         path = String::from("/");
     }
 
-    let query = uri.query().unwrap_or(EStr::EMPTY).to_string();
+    if let Some(query) = uri.query() {
+        path.push('?');
+        path.push_str(query.as_str());
+    }
 
-    Ok(path + &query)
+    Ok(path)
 }
 
 #[macro_export]
@@ -77,9 +127,10 @@ macro_rules! safe_get_handshake_item {
         $v.get($i).map_or_else(
             || {
                 Err(WebSocketError::Handshake(
-                    HandshakeFailureError::HeaderError(
-                        "`$t` header not found in handshake response".into(),
-                    ),
+                    HandshakeFailureError::HeaderError(format!(
+                        "`{}` not found in handshake response",
+                        $t
+                    )),
                 ))
             },
             Ok,
@@ -87,9 +138,16 @@ macro_rules! safe_get_handshake_item {
     };
 }
 
-pub fn get_connection_state(store: &Arc<AtomicU8>) -> State {
-    State::from_repr(store.load(Ordering::Relaxed).into()).unwrap_or(State::ERROR)
-}
-pub fn set_connection_state(state: State, store: &Arc<AtomicU8>) {
-    store.store(state.as_u8(), Ordering::Relaxed);
+/// Awaits `fut`, failing with the error built by `on_elapsed` if `limit` passes first.
+pub async fn with_timeout<T>(
+    limit: Option<Duration>,
+    on_elapsed: fn(Duration) -> TimeoutError,
+    fut: impl Future<Output = T>,
+) -> Result<T, TimeoutError> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, fut)
+            .await
+            .map_err(|_| on_elapsed(limit)),
+        None => Ok(fut.await),
+    }
 }
@@ -8,51 +8,184 @@
 #![deny(clippy::panic)]
 #![deny(clippy::indexing_slicing)]
 #![allow(clippy::missing_errors_doc)]
+#![allow(clippy::multiple_crate_versions)]
 #[allow(clippy::cast_possible_truncation)]
 #[allow(clippy::struct_excessive_bools)]
 pub mod core;
 pub use async_trait;
+#[cfg(feature = "record")]
+pub use core::record::{Recorder, Replay};
+#[cfg(feature = "rpc")]
+pub use core::rpc::RpcClient;
 pub use core::{
-    context::Context, errors::WebSocketError, protocol::WebSocketProtocol, transport::Transport,
+    body::MessageBody,
+    connector::{IpPreference, Keepalive, Resolver},
+    context::Context,
+    enums::State,
+    errors::WebSocketError,
+    extension::{Extension, ExtensionParam, ExtensionSession},
+    manager::{ConnectionHealth, ConnectionManager, HealthReport},
+    middleware::Middleware,
+    protocol::WebSocketProtocol,
+    queue::OverflowPolicy,
+    ratelimit::RateLimit,
+    router::Router,
+    stats::{FrameCounts, StatsSnapshot},
+    stream::{AsyncStream, StreamBuilder},
+    subscriptions::{SubscriptionEncoder, Subscriptions},
+    tls::CertificatePin,
+    transport::{FragmentWriter, Transport},
 };
 
 use core::{
-    stream::{StreamBuilder, StreamType},
+    errors::ConnectionError,
+    stream::StreamType,
+    trace::{Span, connection_span, in_span},
+    transport::TransportOptions,
     utils::{get_socket_address, get_uri},
 };
 
 use log::{debug, info};
-use std::str;
+use std::{str, sync::Arc};
+use tokio::sync::Mutex;
 
 pub struct WebSocket<P: WebSocketProtocol> {
     stream: StreamType<P>,
+    span: Span,
+    user_protocol: Arc<Mutex<P>>,
+    transport: Transport,
+
+    // `None` for connections made with `from_stream`, which can't be redialed.
+    builder: Option<StreamBuilder>,
 }
 
 impl<P: WebSocketProtocol + Send + Sync + 'static> WebSocket<P> {
     pub async fn connect(uri: &str, protocol: P) -> Result<Self, WebSocketError> {
-        env_logger::init();
         let uri_obj = get_uri(String::from(uri))?;
-        info!(
-            "Attempting to create connection with {}",
-            get_socket_address(&uri_obj)?
-        );
+        Self::connect_with(StreamBuilder::new(uri_obj, None)?, protocol).await
+    }
+
+    /// Connects with the options set on `builder`, see [`StreamBuilder`].
+    pub async fn connect_with(builder: StreamBuilder, protocol: P) -> Result<Self, WebSocketError> {
+        let span = connection_span(builder.uri());
+        let user_protocol = Arc::new(Mutex::new(protocol));
+        let transport = Transport::detached(builder.transport_options());
+        let stream = in_span(&span, async {
+            info!(
+                "Attempting to create connection with {}",
+                get_socket_address(builder.uri())?
+            );
+            builder
+                .open(Arc::clone(&user_protocol), transport.clone())
+                .await
+        })
+        .await?;
+
+        Ok(Self {
+            stream,
+            span,
+            user_protocol,
+            transport,
+            builder: Some(builder),
+        })
+    }
 
-        let stream = StreamBuilder::new(uri_obj, None)?
-            .build_stream(protocol)
-            .await?;
+    /// Runs the handshake over `io`, a stream the caller already opened (a Unix
+    /// domain socket, an in-memory `tokio::io::duplex`, a proxied connection, ...).
+    /// `uri` is only used to build the handshake request.
+    pub async fn from_stream(
+        io: impl AsyncStream + 'static,
+        uri: &str,
+        protocol: P,
+    ) -> Result<Self, WebSocketError> {
+        let uri_obj = get_uri(String::from(uri))?;
+        let span = connection_span(&uri_obj);
+        let builder = StreamBuilder::new(uri_obj, None)?;
+        let user_protocol = Arc::new(Mutex::new(protocol));
+        let transport = Transport::detached(&TransportOptions::default());
+        let stream = in_span(
+            &span,
+            builder.open_from(io, Arc::clone(&user_protocol), transport.clone()),
+        )
+        .await?;
 
-        Ok(Self { stream })
+        Ok(Self {
+            stream,
+            span,
+            user_protocol,
+            transport,
+            builder: None,
+        })
     }
 
+    /// Reads and dispatches frames until the closing handshake completes. The
+    /// state moves to `ERROR` whenever the loop stops on an error.
     pub async fn run(&mut self) -> Result<(), WebSocketError> {
         debug!("Starting Event Loop");
-        match &mut self.stream {
-            StreamType::Plain(plain_stream) => loop {
-                plain_stream.read().await?;
-            },
-            StreamType::Secured(secured_stream) => loop {
-                secured_stream.read().await?;
-            },
+        while self.state() != State::CLOSED {
+            if let Err(err) = in_span(&self.span, self.stream.read()).await {
+                self.transport.set_state(State::ERROR);
+                return Err(err);
+            }
+        }
+        debug!("Event Loop finished");
+        Ok(())
+    }
+
+    /// Drops the current connection and dials the server again with the same
+    /// options. The handler's `on_connect` runs again and `Transport` handles
+    /// handed out earlier keep working with the new connection.
+    pub async fn reconnect(&mut self) -> Result<(), WebSocketError> {
+        let Some(builder) = &self.builder else {
+            return Err(WebSocketError::Stream(ConnectionError::ConnectorError(
+                "Connections made from a stream can't reconnect".into(),
+            )));
         };
+
+        self.transport.set_state(State::CONNECTING);
+        self.transport.stats_handle().record_reconnect();
+        info!("Reconnecting to {}", get_socket_address(builder.uri())?);
+
+        let opened = builder.open(Arc::clone(&self.user_protocol), self.transport.clone());
+        match in_span(&self.span, opened).await {
+            Ok(stream) => {
+                self.stream = stream;
+                Ok(())
+            }
+            Err(err) => {
+                self.transport.set_state(State::CLOSED);
+                Err(err)
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn state(&self) -> State {
+        self.transport.state()
+    }
+
+    /// A handle for writing to this connection, the same one passed to `on_connect`.
+    #[must_use]
+    pub fn transport(&self) -> Transport {
+        self.transport.clone()
+    }
+
+    /// Subprotocol the server selected during the last handshake.
+    #[must_use]
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.stream.subprotocol()
+    }
+
+    /// Extensions the server accepted during the last handshake, in the order
+    /// frames go through them.
+    #[must_use]
+    pub fn extensions(&self) -> Vec<&str> {
+        self.stream.extensions()
+    }
+
+    /// Counters for this connection, kept across reconnects.
+    #[must_use]
+    pub fn stats(&self) -> StatsSnapshot {
+        self.transport.stats()
     }
 }
@@ -0,0 +1,164 @@
+use mayuri::{
+    IpPreference, Keepalive, Resolver, StreamBuilder, WebSocket, WebSocketError,
+    async_trait::async_trait,
+    core::{
+        errors::ConnectionError,
+        testing::{Idle, MockConnection, MockServer},
+        utils::get_uri,
+    },
+};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+type Queries = Arc<Mutex<Vec<(String, u16)>>>;
+
+// Resolves every host to fixed addresses and remembers what it was asked for.
+struct Fixed {
+    addresses: Vec<SocketAddr>,
+    queries: Queries,
+}
+
+#[async_trait]
+impl Resolver for Fixed {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.queries.lock().unwrap().push((host.into(), port));
+        Ok(self.addresses.clone())
+    }
+}
+
+// An address nothing listens on, connections to it are refused.
+async fn refused_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn builder(port: u16, addresses: Vec<SocketAddr>) -> (StreamBuilder, Queries) {
+    let queries = Arc::default();
+    let resolver = Fixed {
+        addresses,
+        queries: Arc::clone(&queries),
+    };
+    let uri = get_uri(format!("ws://echo.test:{port}/")).unwrap();
+    let builder = StreamBuilder::new(uri, None)
+        .unwrap()
+        .resolver(resolver)
+        .ip_preference(IpPreference::PreferIpv4);
+    (builder, queries)
+}
+
+#[tokio::test]
+async fn resolves_through_custom_resolver() {
+    let server = MockServer::bind().await.unwrap();
+    let address = server.local_addr().unwrap();
+    let (builder, queries) = builder(address.port(), vec![address]);
+
+    let (ws, connection) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    assert!(ws.is_ok());
+    assert_eq!(
+        connection.unwrap().request_header("host"),
+        Some("echo.test")
+    );
+    assert_eq!(
+        *queries.lock().unwrap(),
+        [("echo.test".to_string(), address.port())]
+    );
+}
+
+#[tokio::test]
+async fn falls_back_to_the_next_address() {
+    let server = MockServer::bind().await.unwrap();
+    let address = server.local_addr().unwrap();
+    let (builder, _) = builder(address.port(), vec![refused_address().await, address]);
+
+    let (ws, connection) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    assert!(ws.is_ok());
+    assert!(connection.is_ok());
+}
+
+#[tokio::test]
+async fn reports_every_failed_attempt() {
+    let refused = [refused_address().await, refused_address().await];
+    let (builder, _) = builder(refused[0].port(), refused.to_vec());
+
+    let Err(WebSocketError::Stream(ConnectionError::ConnectFailed { host, attempts })) =
+        WebSocket::connect_with(builder, Idle).await
+    else {
+        panic!("expected every attempt to fail");
+    };
+    assert_eq!(host, "echo.test");
+    let tried: Vec<SocketAddr> = attempts.iter().map(|attempt| attempt.address).collect();
+    assert_eq!(tried, refused);
+}
+
+#[tokio::test]
+async fn only_tries_the_preferred_family() {
+    let ipv6: SocketAddr = "[::1]:9".parse().unwrap();
+    let (builder, _) = builder(9, vec![ipv6]);
+    let builder = builder.ip_preference(IpPreference::Ipv4Only);
+
+    let result = WebSocket::connect_with(builder, Idle).await;
+    assert!(matches!(
+        result,
+        Err(WebSocketError::Stream(ConnectionError::ResolveError(_)))
+    ));
+}
+
+#[tokio::test]
+async fn connects_with_socket_options() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let uri = get_uri(format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+    let builder = StreamBuilder::new(uri, None)
+        .unwrap()
+        .nodelay(true)
+        .keepalive(Keepalive {
+            time: Some(Duration::from_secs(30)),
+            interval: Some(Duration::from_secs(5)),
+            retries: Some(3),
+        })
+        .send_buffer_size(64 * 1024)
+        .recv_buffer_size(64 * 1024)
+        .local_address(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let accept = async {
+        let (stream, peer) = listener.accept().await.unwrap();
+        MockConnection::accept(stream).await.unwrap();
+        peer
+    };
+
+    let (ws, peer) = tokio::join!(WebSocket::connect_with(builder, Idle), accept);
+    assert!(ws.is_ok());
+    assert_eq!(peer.ip(), Ipv4Addr::LOCALHOST);
+}
+
+#[tokio::test]
+async fn local_address_restricts_the_family_tried() {
+    let ipv6: SocketAddr = "[::1]:9".parse().unwrap();
+    let (builder, _) = builder(9, vec![ipv6]);
+    let builder = builder.local_address(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    let result = WebSocket::connect_with(builder, Idle).await;
+    assert!(matches!(
+        result,
+        Err(WebSocketError::Stream(ConnectionError::ResolveError(_)))
+    ));
+}
+
+#[tokio::test]
+async fn fails_when_a_socket_option_cant_be_applied() {
+    let server = MockServer::bind().await.unwrap();
+    let address = server.local_addr().unwrap();
+    let (builder, _) = builder(address.port(), vec![address]);
+    let builder = builder.interface("mayuri-missing0");
+
+    let result = WebSocket::connect_with(builder, Idle).await;
+    assert!(matches!(
+        result,
+        Err(WebSocketError::Stream(
+            ConnectionError::ConnectFailed { .. }
+        ))
+    ));
+}
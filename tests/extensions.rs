@@ -0,0 +1,293 @@
+use mayuri::{
+    Extension, ExtensionParam, ExtensionSession, StreamBuilder, WebSocket, WebSocketError,
+    core::{
+        errors::HandshakeFailureError,
+        extension::RSV2,
+        frame::Frame,
+        testing::{Forward, MockServer, encode_frame},
+        utils::get_uri,
+    },
+};
+
+// XORs data frames with a key the server agrees to, marking them with RSV2.
+struct Xor {
+    key: u8,
+    offer_key: bool,
+}
+
+impl Extension for Xor {
+    fn name(&self) -> &str {
+        "x-xor"
+    }
+
+    fn offer(&self) -> Vec<ExtensionParam> {
+        if !self.offer_key {
+            return Vec::new();
+        }
+        vec![ExtensionParam::new("key", Some(&self.key.to_string()))]
+    }
+
+    fn rsv_bits(&self) -> u8 {
+        RSV2
+    }
+
+    fn session(&self, params: &[ExtensionParam]) -> Result<Box<dyn ExtensionSession>, String> {
+        let key = self.key.to_string();
+        match params.iter().find(|param| param.name == "key") {
+            Some(param) if param.value.as_deref() == Some(&key) => {
+                Ok(Box::new(XorSession(self.key)))
+            }
+            _ => Err("`key` is missing".into()),
+        }
+    }
+}
+
+struct XorSession(u8);
+
+impl XorSession {
+    fn apply(&self, frame: &mut Frame) {
+        for byte in &mut frame.payload_data {
+            *byte ^= self.0;
+        }
+    }
+}
+
+impl ExtensionSession for XorSession {
+    // Control frames never get here.
+    fn outgoing(&mut self, frame: &mut Frame) -> Result<(), String> {
+        self.apply(frame);
+        frame.headers.rsv2 = true;
+        Ok(())
+    }
+
+    fn incoming(&mut self, frame: &mut Frame) -> Result<(), String> {
+        if frame.headers.rsv2 {
+            self.apply(frame);
+            frame.headers.rsv2 = false;
+        }
+        Ok(())
+    }
+}
+
+// Numbers the messages sent on each connection.
+struct Numbered;
+
+impl Extension for Numbered {
+    fn name(&self) -> &str {
+        "x-numbered"
+    }
+
+    fn session(&self, _params: &[ExtensionParam]) -> Result<Box<dyn ExtensionSession>, String> {
+        Ok(Box::new(NumberedSession(0)))
+    }
+}
+
+struct NumberedSession(u32);
+
+impl ExtensionSession for NumberedSession {
+    fn outgoing(&mut self, frame: &mut Frame) -> Result<(), String> {
+        let mut payload = format!("{}:", self.0).into_bytes();
+        payload.append(&mut frame.payload_data);
+        frame.payload_data = payload;
+        self.0 += 1;
+        Ok(())
+    }
+}
+
+// Offers whatever it's given and accepts any response.
+struct Offer {
+    name: &'static str,
+    params: Vec<ExtensionParam>,
+}
+
+impl Extension for Offer {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn offer(&self) -> Vec<ExtensionParam> {
+        self.params.clone()
+    }
+
+    fn session(&self, _params: &[ExtensionParam]) -> Result<Box<dyn ExtensionSession>, String> {
+        Ok(Box::new(NumberedSession(0)))
+    }
+}
+
+fn rsv2_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = encode_frame(true, 0x1, payload);
+    frame[0] |= RSV2;
+    frame
+}
+
+#[tokio::test]
+async fn negotiates_and_transforms_frames() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .extension(Xor {
+            key: 42,
+            offer_key: true,
+        });
+    let (protocol, mut messages) = Forward::new();
+    let (ws, server) = tokio::join!(WebSocket::connect_with(builder, protocol), server.accept());
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    assert_eq!(
+        server.request_header("sec-websocket-extensions"),
+        Some("x-xor; key=42")
+    );
+    assert_eq!(ws.extensions(), ["x-xor"]);
+
+    ws.transport().write_text(b"hi").await.unwrap();
+    let frame = server.recv().await.unwrap();
+    assert_eq!(frame.rsv, RSV2);
+    assert_eq!(frame.payload, [b'h' ^ 42, b'i' ^ 42]);
+
+    let client = tokio::spawn(async move { ws.run().await });
+    let xored: Vec<u8> = b"hello".iter().map(|byte| byte ^ 42).collect();
+    server.send_raw(&rsv2_frame(&xored)).await.unwrap();
+    assert_eq!(messages.recv().await.unwrap().read_text(), "hello");
+
+    // Control frames don't go through the extension.
+    server.send_ping(b"plain").await.unwrap();
+    let pong = server.recv().await.unwrap();
+    assert_eq!((pong.rsv, pong.payload.as_slice()), (0, &b"plain"[..]));
+
+    server.send_close(1000, "").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    client.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn starts_a_session_on_every_connection() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .extension(Numbered);
+    let (protocol, _messages) = Forward::new();
+    let (ws, first) = tokio::join!(WebSocket::connect_with(builder, protocol), server.accept());
+    let (mut ws, mut first) = (ws.unwrap(), first.unwrap());
+    let mut transport = ws.transport();
+    transport.write_text(b"a").await.unwrap();
+    transport.write_text(b"b").await.unwrap();
+    assert_eq!(first.expect_text().await.unwrap(), "0:a");
+    assert_eq!(first.expect_text().await.unwrap(), "1:b");
+    first.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+
+    let (reconnected, second) = tokio::join!(ws.reconnect(), server.accept());
+    reconnected.unwrap();
+    let mut second = second.unwrap();
+    transport.write_text(b"c").await.unwrap();
+    assert_eq!(second.expect_text().await.unwrap(), "0:c");
+}
+
+#[tokio::test]
+async fn fails_handshake_when_extension_rejects_parameters() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .extension(Xor {
+            key: 42,
+            offer_key: false,
+        });
+    let (protocol, _messages) = Forward::new();
+    let (ws, _server) = tokio::join!(WebSocket::connect_with(builder, protocol), server.accept());
+    assert!(ws.is_err());
+}
+
+#[tokio::test]
+async fn rejects_rsv_bits_without_extension() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, _messages) = Forward::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    assert!(ws.extensions().is_empty());
+    let client = tokio::spawn(async move { ws.run().await });
+
+    server.send_raw(&rsv2_frame(b"?")).await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1002));
+    assert!(client.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn offers_quoted_parameter_values() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .extension(Offer {
+            name: "x-offer",
+            params: vec![
+                ExtensionParam::new("level", Some("3")),
+                ExtensionParam::new("label", Some(r#""a \"b\"""#)),
+                ExtensionParam::new("flag", None),
+            ],
+        });
+    let (protocol, _messages) = Forward::new();
+    let (ws, server) = tokio::join!(WebSocket::connect_with(builder, protocol), server.accept());
+    ws.unwrap();
+    assert_eq!(
+        server.unwrap().request_header("sec-websocket-extensions"),
+        Some(r#"x-offer; level=3; label="a \"b\""; flag"#)
+    );
+}
+
+#[tokio::test]
+async fn rejects_offers_that_would_break_the_header() {
+    let server = MockServer::bind().await.unwrap();
+    let uri = server.uri().unwrap();
+    let offers = [
+        ("x-offer\r\nX-Injected: 1", Vec::new()),
+        ("x offer", Vec::new()),
+        ("x-offer", vec![ExtensionParam::new("a=b", None)]),
+        (
+            "x-offer",
+            vec![ExtensionParam::new("key", Some("1\r\nX-Injected: 1"))],
+        ),
+        (
+            "x-offer",
+            vec![ExtensionParam::new("key", Some(r#""unterminated"#))],
+        ),
+    ];
+    for (name, params) in offers {
+        let builder = StreamBuilder::new(get_uri(uri.clone()).unwrap(), None)
+            .unwrap()
+            .extension(Offer { name, params });
+        let (protocol, _messages) = Forward::new();
+        let rejected = WebSocket::connect_with(builder, protocol).await;
+        assert!(
+            matches!(
+                rejected,
+                Err(WebSocketError::Handshake(
+                    HandshakeFailureError::HeaderError(_)
+                ))
+            ),
+            "{name:?}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn rejects_rsv_bits_on_control_frames() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .extension(Xor {
+            key: 42,
+            offer_key: true,
+        });
+    let (protocol, _messages) = Forward::new();
+    let (ws, server) = tokio::join!(WebSocket::connect_with(builder, protocol), server.accept());
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let client = tokio::spawn(async move { ws.run().await });
+
+    // RSV2 belongs to the negotiated extension, but never on a control frame.
+    let mut ping = encode_frame(true, 0x9, b"");
+    ping[0] |= RSV2;
+    server.send_raw(&ping).await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1002));
+    assert!(client.await.unwrap().is_err());
+}
@@ -0,0 +1,83 @@
+use mayuri::core::{
+    enums::Opcode,
+    frame::{Frame, HandshakeHeaders, Headers},
+};
+use proptest::prelude::*;
+
+// Undoes the client side masking so the frame can go through the decoder.
+fn decode_client_frame(bytes: &[u8]) -> Frame {
+    let mut headers = Headers::decode(bytes).unwrap();
+    assert!(headers.mask);
+    let mut offset = 2;
+    headers.payload_len_ext = match headers.extend_by {
+        16 => {
+            offset += 2;
+            u64::from(u16::from_be_bytes(bytes[2..4].try_into().unwrap()))
+        }
+        64 => {
+            offset += 8;
+            u64::from_be_bytes(bytes[2..10].try_into().unwrap())
+        }
+        _ => 0,
+    };
+    let key = &bytes[offset..offset + 4];
+    let payload: Vec<u8> = bytes[offset + 4..]
+        .iter()
+        .zip(key.iter().cycle())
+        .map(|(byte, key)| byte ^ key)
+        .collect();
+    Frame::decode(&payload, headers).unwrap()
+}
+
+fn opcode() -> impl Strategy<Value = Opcode> {
+    prop_oneof![
+        Just(Opcode::Continuation),
+        Just(Opcode::Text),
+        Just(Opcode::Binary),
+        Just(Opcode::Close),
+        Just(Opcode::Ping),
+        Just(Opcode::Pong),
+    ]
+}
+
+// Sizes around the 7 bit, 16 bit and 64 bit length encodings.
+fn payload() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..=130),
+        prop::collection::vec(any::<u8>(), 65_530..=65_541),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn encode_decode_round_trip(opcode in opcode(), fin: bool, payload in payload()) {
+        let mut frame = Frame::set_defaults(opcode, &payload);
+        frame.headers.fin = fin;
+        let encoded = frame.encode().unwrap();
+
+        let expected_len = match payload.len() {
+            0..=125 => 2,
+            126..=65_535 => 4,
+            _ => 10,
+        } + 4 + payload.len();
+        prop_assert_eq!(encoded.len(), expected_len);
+        prop_assert_eq!(frame.headers.encoded_len() + payload.len(), expected_len);
+        prop_assert_eq!(&frame.payload_data, &payload);
+
+        let decoded = decode_client_frame(&encoded);
+        prop_assert_eq!(decoded.headers.opcode, opcode);
+        prop_assert_eq!(decoded.headers.fin, fin);
+        prop_assert!(!decoded.headers.rsv1 && !decoded.headers.rsv2 && !decoded.headers.rsv3);
+        prop_assert_eq!(decoded.payload_data, payload);
+    }
+
+    #[test]
+    fn decoders_reject_garbage_without_panicking(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Ok(mut headers) = Headers::decode(&bytes) {
+            headers.payload_len_ext = u64::MAX;
+            headers.extend_by = 64;
+            prop_assert!(Frame::decode(&bytes, headers).is_err());
+        }
+        let _ = HandshakeHeaders::new(&String::from_utf8_lossy(&bytes));
+    }
+}
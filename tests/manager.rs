@@ -0,0 +1,121 @@
+use mayuri::{
+    ConnectionManager, State, StreamBuilder, WebSocketError,
+    core::{
+        errors::ConnectionError,
+        testing::{ClientFrame, Idle, MockServer, Script},
+        utils::get_uri,
+    },
+};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+type Served = JoinHandle<Result<Vec<ClientFrame>, WebSocketError>>;
+
+async fn serve(script: Script) -> (StreamBuilder, Served) {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None).unwrap();
+    (builder, server.serve(script))
+}
+
+// Keeps the connection open until the client closes it.
+fn until_closed() -> Script {
+    Script::new().expect_close(Some(1000))
+}
+
+#[tokio::test]
+async fn adds_and_reports_connections() {
+    let mut manager = ConnectionManager::new();
+    let (first, _first) = serve(until_closed()).await;
+    let (second, _second) = serve(until_closed()).await;
+    manager.add("first", first, Idle).await.unwrap();
+    manager.add("second", second, Idle).await.unwrap();
+
+    let mut names: Vec<&str> = manager.names().collect();
+    names.sort_unstable();
+    assert_eq!(names, ["first", "second"]);
+    assert_eq!(manager.len(), 2);
+    assert_eq!(manager.transport("first").unwrap().state(), State::OPEN);
+    assert!(manager.transport("third").is_none());
+
+    let health = manager.health();
+    assert!(health.is_healthy());
+    assert_eq!((health.open, health.closed, health.errored), (2, 0, 0));
+    assert_eq!(health.connections["second"].state, State::OPEN);
+}
+
+#[tokio::test]
+async fn exports_every_connection_in_one_exposition() {
+    let mut manager = ConnectionManager::new();
+    let (first, _first) = serve(until_closed()).await;
+    let (second, _second) = serve(until_closed()).await;
+    manager.add("second", second, Idle).await.unwrap();
+    manager.add("first", first, Idle).await.unwrap();
+
+    let text = manager.to_prometheus();
+    assert_eq!(
+        text.matches("# TYPE mayuri_reconnects_total counter")
+            .count(),
+        1
+    );
+    assert!(text.contains(concat!(
+        "mayuri_reconnects_total{connection=\"first\"} 0\n",
+        "mayuri_reconnects_total{connection=\"second\"} 0\n",
+    )));
+}
+
+#[tokio::test]
+async fn rejects_duplicate_names() {
+    let mut manager = ConnectionManager::new();
+    let (builder, _server) = serve(until_closed()).await;
+    manager.add("feed", builder.clone(), Idle).await.unwrap();
+
+    let result = manager.add("feed", builder, Idle).await;
+    assert!(matches!(
+        result,
+        Err(WebSocketError::Stream(ConnectionError::DuplicateName(name))) if name == "feed"
+    ));
+    assert_eq!(manager.len(), 1);
+}
+
+#[tokio::test]
+async fn remove_closes_the_connection() {
+    let mut manager = ConnectionManager::new();
+    let (builder, server) = serve(until_closed().send_close(1000, "")).await;
+    manager.add("feed", builder, Idle).await.unwrap();
+
+    assert!(manager.remove("feed").await);
+    assert!(!manager.remove("feed").await);
+    assert!(manager.is_empty());
+    let frames = server.await.unwrap().unwrap();
+    assert_eq!(frames.last().unwrap().close_code(), Some(1000));
+}
+
+#[tokio::test]
+async fn reports_finished_and_failed_connections() {
+    let mut manager = ConnectionManager::new();
+    let (closing, _closing) =
+        serve(Script::new().send_close(1000, "").expect_close(Some(1000))).await;
+    let (failing, _failing) =
+        serve(Script::new().sleep(Duration::from_millis(50)).disconnect()).await;
+    manager.add("closing", closing, Idle).await.unwrap();
+    manager.add("failing", failing, Idle).await.unwrap();
+
+    let mut finished = Vec::new();
+    while let Some((name, result)) = manager.next_finished().await {
+        finished.push((name, result.is_ok()));
+    }
+    finished.sort_unstable();
+    assert_eq!(
+        finished,
+        [
+            ("closing".to_string(), true),
+            ("failing".to_string(), false)
+        ]
+    );
+
+    let health = manager.health();
+    assert!(!health.is_healthy());
+    assert_eq!((health.open, health.closed, health.errored), (0, 1, 1));
+    assert!(health.connections["closing"].last_error.is_none());
+    assert!(health.connections["failing"].last_error.is_some());
+}
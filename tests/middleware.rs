@@ -0,0 +1,162 @@
+use mayuri::{
+    Middleware, StreamBuilder, Transport, WebSocket, WebSocketError,
+    async_trait::async_trait,
+    core::{
+        enums::Opcode,
+        errors::ConnectionError,
+        frame::Frame,
+        testing::{Forward, MockServer},
+        utils::get_uri,
+    },
+};
+use std::sync::{Arc, Mutex};
+
+type Log = Arc<Mutex<Vec<String>>>;
+
+// Records every message it sees, to check the order middleware runs in.
+struct Tag {
+    name: &'static str,
+    log: Log,
+}
+
+impl Tag {
+    fn record(&self, direction: &str) {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} {direction}", self.name));
+    }
+}
+
+#[async_trait]
+impl Middleware for Tag {
+    async fn inbound(&self, frame: Frame, _transport: &Transport) -> Vec<Frame> {
+        self.record("in");
+        vec![frame]
+    }
+
+    async fn outbound(&self, frame: Frame) -> Result<Vec<Frame>, WebSocketError> {
+        self.record("out");
+        Ok(vec![frame])
+    }
+}
+
+// Drops heartbeats, answers `expired` with `refresh`, splits batches and
+// upper-cases everything else.
+struct Control;
+
+#[async_trait]
+impl Middleware for Control {
+    async fn inbound(&self, frame: Frame, transport: &Transport) -> Vec<Frame> {
+        let text = String::from_utf8_lossy(&frame.payload_data).to_string();
+        match text.as_str() {
+            "heartbeat" => Vec::new(),
+            "expired" => {
+                transport.clone().write_text(b"refresh").await.unwrap();
+                Vec::new()
+            }
+            _ => text
+                .split(',')
+                .map(|part| Frame::set_defaults(Opcode::Text, part.to_uppercase().as_bytes()))
+                .collect(),
+        }
+    }
+}
+
+// Fails every write.
+struct Refuse;
+
+#[async_trait]
+impl Middleware for Refuse {
+    async fn outbound(&self, _frame: Frame) -> Result<Vec<Frame>, WebSocketError> {
+        Err(WebSocketError::Stream(ConnectionError::WriteError(
+            "refused".into(),
+        )))
+    }
+}
+
+#[tokio::test]
+async fn runs_chain_in_order_and_drops_transforms_and_injects() {
+    let server = MockServer::bind().await.unwrap();
+    let log = Log::default();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .middleware(Tag {
+            name: "outer",
+            log: Arc::clone(&log),
+        })
+        .middleware(Control)
+        .middleware(Tag {
+            name: "inner",
+            log: Arc::clone(&log),
+        });
+    let (protocol, mut messages) = Forward::new();
+    let (ws, server) = tokio::join!(WebSocket::connect_with(builder, protocol), server.accept());
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let transport = ws.transport();
+    let client = tokio::spawn(async move { ws.run().await });
+
+    server.send_text("heartbeat").await.unwrap();
+    server.send_text("a,b").await.unwrap();
+    assert_eq!(messages.recv().await.unwrap().read_text(), "A");
+    assert_eq!(messages.recv().await.unwrap().read_text(), "B");
+
+    transport.clone().write_text(b"hello").await.unwrap();
+    assert_eq!(server.expect_text().await.unwrap(), "hello");
+
+    server.send_text("expired").await.unwrap();
+    assert_eq!(server.expect_text().await.unwrap(), "refresh");
+
+    // Control frames skip middleware.
+    server.send_ping(b"ping").await.unwrap();
+    assert_eq!(server.recv().await.unwrap().payload, b"ping");
+    assert_eq!(
+        messages.recv().await.unwrap().frame.headers.opcode,
+        Opcode::Ping
+    );
+    server.send_close(1000, "").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    client.await.unwrap().unwrap();
+    assert!(messages.try_recv().is_err());
+
+    let expected = [
+        "outer in", // heartbeat
+        "outer in", // a,b
+        "inner in",
+        "inner in",
+        "inner out", // hello
+        "outer out",
+        "outer in",  // expired
+        "inner out", // refresh
+        "outer out",
+    ];
+    assert_eq!(*log.lock().unwrap(), expected);
+}
+
+#[tokio::test]
+async fn answers_pings_when_middleware_refuses_writes() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .middleware(Refuse);
+    let (protocol, mut messages) = Forward::new();
+    let (ws, server) = tokio::join!(WebSocket::connect_with(builder, protocol), server.accept());
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let mut transport = ws.transport();
+    let client = tokio::spawn(async move { ws.run().await });
+
+    assert!(transport.write_text(b"hello").await.is_err());
+    server.send_ping(b"still there?").await.unwrap();
+    assert_eq!(server.recv().await.unwrap().payload, b"still there?");
+    server.send_text("yes").await.unwrap();
+    assert_eq!(
+        messages.recv().await.unwrap().frame.headers.opcode,
+        Opcode::Ping
+    );
+    assert_eq!(messages.recv().await.unwrap().read_text(), "yes");
+
+    transport.close(1000, "").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    server.send_close(1000, "").await.unwrap();
+    client.await.unwrap().unwrap();
+}
@@ -0,0 +1,588 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use mayuri::{
+    Context, State, StreamBuilder, Transport, WebSocket, WebSocketError, WebSocketProtocol,
+    async_trait::async_trait,
+    core::{
+        enums::Opcode,
+        errors::HandshakeFailureError,
+        testing::{MockConnection, MockServer, Script, encode_frame},
+        utils::get_uri,
+    },
+};
+use sha1::{Digest, Sha1};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{
+        Semaphore,
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    },
+};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Connected,
+    Message(String),
+    Frame(Opcode, bool),
+    Closed,
+}
+
+// Records every callback and echoes text messages back.
+struct Echo {
+    events: UnboundedSender<Event>,
+    transport: Option<Transport>,
+    greeting: Option<&'static str>,
+}
+
+impl Echo {
+    fn new() -> (Self, UnboundedReceiver<Event>) {
+        let (events, rx) = unbounded_channel();
+        let echo = Self {
+            events,
+            transport: None,
+            greeting: None,
+        };
+        (echo, rx)
+    }
+}
+
+#[async_trait]
+impl WebSocketProtocol for Echo {
+    async fn on_connect(&mut self, mut transport: Transport) {
+        if let Some(greeting) = self.greeting {
+            transport.write_text(greeting.as_bytes()).await.unwrap();
+        }
+        self.transport = Some(transport);
+        self.events.send(Event::Connected).unwrap();
+    }
+
+    async fn on_message(&mut self, ctx: Context) {
+        if ctx.frame.headers.opcode != Opcode::Text {
+            return;
+        }
+        let text = ctx.read_text();
+        if let Some(transport) = &mut self.transport {
+            transport.write_text(text.as_bytes()).await.unwrap();
+        }
+        self.events.send(Event::Message(text)).unwrap();
+    }
+
+    async fn on_close(&mut self, _ctx: Context) {
+        self.events.send(Event::Closed).unwrap();
+    }
+
+    async fn on_frame(&mut self, ctx: Context) {
+        let headers = &ctx.frame.headers;
+        self.events
+            .send(Event::Frame(headers.opcode, headers.fin))
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn echoes_over_duplex() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, mut events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/chat", protocol),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+
+    assert_eq!(server.request_header("upgrade"), Some("websocket"));
+    assert!(server.request().starts_with("GET /chat HTTP/1.1"));
+
+    let client = tokio::spawn(async move {
+        ws.run().await.unwrap();
+        ws.state()
+    });
+
+    assert_eq!(events.recv().await, Some(Event::Connected));
+    server.send_text("hello").await.unwrap();
+    assert_eq!(server.expect_text().await.unwrap(), "hello");
+    assert_eq!(events.recv().await, Some(Event::Message("hello".into())));
+
+    server.send_close(1000, "bye").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    assert_eq!(client.await.unwrap(), State::CLOSED);
+    assert_eq!(events.recv().await, Some(Event::Closed));
+}
+
+#[tokio::test]
+async fn plays_script_on_local_port() {
+    let server = MockServer::bind().await.unwrap();
+    let uri = server.uri().unwrap();
+    let handle = server.serve(
+        Script::new()
+            .expect_text("hi")
+            .send_text("ping")
+            .expect_text("ping")
+            .send_close(1000, "")
+            .expect_close(Some(1000)),
+    );
+
+    let (mut protocol, _events) = Echo::new();
+    protocol.greeting = Some("hi");
+    let mut ws = WebSocket::connect(&uri, protocol).await.unwrap();
+    ws.run().await.unwrap();
+
+    let received = handle.await.unwrap().unwrap();
+    assert_eq!(received.len(), 3);
+}
+
+#[tokio::test]
+async fn reassembles_slowly_sent_frames() {
+    let server = MockServer::bind().await.unwrap();
+    let uri = server.uri().unwrap();
+    let frame = encode_frame(true, 0x1, b"slow");
+    let handle = server.serve(
+        Script::new()
+            .send_slowly(&frame, Duration::from_millis(5))
+            .expect_text("slow")
+            .send_close(1000, "")
+            .expect_close(None),
+    );
+
+    let (protocol, mut events) = Echo::new();
+    let mut ws = WebSocket::connect(&uri, protocol).await.unwrap();
+    ws.run().await.unwrap();
+
+    handle.await.unwrap().unwrap();
+    assert_eq!(events.recv().await, Some(Event::Connected));
+    assert_eq!(events.recv().await, Some(Event::Message("slow".into())));
+}
+
+#[tokio::test]
+async fn delivers_empty_messages() {
+    let server = MockServer::bind().await.unwrap();
+    let uri = server.uri().unwrap();
+    let handle = server.serve(
+        Script::new()
+            .send_text("")
+            .expect_text("")
+            .send_close(1000, "")
+            .expect_close(Some(1000)),
+    );
+
+    let (protocol, mut events) = Echo::new();
+    let mut ws = WebSocket::connect(&uri, protocol).await.unwrap();
+    ws.run().await.unwrap();
+
+    handle.await.unwrap().unwrap();
+    assert_eq!(events.recv().await, Some(Event::Connected));
+    assert_eq!(events.recv().await, Some(Event::Message(String::new())));
+}
+
+#[tokio::test]
+async fn handles_empty_frames_of_every_kind() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, mut events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let client = tokio::spawn(async move { ws.run().await });
+    assert_eq!(events.recv().await, Some(Event::Connected));
+
+    server.send_binary(&[]).await.unwrap();
+    server.send_ping(&[]).await.unwrap();
+    let pong = server.recv().await.unwrap();
+    assert_eq!((pong.opcode, pong.payload.len()), (Opcode::Pong, 0));
+    // A message ending in an empty Continuation frame, and one made of empty frames.
+    server.send_frame(false, 0x1, b"done").await.unwrap();
+    server.send_frame(true, 0x0, &[]).await.unwrap();
+    assert_eq!(server.expect_text().await.unwrap(), "done");
+    server.send_frame(false, 0x1, &[]).await.unwrap();
+    server.send_frame(true, 0x0, &[]).await.unwrap();
+    assert_eq!(server.expect_text().await.unwrap(), "");
+
+    // A Close frame without a code is answered with a normal closure.
+    server.send_frame(true, 0x8, &[]).await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    client.await.unwrap().unwrap();
+    for event in [
+        Event::Message("done".into()),
+        Event::Message(String::new()),
+        Event::Closed,
+    ] {
+        assert_eq!(events.recv().await, Some(event));
+    }
+}
+
+#[tokio::test]
+async fn keeps_frames_sent_with_the_handshake_response() {
+    let (io, mut server) = tokio::io::duplex(4096);
+    let accept = async move {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(server.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        let key = request
+            .lines()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        let accept = STANDARD.encode(Sha1::digest(format!("{key}{WEBSOCKET_GUID}")));
+        // The first frame goes out in the same write as the response.
+        let mut response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+        )
+        .into_bytes();
+        response.extend(encode_frame(true, 0x1, b"early"));
+        server.write_all(&response).await.unwrap();
+        server
+    };
+
+    let (protocol, mut events) = Echo::new();
+    let (ws, _server) = tokio::join!(WebSocket::from_stream(io, "ws://mock/", protocol), accept);
+    let mut ws = ws.unwrap();
+    let client = tokio::spawn(async move { ws.run().await });
+    assert_eq!(events.recv().await, Some(Event::Connected));
+    assert_eq!(events.recv().await, Some(Event::Message("early".into())));
+    client.abort();
+}
+
+#[tokio::test]
+async fn sends_headers_and_negotiates_subprotocol() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .header("Authorization", "Bearer token")
+        .subprotocol("v2.chat")
+        .subprotocol("chat");
+
+    let (protocol, _events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::connect_with(builder.clone(), protocol),
+        server.accept()
+    );
+    let (ws, server) = (ws.unwrap(), server.unwrap());
+    assert_eq!(server.request_header("authorization"), Some("Bearer token"));
+    assert_eq!(
+        server.request_header("sec-websocket-protocol"),
+        Some("v2.chat, chat")
+    );
+    assert_eq!(ws.subprotocol(), Some("v2.chat"));
+
+    let (protocol, _events) = Echo::new();
+    let rejected = WebSocket::connect_with(builder.header("Upgrade", "h2c"), protocol).await;
+    assert!(rejected.is_err());
+}
+
+#[tokio::test]
+async fn rejects_subprotocols_that_arent_tokens() {
+    let server = MockServer::bind().await.unwrap();
+    let uri = server.uri().unwrap();
+    for subprotocol in ["chat\r\nX-Injected: 1", "", "two words", "a,b"] {
+        let builder = StreamBuilder::new(get_uri(uri.clone()).unwrap(), None)
+            .unwrap()
+            .subprotocol(subprotocol);
+        let (protocol, _events) = Echo::new();
+        let rejected = WebSocket::connect_with(builder, protocol).await;
+        assert!(
+            matches!(
+                rejected,
+                Err(WebSocketError::Handshake(
+                    HandshakeFailureError::HeaderError(_)
+                ))
+            ),
+            "{subprotocol:?}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn fails_on_reserved_opcode() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, _events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+
+    server.send_frame(true, 0x3, b"?").await.unwrap();
+    assert!(ws.run().await.is_err());
+    assert_eq!(ws.state(), State::ERROR);
+}
+
+#[tokio::test]
+async fn fails_on_mid_frame_disconnect() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, _events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let mut ws = ws.unwrap();
+
+    server
+        .unwrap()
+        .disconnect_mid_frame(0x1, b"cut short", 4)
+        .await
+        .unwrap();
+    assert!(ws.run().await.is_err());
+    assert_ne!(ws.state(), State::OPEN);
+}
+
+#[tokio::test]
+async fn client_starts_closing_handshake() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, _events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+
+    ws.transport().close(4000, "done").await.unwrap();
+    assert_eq!(ws.state(), State::CLOSING);
+    let frame = server.recv().await.unwrap();
+    assert_eq!(frame.close_code(), Some(4000));
+    assert_eq!(&frame.payload[2..], b"done");
+
+    server.send_close(4000, "").await.unwrap();
+    ws.run().await.unwrap();
+    assert_eq!(ws.state(), State::CLOSED);
+}
+
+#[tokio::test]
+async fn answers_ping_and_rejects_masked_frames() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, _events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let client = tokio::spawn(async move { ws.run().await });
+
+    server.send_ping(b"are you there").await.unwrap();
+    let pong = server.recv().await.unwrap();
+    assert_eq!(pong.opcode, Opcode::Pong);
+    assert_eq!(pong.payload, b"are you there");
+
+    // Mask bit set with an all-zero masking key.
+    server
+        .send_raw(&[0x81, 0x81, 0, 0, 0, 0, b'x'])
+        .await
+        .unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1002));
+    assert!(client.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn reassembles_fragmented_messages() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .frame_hook(true);
+    let handle = server.serve(
+        Script::new()
+            .send_frame(false, 0x1, b"hel")
+            .send_ping(b"mid")
+            .send_frame(true, 0x0, b"lo")
+            .expect_frame(Some(Opcode::Pong))
+            .expect_text("hello")
+            .send_close(1000, "")
+            .expect_close(Some(1000)),
+    );
+
+    let (protocol, mut events) = Echo::new();
+    let mut ws = WebSocket::connect_with(builder, protocol).await.unwrap();
+    ws.run().await.unwrap();
+    handle.await.unwrap().unwrap();
+
+    let expected = [
+        Event::Connected,
+        Event::Frame(Opcode::Text, false),
+        Event::Frame(Opcode::Ping, true),
+        Event::Frame(Opcode::Continuation, true),
+        Event::Message("hello".into()),
+        Event::Frame(Opcode::Close, true),
+        Event::Closed,
+    ];
+    for event in expected {
+        assert_eq!(events.recv().await, Some(event));
+    }
+}
+
+#[tokio::test]
+async fn sends_fragmented_messages() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, _events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (ws, mut server) = (ws.unwrap(), server.unwrap());
+
+    ws.transport()
+        .send_fragmented(Opcode::Text, ["frag", "ment", "ed"])
+        .await
+        .unwrap();
+    let frames = [
+        (Opcode::Text, false, "frag"),
+        (Opcode::Continuation, false, "ment"),
+        (Opcode::Continuation, true, "ed"),
+    ];
+    for (opcode, fin, text) in frames {
+        let frame = server.recv().await.unwrap();
+        assert_eq!(
+            (frame.opcode, frame.fin, frame.text().as_str()),
+            (opcode, fin, text)
+        );
+    }
+}
+
+#[tokio::test]
+async fn fails_on_unexpected_continuation() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, _events) = Echo::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let client = tokio::spawn(async move { ws.run().await });
+
+    server.send_frame(true, 0x0, b"orphan").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1002));
+    assert!(client.await.unwrap().is_err());
+}
+
+// Handles each message only once the test releases it.
+struct Stuck {
+    release: Arc<Semaphore>,
+    events: UnboundedSender<Event>,
+}
+
+#[async_trait]
+impl WebSocketProtocol for Stuck {
+    async fn on_connect(&mut self, _transport: Transport) {}
+
+    async fn on_message(&mut self, ctx: Context) {
+        if ctx.frame.headers.opcode != Opcode::Text {
+            return;
+        }
+        if ctx.read_text() == "panic" {
+            panic!("handler panicked");
+        }
+        self.release.acquire().await.unwrap().forget();
+        self.events.send(Event::Message(ctx.read_text())).unwrap();
+    }
+
+    async fn on_close(&mut self, _ctx: Context) {
+        self.events.send(Event::Closed).unwrap();
+    }
+}
+
+async fn connect<P: WebSocketProtocol + Send + Sync + 'static>(
+    protocol: P,
+) -> (WebSocket<P>, MockConnection) {
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    (ws.unwrap(), server.unwrap())
+}
+
+#[tokio::test]
+async fn reads_on_while_a_handler_is_busy() {
+    let release = Arc::new(Semaphore::new(0));
+    let (events, mut rx) = unbounded_channel();
+    let protocol = Stuck {
+        release: Arc::clone(&release),
+        events,
+    };
+    let (mut ws, mut server) = connect(protocol).await;
+    let client = tokio::spawn(async move { ws.run().await });
+
+    server.send_text("one").await.unwrap();
+    server.send_text("panic").await.unwrap();
+    server.send_text("two").await.unwrap();
+    server.send_ping(b"still there").await.unwrap();
+    assert_eq!(server.recv().await.unwrap().opcode, Opcode::Pong);
+    server.send_close(1000, "").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    client.await.unwrap().unwrap();
+    assert!(rx.try_recv().is_err());
+
+    // Handlers still run in order, and a panicking one doesn't stop the rest.
+    release.add_permits(2);
+    for event in [
+        Event::Message("one".into()),
+        Event::Message("two".into()),
+        Event::Closed,
+    ] {
+        assert_eq!(rx.recv().await, Some(event));
+    }
+}
+
+#[tokio::test]
+async fn fails_on_invalid_close_frames() {
+    let mut invalid: Vec<(Vec<u8>, u16)> = [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000]
+        .into_iter()
+        .map(|code: u16| (code.to_be_bytes().to_vec(), 1002))
+        .collect();
+    invalid.push((vec![0x03], 1002));
+    invalid.push((vec![0x03, 0xE8, 0xFF, 0xFE], 1007));
+
+    for (payload, expected) in invalid {
+        let (protocol, _events) = Echo::new();
+        let (mut ws, mut server) = connect(protocol).await;
+        server
+            .send_frame(true, Opcode::Close as u8, &payload)
+            .await
+            .unwrap();
+        assert!(ws.run().await.is_err());
+        assert_eq!(
+            server.expect_close().await.unwrap(),
+            Some(expected),
+            "{payload:?}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn echoes_valid_close_codes() {
+    for code in [1000, 1001, 1003, 1007, 1011, 3000, 4999] {
+        let (protocol, _events) = Echo::new();
+        let (mut ws, mut server) = connect(protocol).await;
+        server.send_close(code, "bye ✓").await.unwrap();
+        ws.run().await.unwrap();
+        assert_eq!(server.expect_close().await.unwrap(), Some(code));
+    }
+}
+
+#[tokio::test]
+async fn validates_utf8_across_fragments() {
+    let (protocol, _events) = Echo::new();
+    let (mut ws, mut server) = connect(protocol).await;
+    let client = tokio::spawn(async move { ws.run().await });
+
+    // `é` split between two frames.
+    server.send_frame(false, 0x1, b"caf\xC3").await.unwrap();
+    server.send_frame(true, 0x0, b"\xA9").await.unwrap();
+    assert_eq!(server.expect_text().await.unwrap(), "café");
+
+    // Fails on the first fragment, without waiting for the message to end.
+    server.send_frame(false, 0x1, b"ok \xFF").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1007));
+    assert!(client.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn fails_on_text_ending_mid_character() {
+    let (protocol, _events) = Echo::new();
+    let (mut ws, mut server) = connect(protocol).await;
+
+    server.send_frame(true, 0x1, b"caf\xC3").await.unwrap();
+    assert!(ws.run().await.is_err());
+    assert_eq!(server.expect_close().await.unwrap(), Some(1007));
+}
@@ -0,0 +1,139 @@
+use mayuri::{
+    Extension, ExtensionParam, ExtensionSession, OverflowPolicy, State, StreamBuilder, WebSocket,
+    WebSocketError,
+    core::{
+        errors::ConnectionError,
+        frame::Frame,
+        testing::{Idle, MockServer},
+        utils::get_uri,
+    },
+};
+
+// Refuses to send the payload `poison`.
+struct Poison;
+
+impl Extension for Poison {
+    fn name(&self) -> &str {
+        "x-poison"
+    }
+
+    fn session(&self, _params: &[ExtensionParam]) -> Result<Box<dyn ExtensionSession>, String> {
+        Ok(Box::new(Self))
+    }
+}
+
+impl ExtensionSession for Poison {
+    fn outgoing(&mut self, frame: &mut Frame) -> Result<(), String> {
+        if frame.payload_data == b"poison" {
+            return Err("poisoned".into());
+        }
+        Ok(())
+    }
+}
+
+// Connects, lets the server close the connection and returns once it's closed,
+// so new writes go to the outbound queue.
+async fn closed(server: &MockServer, builder: StreamBuilder) -> WebSocket<Idle> {
+    let (ws, first) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    let (mut ws, mut first) = (ws.unwrap(), first.unwrap());
+    first.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    ws
+}
+
+async fn queue_three(server: &MockServer, policy: OverflowPolicy) -> (WebSocket<Idle>, Vec<bool>) {
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .outbound_queue(2, policy)
+        .unwrap();
+    let ws = closed(server, builder).await;
+    let mut transport = ws.transport();
+    let mut written = Vec::new();
+    for text in ["one", "two", "three"] {
+        written.push(transport.write_text(text.as_bytes()).await.is_ok());
+    }
+    assert_eq!(transport.queued(), 2);
+    (ws, written)
+}
+
+// Reconnects and returns the texts the server got first.
+async fn flushed(server: &MockServer, ws: &mut WebSocket<Idle>) -> Vec<String> {
+    let (reconnected, second) = tokio::join!(ws.reconnect(), server.accept());
+    reconnected.unwrap();
+    let mut second = second.unwrap();
+    assert_eq!(ws.transport().queued(), 0);
+    let mut transport = ws.transport();
+    transport.write_text(b"after").await.unwrap();
+    let mut texts = Vec::new();
+    for _ in 0..3 {
+        texts.push(second.expect_text().await.unwrap());
+    }
+    texts
+}
+
+#[test]
+fn rejects_a_queue_that_holds_nothing() {
+    let builder = StreamBuilder::new(get_uri("ws://mock/".into()).unwrap(), None).unwrap();
+    assert!(matches!(
+        builder
+            .clone()
+            .outbound_queue(0, OverflowPolicy::DropNewest),
+        Err(ConnectionError::InvalidQueue(_))
+    ));
+    assert!(builder.outbound_queue(1, OverflowPolicy::Error).is_ok());
+}
+
+#[tokio::test]
+async fn drops_the_oldest_message_when_full() {
+    let server = MockServer::bind().await.unwrap();
+    let (mut ws, written) = queue_three(&server, OverflowPolicy::DropOldest).await;
+    assert_eq!(written, [true, true, true]);
+    assert_eq!(flushed(&server, &mut ws).await, ["two", "three", "after"]);
+}
+
+#[tokio::test]
+async fn drops_the_newest_message_when_full() {
+    let server = MockServer::bind().await.unwrap();
+    let (mut ws, written) = queue_three(&server, OverflowPolicy::DropNewest).await;
+    assert_eq!(written, [true, true, true]);
+    assert_eq!(flushed(&server, &mut ws).await, ["one", "two", "after"]);
+}
+
+#[tokio::test]
+async fn fails_writes_when_full() {
+    let server = MockServer::bind().await.unwrap();
+    let (mut ws, written) = queue_three(&server, OverflowPolicy::Error).await;
+    assert_eq!(written, [true, true, false]);
+    let mut transport = ws.transport();
+    assert!(matches!(
+        transport.write_text(b"four").await,
+        Err(WebSocketError::Stream(ConnectionError::QueueFull(2)))
+    ));
+    assert_eq!(flushed(&server, &mut ws).await, ["one", "two", "after"]);
+}
+
+#[tokio::test]
+async fn fails_the_connection_when_the_queue_cant_be_flushed() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .extension(Poison)
+        .outbound_queue(4, OverflowPolicy::Error)
+        .unwrap();
+    let mut ws = closed(&server, builder).await;
+    let mut transport = ws.transport();
+    transport.write_text(b"poison").await.unwrap();
+    transport.write_text(b"later").await.unwrap();
+
+    let (reconnected, second) = tokio::join!(ws.reconnect(), server.accept());
+    assert!(matches!(
+        reconnected,
+        Err(WebSocketError::Stream(
+            ConnectionError::ExtensionError { .. }
+        ))
+    ));
+    drop(second.unwrap());
+    assert_eq!(ws.state(), State::CLOSED);
+    // Both stay queued, in order.
+    assert_eq!(transport.queued(), 2);
+}
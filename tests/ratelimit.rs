@@ -0,0 +1,157 @@
+use mayuri::{
+    OverflowPolicy, RateLimit, StreamBuilder, WebSocket, WebSocketError,
+    core::{
+        enums::Opcode,
+        errors::ConnectionError,
+        testing::{Idle, MockConnection, MockServer},
+        utils::get_uri,
+    },
+};
+use std::time::{Duration, Instant};
+
+const PER: Duration = Duration::from_millis(100);
+
+async fn connect(limit: RateLimit) -> (WebSocket<Idle>, MockConnection) {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .rate_limit(limit);
+    let (ws, connection) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    (ws.unwrap(), connection.unwrap())
+}
+
+fn would_exceed(result: Result<(), WebSocketError>) -> bool {
+    matches!(
+        result,
+        Err(WebSocketError::Stream(ConnectionError::WouldExceedRateLimit(wait)))
+            if wait > Duration::ZERO && wait <= PER
+    )
+}
+
+#[test]
+fn rejects_limits_that_let_nothing_through() {
+    assert!(matches!(
+        RateLimit::new(0, PER),
+        Err(ConnectionError::InvalidRateLimit(_))
+    ));
+    assert!(matches!(
+        RateLimit::new(1, Duration::ZERO),
+        Err(ConnectionError::InvalidRateLimit(_))
+    ));
+    assert!(RateLimit::per_second(1).is_ok());
+}
+
+#[tokio::test]
+async fn sends_bursts_back_to_back() {
+    let limit = RateLimit::new(1, PER).unwrap().burst(3).fail_fast();
+    let (ws, _server) = connect(limit).await;
+    let mut transport = ws.transport();
+
+    for _ in 0..3 {
+        transport.write_text(b"burst").await.unwrap();
+    }
+    assert!(would_exceed(transport.write_text(b"over").await));
+}
+
+#[tokio::test]
+async fn waits_for_capacity() {
+    let limit = RateLimit::new(2, PER * 2).unwrap();
+    let (ws, mut server) = connect(limit).await;
+    let mut transport = ws.transport();
+
+    let started = Instant::now();
+    for text in ["one", "two", "three"] {
+        transport.write_text(text.as_bytes()).await.unwrap();
+    }
+    assert!(started.elapsed() >= PER * 9 / 10);
+    for text in ["one", "two", "three"] {
+        assert_eq!(server.expect_text().await.unwrap(), text);
+    }
+}
+
+#[tokio::test]
+async fn fails_fast_and_recovers() {
+    let limit = RateLimit::new(1, PER).unwrap().fail_fast();
+    let (ws, mut server) = connect(limit).await;
+    let mut transport = ws.transport();
+
+    transport.write_text(b"one").await.unwrap();
+    assert!(would_exceed(transport.write_text(b"two").await));
+    // Control frames aren't limited.
+    transport.ping(b"").await.unwrap();
+    tokio::time::sleep(PER).await;
+    transport.write_text(b"three").await.unwrap();
+
+    assert_eq!(server.expect_text().await.unwrap(), "one");
+    assert!(server.recv().await.unwrap().payload.is_empty());
+    assert_eq!(server.expect_text().await.unwrap(), "three");
+}
+
+#[tokio::test]
+async fn charges_fragmented_messages_once() {
+    let limit = RateLimit::new(1, PER).unwrap().fail_fast();
+    let (ws, mut server) = connect(limit).await;
+    let mut transport = ws.transport();
+
+    // Every fragment is sent, though only one message fits the limit.
+    transport
+        .send_fragmented(Opcode::Text, ["one", "two", "three"])
+        .await
+        .unwrap();
+    assert!(would_exceed(transport.write_text(b"four").await));
+    for text in ["one", "two", "three"] {
+        assert_eq!(server.recv().await.unwrap().payload, text.as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn charges_streamed_messages_once() {
+    let limit = RateLimit::new(1, PER).unwrap().fail_fast();
+    let (ws, mut server) = connect(limit).await;
+    let mut transport = ws.transport();
+
+    // Sent in three frames of at most 64 KiB.
+    let body = vec![7; 150_000];
+    transport
+        .send_stream(Opcode::Binary, body.as_slice())
+        .await
+        .unwrap();
+    assert!(would_exceed(transport.write_text(b"after").await));
+    let mut received = Vec::new();
+    for opcode in [Opcode::Binary, Opcode::Continuation, Opcode::Continuation] {
+        let frame = server.recv().await.unwrap();
+        assert_eq!(frame.opcode, opcode);
+        received.extend(frame.payload);
+    }
+    assert_eq!(received, body);
+}
+
+#[tokio::test]
+async fn flushing_the_queue_takes_capacity() {
+    let server = MockServer::bind().await.unwrap();
+    let limit = RateLimit::new(1, PER).unwrap().fail_fast();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .rate_limit(limit)
+        .outbound_queue(4, OverflowPolicy::Error)
+        .unwrap();
+    let (ws, first) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    let (mut ws, mut first) = (ws.unwrap(), first.unwrap());
+    first.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+
+    let mut transport = ws.transport();
+    transport.write_text(b"one").await.unwrap();
+    transport.write_text(b"two").await.unwrap();
+    let started = Instant::now();
+    let (reconnected, second) = tokio::join!(ws.reconnect(), server.accept());
+    reconnected.unwrap();
+    // The second queued message waited for capacity despite `fail_fast`, and
+    // left none for a new one.
+    assert!(started.elapsed() >= PER * 9 / 10);
+    assert!(would_exceed(transport.write_text(b"three").await));
+
+    let mut second = second.unwrap();
+    assert_eq!(second.expect_text().await.unwrap(), "one");
+    assert_eq!(second.expect_text().await.unwrap(), "two");
+}
@@ -0,0 +1,236 @@
+use mayuri::{
+    Context, Recorder, Replay, StreamBuilder, Transport, WebSocket, WebSocketProtocol,
+    async_trait::async_trait,
+    core::{
+        enums::Opcode,
+        record::Direction,
+        testing::{MockServer, Script},
+        utils::get_uri,
+    },
+};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Collects every message and the close code.
+#[derive(Default)]
+struct Collect {
+    transport: Option<Transport>,
+    messages: Vec<(Opcode, Vec<u8>)>,
+    frames: Vec<(Opcode, bool)>,
+    close_code: Option<u16>,
+}
+
+#[async_trait]
+impl WebSocketProtocol for Collect {
+    async fn on_connect(&mut self, mut transport: Transport) {
+        transport.write_text(b"subscribe").await.unwrap();
+        self.transport = Some(transport);
+    }
+
+    async fn on_message(&mut self, ctx: Context) {
+        self.messages
+            .push((ctx.frame.headers.opcode, ctx.frame.payload_data));
+    }
+
+    async fn on_close(&mut self, ctx: Context) {
+        self.close_code = ctx.frame.close_code();
+    }
+
+    async fn on_frame(&mut self, ctx: Context) {
+        let headers = ctx.frame.headers;
+        self.frames.push((headers.opcode, headers.fin));
+    }
+}
+
+#[tokio::test]
+async fn records_and_replays_a_session() {
+    let path = std::env::temp_dir().join(format!("mayuri-record-{}.jsonl", std::process::id()));
+    let server = MockServer::bind().await.unwrap();
+    let recorder = Recorder::create(&path).unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .recorder(recorder.clone());
+    let handle = server.serve(
+        Script::new()
+            .expect_text("subscribe")
+            .send_text("tick 1")
+            .send_binary(&[0, 159, 146, 150])
+            .send_close(1000, "done")
+            .expect_close(Some(1000)),
+    );
+    let mut ws = WebSocket::connect_with(builder, Collect::default())
+        .await
+        .unwrap();
+    ws.run().await.unwrap();
+    handle.await.unwrap().unwrap();
+    recorder.flush().await.unwrap();
+
+    let replay = Replay::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let frames: Vec<_> = replay
+        .frames()
+        .iter()
+        .map(|recorded| (recorded.direction, recorded.frame.headers.opcode))
+        .collect();
+    assert_eq!(
+        frames,
+        [
+            (Direction::Sent, Opcode::Text),
+            (Direction::Received, Opcode::Text),
+            (Direction::Received, Opcode::Binary),
+            (Direction::Received, Opcode::Close),
+            (Direction::Sent, Opcode::Close),
+        ]
+    );
+
+    let mut protocol = Collect::default();
+    replay.run(&mut protocol).await;
+    assert_eq!(
+        protocol.messages,
+        [
+            (Opcode::Text, b"tick 1".to_vec()),
+            (Opcode::Binary, vec![0, 159, 146, 150]),
+        ]
+    );
+    assert_eq!(protocol.close_code, Some(1000));
+}
+
+#[tokio::test]
+async fn records_streamed_messages() {
+    let path = std::env::temp_dir().join(format!("mayuri-streamed-{}.jsonl", std::process::id()));
+    let server = MockServer::bind().await.unwrap();
+    let recorder = Recorder::create(&path).unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .stream_messages(true)
+        .recorder(recorder.clone());
+    let data: Vec<u8> = (0..150_000).map(|i| (i % 251) as u8).collect();
+    let handle = server.serve(
+        Script::new()
+            .expect_text("subscribe")
+            .send_binary(&data)
+            .send_close(1000, "")
+            .expect_close(Some(1000)),
+    );
+    let mut ws = WebSocket::connect_with(builder, Collect::default())
+        .await
+        .unwrap();
+    ws.run().await.unwrap();
+    handle.await.unwrap().unwrap();
+    recorder.flush().await.unwrap();
+
+    let replay = Replay::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let received: Vec<_> = replay
+        .frames()
+        .iter()
+        .filter(|recorded| recorded.direction == Direction::Received)
+        .map(|recorded| {
+            let headers = &recorded.frame.headers;
+            (
+                headers.opcode,
+                headers.fin,
+                recorded.frame.payload_data.len(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        received,
+        [
+            (Opcode::Binary, false, 65_536),
+            (Opcode::Continuation, false, 65_536),
+            (Opcode::Continuation, true, 18_928),
+            (Opcode::Close, true, 2),
+        ]
+    );
+
+    let mut protocol = Collect::default();
+    replay.run(&mut protocol).await;
+    assert_eq!(protocol.messages, [(Opcode::Binary, data)]);
+}
+
+#[tokio::test]
+async fn replays_with_original_timing() {
+    let recording = concat!(
+        r#"{"direction":"received","fin":true,"opcode":"text","text":"a","timestamp":1000000}"#,
+        "\n",
+        r#"{"direction":"received","fin":true,"opcode":"text","text":"b","timestamp":1100000}"#,
+        "\n",
+    );
+    let replay = Replay::from_reader(recording.as_bytes()).unwrap();
+
+    let started = Instant::now();
+    replay.run(&mut Collect::default()).await;
+    assert!(started.elapsed() < Duration::from_millis(100));
+
+    let started = Instant::now();
+    let mut protocol = Collect::default();
+    replay.realtime(true).run(&mut protocol).await;
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    assert_eq!(protocol.messages.len(), 2);
+}
+
+#[tokio::test]
+async fn replays_fragmented_messages_whole() {
+    let recording = concat!(
+        r#"{"direction":"received","fin":false,"opcode":"text","text":"hel","timestamp":1}"#,
+        "\n",
+        r#"{"base64":"","direction":"received","fin":true,"opcode":"ping","timestamp":2}"#,
+        "\n",
+        r#"{"base64":"bG8=","direction":"received","fin":true,"opcode":"continuation","timestamp":3}"#,
+        "\n",
+        r#"{"base64":"A+g=","direction":"received","fin":true,"opcode":"close","timestamp":4}"#,
+        "\n",
+    );
+    let replay = Replay::from_reader(recording.as_bytes()).unwrap();
+
+    let mut protocol = Collect::default();
+    replay.clone().frame_hook(true).run(&mut protocol).await;
+    assert_eq!(
+        protocol.messages,
+        [
+            (Opcode::Ping, Vec::new()),
+            (Opcode::Text, b"hello".to_vec())
+        ]
+    );
+    assert_eq!(
+        protocol.frames,
+        [
+            (Opcode::Text, false),
+            (Opcode::Ping, true),
+            (Opcode::Continuation, true),
+            (Opcode::Close, true),
+        ]
+    );
+    assert_eq!(protocol.close_code, Some(1000));
+
+    // Without the hook only messages are delivered.
+    let mut protocol = Collect::default();
+    replay.run(&mut protocol).await;
+    assert_eq!(protocol.messages.len(), 2);
+    assert!(protocol.frames.is_empty());
+}
+
+#[tokio::test]
+async fn stops_replaying_at_a_protocol_violation() {
+    let recording = concat!(
+        r#"{"direction":"received","fin":true,"opcode":"text","text":"a","timestamp":1}"#,
+        "\n",
+        r#"{"base64":"Yg==","direction":"received","fin":true,"opcode":"continuation","timestamp":2}"#,
+        "\n",
+        r#"{"direction":"received","fin":true,"opcode":"text","text":"c","timestamp":3}"#,
+        "\n",
+    );
+    let mut protocol = Collect::default();
+    Replay::from_reader(recording.as_bytes())
+        .unwrap()
+        .run(&mut protocol)
+        .await;
+    assert_eq!(protocol.messages, [(Opcode::Text, b"a".to_vec())]);
+}
+
+#[test]
+fn rejects_invalid_lines() {
+    let err = Replay::from_reader(&b"\n{\"direction\":\"sideways\"}\n"[..]).unwrap_err();
+    assert!(err.to_string().contains("line 2"), "{err}");
+}
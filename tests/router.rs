@@ -0,0 +1,111 @@
+use mayuri::{
+    Router, WebSocket,
+    core::{enums::Opcode, testing::MockServer},
+};
+use tokio::sync::mpsc::unbounded_channel;
+
+#[tokio::test]
+async fn routes_messages_to_first_matching_handler() {
+    let (tx, mut routed) = unbounded_channel();
+    let (trades, greetings, binary, any_trade, fallback) =
+        (tx.clone(), tx.clone(), tx.clone(), tx.clone(), tx);
+    let router = Router::new()
+        .on_field("type", "trade", move |ctx, _transport| {
+            let trades = trades.clone();
+            async move { trades.send(format!("trade {}", ctx.read_text())).unwrap() }
+        })
+        .on_text_prefix("hello", move |_ctx, mut transport| {
+            let greetings = greetings.clone();
+            async move {
+                transport.write_text(b"hi").await.unwrap();
+                greetings.send("greeting".into()).unwrap();
+            }
+        })
+        .on_opcode(Opcode::Binary, move |ctx, _transport| {
+            let binary = binary.clone();
+            async move {
+                binary
+                    .send(format!("binary {:?}", ctx.frame.payload_data))
+                    .unwrap()
+            }
+        })
+        .on(
+            |ctx| ctx.read_text().contains("trade"),
+            move |_ctx, _transport| {
+                let any_trade = any_trade.clone();
+                async move { any_trade.send("any trade".into()).unwrap() }
+            },
+        )
+        .fallback(move |ctx, _transport| {
+            let fallback = fallback.clone();
+            async move {
+                fallback
+                    .send(format!("fallback {}", ctx.read_text()))
+                    .unwrap()
+            }
+        });
+
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", router),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let client = tokio::spawn(async move { ws.run().await });
+
+    server.send_text(r#"{"type":"trade"}"#).await.unwrap();
+    assert_eq!(routed.recv().await.unwrap(), r#"trade {"type":"trade"}"#);
+    server.send_text("hello there").await.unwrap();
+    assert_eq!(server.expect_text().await.unwrap(), "hi");
+    assert_eq!(routed.recv().await.unwrap(), "greeting");
+    server.send_binary(&[1, 2]).await.unwrap();
+    assert_eq!(routed.recv().await.unwrap(), "binary [1, 2]");
+    server
+        .send_text(r#"{"type":"quote","note":"trade"}"#)
+        .await
+        .unwrap();
+    assert_eq!(routed.recv().await.unwrap(), "any trade");
+    server.send_text("other").await.unwrap();
+    assert_eq!(routed.recv().await.unwrap(), "fallback other");
+
+    server.send_close(1000, "").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    client.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn runs_the_connect_and_close_hooks() {
+    let (tx, mut events) = unbounded_channel();
+    let (connected, closed) = (tx.clone(), tx);
+    let router = Router::new()
+        .on_connect(move |mut transport| {
+            let connected = connected.clone();
+            async move {
+                transport.write_text(b"subscribe").await.unwrap();
+                connected.send("connected".to_string()).unwrap();
+            }
+        })
+        .on_close(move |ctx, _transport| {
+            let closed = closed.clone();
+            async move {
+                closed
+                    .send(format!("closed {:?}", ctx.frame.close_code()))
+                    .unwrap()
+            }
+        });
+
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", router),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let client = tokio::spawn(async move { ws.run().await });
+    assert_eq!(server.expect_text().await.unwrap(), "subscribe");
+    assert_eq!(events.recv().await.unwrap(), "connected");
+
+    server.send_close(4000, "bye").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(4000));
+    client.await.unwrap().unwrap();
+    assert_eq!(events.recv().await.unwrap(), "closed Some(4000)");
+}
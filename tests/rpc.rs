@@ -0,0 +1,217 @@
+use mayuri::{
+    Context, OverflowPolicy, RpcClient, StreamBuilder, Transport, WebSocket, WebSocketProtocol,
+    async_trait::async_trait,
+    core::{
+        errors::RpcError,
+        testing::{Idle, MockConnection, MockServer},
+        utils::get_uri,
+    },
+};
+use serde_json::{Value, json};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::JoinHandle,
+};
+
+// Hands messages to the client and forwards whatever it doesn't handle.
+struct Rpc {
+    client: Arc<OnceLock<RpcClient>>,
+    unhandled: UnboundedSender<String>,
+}
+
+#[async_trait]
+impl WebSocketProtocol for Rpc {
+    async fn on_connect(&mut self, _transport: Transport) {}
+
+    async fn on_message(&mut self, ctx: Context) {
+        if let Some(client) = self.client.get()
+            && !client.handle(&ctx)
+        {
+            self.unhandled.send(ctx.read_text()).unwrap();
+        }
+    }
+
+    async fn on_close(&mut self, _ctx: Context) {}
+}
+
+struct Connected {
+    client: RpcClient,
+    server: MockConnection,
+    unhandled: UnboundedReceiver<String>,
+    running: JoinHandle<()>,
+}
+
+async fn connect() -> Connected {
+    let client = Arc::new(OnceLock::new());
+    let (unhandled, unhandled_rx) = unbounded_channel();
+    let protocol = Rpc {
+        client: Arc::clone(&client),
+        unhandled,
+    };
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let mut ws = ws.unwrap();
+    let rpc = RpcClient::new(ws.transport());
+    client.set(rpc.clone()).unwrap();
+    let running = tokio::spawn(async move {
+        let _ = ws.run().await;
+    });
+    Connected {
+        client: rpc,
+        server: server.unwrap(),
+        unhandled: unhandled_rx,
+        running,
+    }
+}
+
+async fn expect_request(server: &mut MockConnection) -> Value {
+    serde_json::from_str(&server.expect_text().await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn calls_return_the_matching_result() {
+    let Connected {
+        client, mut server, ..
+    } = connect().await;
+    let first = tokio::spawn({
+        let client = client.clone();
+        async move { client.call::<i64>("add", [1, 2]).await }
+    });
+    let add = expect_request(&mut server).await;
+    let second = tokio::spawn({
+        let client = client.clone();
+        async move { client.call::<String>("echo", ["hi"]).await }
+    });
+    let echo = expect_request(&mut server).await;
+    assert_eq!(add["jsonrpc"], "2.0");
+    assert_eq!(add["method"], "add");
+    assert_eq!(add["params"], json!([1, 2]));
+    assert_ne!(add["id"], echo["id"]);
+
+    // Answered out of order, each call still gets its own result.
+    let replies = json!([
+        {"jsonrpc": "2.0", "id": echo["id"], "result": "hi"},
+        {"jsonrpc": "2.0", "id": add["id"], "result": 3},
+    ]);
+    server.send_text(&replies.to_string()).await.unwrap();
+    assert_eq!(first.await.unwrap().unwrap(), 3);
+    assert_eq!(second.await.unwrap().unwrap(), "hi");
+}
+
+#[tokio::test]
+async fn calls_fail_with_the_remote_error() {
+    let Connected {
+        client, mut server, ..
+    } = connect().await;
+    let call = tokio::spawn(async move { client.call::<Value>("missing", ()).await });
+    let request = expect_request(&mut server).await;
+    assert!(request.get("params").is_none());
+
+    let reply = json!({
+        "jsonrpc": "2.0",
+        "id": request["id"],
+        "error": {"code": -32601, "message": "Method not found", "data": "missing"},
+    });
+    server.send_text(&reply.to_string()).await.unwrap();
+    assert!(matches!(
+        call.await.unwrap(),
+        Err(RpcError::Remote { code: -32601, message, data: Some(data) })
+            if message == "Method not found" && data == "missing"
+    ));
+}
+
+#[tokio::test]
+async fn calls_time_out_and_ignore_late_responses() {
+    let Connected {
+        client,
+        mut server,
+        mut unhandled,
+        ..
+    } = connect().await;
+    let limit = Duration::from_millis(50);
+    let call =
+        tokio::spawn(async move { client.call_with_timeout::<i64>("slow", (), limit).await });
+    let request = expect_request(&mut server).await;
+    assert!(matches!(call.await.unwrap(), Err(RpcError::Timeout(timeout)) if timeout == limit));
+
+    let late = json!({"jsonrpc": "2.0", "id": request["id"], "result": 1}).to_string();
+    server.send_text(&late).await.unwrap();
+    assert_eq!(unhandled.recv().await.unwrap(), late);
+}
+
+#[tokio::test]
+async fn routes_notifications_to_the_handler() {
+    let Connected {
+        client,
+        mut server,
+        mut unhandled,
+        ..
+    } = connect().await;
+    let (tx, mut notifications) = unbounded_channel();
+    client.on_notification(move |method, params| tx.send((method.to_string(), params)).unwrap());
+
+    server
+        .send_text(r#"{"jsonrpc":"2.0","method":"tick","params":{"n":1}}"#)
+        .await
+        .unwrap();
+    assert_eq!(
+        notifications.recv().await.unwrap(),
+        ("tick".to_string(), json!({"n": 1}))
+    );
+
+    // Requests from the server and other messages are left to the protocol.
+    let request = r#"{"jsonrpc":"2.0","id":7,"method":"ask"}"#;
+    server.send_text(request).await.unwrap();
+    assert_eq!(unhandled.recv().await.unwrap(), request);
+    server.send_text("not json").await.unwrap();
+    assert_eq!(unhandled.recv().await.unwrap(), "not json");
+
+    client.notify("ack", [1]).await.unwrap();
+    let sent = expect_request(&mut server).await;
+    assert_eq!(sent["method"], "ack");
+    assert!(sent.get("id").is_none());
+    assert!(notifications.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn pending_calls_fail_when_the_connection_closes() {
+    let Connected {
+        client,
+        mut server,
+        running,
+        ..
+    } = connect().await;
+    let call = tokio::spawn(async move { client.call::<Value>("never", ()).await });
+    expect_request(&mut server).await;
+
+    server.send_close(1000, "").await.unwrap();
+    running.await.unwrap();
+    assert!(matches!(call.await.unwrap(), Err(RpcError::Closed)));
+}
+
+#[tokio::test]
+async fn calls_fail_while_the_connection_is_closed() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .outbound_queue(4, OverflowPolicy::Error)
+        .unwrap();
+    let (ws, connection) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    let (mut ws, mut connection) = (ws.unwrap(), connection.unwrap());
+    let client = RpcClient::new(ws.transport());
+
+    connection.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    let result = client
+        .call_with_timeout::<Value>("queued", (), Duration::from_secs(30))
+        .await;
+    assert!(matches!(result, Err(RpcError::Closed)));
+    assert_eq!(ws.transport().queued(), 0);
+}
@@ -0,0 +1,227 @@
+use mayuri::{
+    State, StreamBuilder, WebSocket, WebSocketError,
+    core::{
+        errors::ConnectionError,
+        testing::{Idle, MockConnection, MockServer, encode_frame},
+        utils::get_uri,
+    },
+};
+use std::time::Duration;
+use tokio::sync::watch;
+
+async fn connect() -> (WebSocket<Idle>, MockConnection) {
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", Idle),
+        acceptor.accept()
+    );
+    (ws.unwrap(), server.unwrap())
+}
+
+async fn connect_with(
+    options: impl FnOnce(StreamBuilder) -> StreamBuilder,
+) -> (WebSocket<Idle>, MockConnection) {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None).unwrap();
+    let (ws, connection) = tokio::join!(
+        WebSocket::connect_with(options(builder), Idle),
+        server.accept()
+    );
+    (ws.unwrap(), connection.unwrap())
+}
+
+// Runs the event loop, which must fail, and checks subscribers were told so.
+async fn fails(ws: &mut WebSocket<Idle>, states: &mut watch::Receiver<State>) {
+    assert!(ws.run().await.is_err());
+    assert_eq!(changed(states), Some(State::ERROR));
+    assert_eq!(ws.state(), State::ERROR);
+}
+
+// The state the receiver was last told about, if it changed since.
+fn changed(states: &mut watch::Receiver<State>) -> Option<State> {
+    states
+        .has_changed()
+        .unwrap()
+        .then(|| *states.borrow_and_update())
+}
+
+#[tokio::test]
+async fn moves_through_the_closing_handshake() {
+    let (mut ws, mut server) = connect().await;
+    let mut transport = ws.transport();
+    let mut states = transport.subscribe();
+    assert_eq!(*states.borrow_and_update(), State::OPEN);
+
+    transport.close(1000, "").await.unwrap();
+    assert_eq!(changed(&mut states), Some(State::CLOSING));
+    assert_eq!(transport.state(), State::CLOSING);
+
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    server.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    assert_eq!(changed(&mut states), Some(State::CLOSED));
+    assert_eq!(ws.state(), State::CLOSED);
+}
+
+#[tokio::test]
+async fn closes_when_the_server_closes_first() {
+    let (mut ws, mut server) = connect().await;
+    let mut states = ws.transport().subscribe();
+
+    server.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    assert_eq!(changed(&mut states), Some(State::CLOSED));
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+}
+
+#[tokio::test]
+async fn reports_errors_of_the_event_loop() {
+    let (mut ws, server) = connect().await;
+    let mut states = ws.transport().subscribe();
+
+    server.disconnect().await.unwrap();
+    assert!(ws.run().await.is_err());
+    assert_eq!(changed(&mut states), Some(State::ERROR));
+    assert_eq!(changed(&mut states), None);
+}
+
+#[tokio::test]
+async fn reports_idle_timeouts() {
+    let (mut ws, _server) =
+        connect_with(|builder| builder.read_idle_timeout(Duration::from_millis(50))).await;
+    let mut states = ws.transport().subscribe();
+    fails(&mut ws, &mut states).await;
+}
+
+#[tokio::test]
+async fn reports_protocol_violations() {
+    let (mut ws, mut server) = connect().await;
+    let mut states = ws.transport().subscribe();
+    // RSV1 without an extension owning it.
+    let mut frame = encode_frame(true, 0x1, b"?");
+    frame[0] |= 0x40;
+    server.send_raw(&frame).await.unwrap();
+    fails(&mut ws, &mut states).await;
+    assert_eq!(server.expect_close().await.unwrap(), Some(1002));
+}
+
+#[tokio::test]
+async fn reports_invalid_utf8() {
+    let (mut ws, mut server) = connect().await;
+    let mut states = ws.transport().subscribe();
+    server.send_frame(true, 0x1, &[0xFF]).await.unwrap();
+    fails(&mut ws, &mut states).await;
+    assert_eq!(server.expect_close().await.unwrap(), Some(1007));
+}
+
+#[tokio::test]
+async fn reports_messages_too_big() {
+    let (mut ws, mut server) = connect_with(|builder| builder.max_message_size(4)).await;
+    let mut states = ws.transport().subscribe();
+    server.send_text("too long").await.unwrap();
+    fails(&mut ws, &mut states).await;
+    assert_eq!(server.expect_close().await.unwrap(), Some(1009));
+}
+
+#[tokio::test]
+async fn reports_disconnects_mid_payload() {
+    let (mut ws, server) = connect().await;
+    let mut states = ws.transport().subscribe();
+    server
+        .disconnect_mid_frame(0x1, b"cut short", 4)
+        .await
+        .unwrap();
+    fails(&mut ws, &mut states).await;
+}
+
+#[tokio::test]
+async fn sends_no_data_after_the_close_frame() {
+    let (mut ws, mut server) = connect().await;
+    let mut transport = ws.transport();
+
+    transport.close(1000, "").await.unwrap();
+    assert!(transport.write_text(b"late").await.is_err());
+    // Control frames still go out.
+    transport.ping(b"").await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(1000));
+    assert!(server.recv().await.unwrap().payload.is_empty());
+    server.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    assert_eq!(ws.state(), State::CLOSED);
+}
+
+#[tokio::test]
+async fn stays_open_when_the_close_frame_cant_be_written() {
+    let (ws, server) = connect().await;
+    let mut transport = ws.transport();
+    let mut states = transport.subscribe();
+    drop(server);
+
+    assert!(transport.close(1000, "").await.is_err());
+    assert_eq!(transport.state(), State::OPEN);
+    // Subscribers saw CLOSING come and go.
+    assert_eq!(changed(&mut states), Some(State::OPEN));
+}
+
+#[tokio::test]
+async fn rejects_close_frames_that_cant_be_sent() {
+    let (ws, mut server) = connect().await;
+    let mut transport = ws.transport();
+    let long = "x".repeat(124);
+
+    for (code, reason) in [(999, ""), (1005, ""), (1006, ""), (1015, ""), (5000, "")]
+        .into_iter()
+        .chain([(1000, long.as_str())])
+    {
+        let result = transport.close(code, reason).await;
+        assert!(
+            matches!(
+                result,
+                Err(WebSocketError::Stream(ConnectionError::InvalidClose(_)))
+            ),
+            "{code}: {result:?}"
+        );
+        assert_eq!(transport.state(), State::OPEN);
+    }
+
+    transport.close(4000, &long[1..]).await.unwrap();
+    assert_eq!(server.expect_close().await.unwrap(), Some(4000));
+}
+
+#[tokio::test]
+async fn reconnects_through_connecting() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None).unwrap();
+    let (ws, first) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    let (mut ws, mut first) = (ws.unwrap(), first.unwrap());
+    let mut states = ws.transport().subscribe();
+
+    first.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    assert_eq!(changed(&mut states), Some(State::CLOSED));
+
+    let (reconnected, connecting) = tokio::join!(ws.reconnect(), async {
+        // The handshake waits for the accept, so CONNECTING is seen first.
+        states.changed().await.unwrap();
+        let connecting = *states.borrow_and_update();
+        server.accept().await.unwrap();
+        connecting
+    });
+    reconnected.unwrap();
+    assert_eq!(connecting, State::CONNECTING);
+    assert_eq!(changed(&mut states), Some(State::OPEN));
+}
+
+#[tokio::test]
+async fn failed_reconnects_end_closed() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None).unwrap();
+    let (ws, first) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    let (mut ws, mut first) = (ws.unwrap(), first.unwrap());
+
+    first.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    drop(server);
+    assert!(ws.reconnect().await.is_err());
+    assert_eq!(ws.state(), State::CLOSED);
+}
@@ -0,0 +1,189 @@
+use mayuri::{
+    StreamBuilder, WebSocket,
+    core::{
+        enums::Opcode,
+        stats::{self, FrameCounts, StatsSnapshot},
+        testing::{Idle, MockServer},
+        utils::get_uri,
+    },
+};
+use std::time::{Duration, SystemTime};
+
+#[tokio::test]
+async fn counts_frames_bytes_and_messages() {
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", Idle),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let mut transport = ws.transport();
+
+    transport.write_text(b"hello").await.unwrap();
+    transport
+        .send_fragmented(Opcode::Binary, [&[1u8, 2][..], &[3]])
+        .await
+        .unwrap();
+    server.send_text("hi").await.unwrap();
+    server.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+
+    let stats = ws.stats();
+    assert_eq!(
+        stats.frames_sent,
+        FrameCounts {
+            text: 1,
+            binary: 1,
+            continuation: 1,
+            close: 1,
+            ..FrameCounts::default()
+        }
+    );
+    assert_eq!(
+        stats.frames_received,
+        FrameCounts {
+            text: 1,
+            close: 1,
+            ..FrameCounts::default()
+        }
+    );
+    assert_eq!((stats.messages_sent, stats.messages_received), (2, 1));
+    // Client frames carry a 4-byte mask on top of the 2-byte header.
+    assert_eq!(stats.bytes_sent, (6 + 5) + (6 + 2) + (6 + 1) + (6 + 2));
+    assert_eq!(stats.bytes_received, (2 + 2) + (2 + 2));
+    assert!(stats.connected_since.is_some());
+    assert!(stats.last_activity >= stats.connected_since);
+}
+
+#[tokio::test]
+async fn measures_ping_round_trips() {
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", Idle),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    let mut transport = ws.transport();
+    let client = tokio::spawn(async move {
+        ws.run().await.unwrap();
+        ws.stats()
+    });
+
+    transport.ping(b"rtt").await.unwrap();
+    let ping = server.recv().await.unwrap();
+    assert_eq!(ping.opcode, Opcode::Ping);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    server
+        .send_frame(true, Opcode::Pong as u8, &ping.payload)
+        .await
+        .unwrap();
+    server.send_close(1000, "").await.unwrap();
+
+    let rtt = client.await.unwrap().ping_rtt.unwrap();
+    assert!(rtt >= Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn keeps_counting_across_reconnects() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None).unwrap();
+    let (ws, first) = tokio::join!(WebSocket::connect_with(builder, Idle), server.accept());
+    let (mut ws, mut first) = (ws.unwrap(), first.unwrap());
+    let mut transport = ws.transport();
+
+    transport.write_text(b"one").await.unwrap();
+    first.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    // Fails on the closed connection, so the Pong below answers nothing.
+    assert!(transport.ping(b"lost").await.is_err());
+
+    let (reconnected, second) = tokio::join!(ws.reconnect(), server.accept());
+    reconnected.unwrap();
+    let mut second = second.unwrap();
+    transport.write_text(b"two").await.unwrap();
+    second
+        .send_frame(true, Opcode::Pong as u8, b"lost")
+        .await
+        .unwrap();
+    second.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+    assert_eq!(second.expect_text().await.unwrap(), "two");
+
+    let stats = ws.stats();
+    assert_eq!(stats.reconnects, 1);
+    assert_eq!(stats.messages_sent, 2);
+    assert_eq!(stats.frames_received.pong, 1);
+    assert_eq!(stats.ping_rtt, None);
+}
+
+#[test]
+fn renders_prometheus_families_once() {
+    let stats = StatsSnapshot {
+        bytes_sent: 10,
+        frames_received: FrameCounts {
+            text: 3,
+            ..FrameCounts::default()
+        },
+        ping_rtt: Some(Duration::from_micros(1500)),
+        connected_since: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(60)),
+        ..StatsSnapshot::default()
+    };
+    let text = stats.to_prometheus("feed \"a\"");
+
+    assert!(text.contains("# HELP mayuri_bytes_sent_total "));
+    assert!(text.contains("# TYPE mayuri_bytes_sent_total counter\n"));
+    assert!(text.contains("mayuri_bytes_sent_total{connection=\"feed \\\"a\\\"\"} 10\n"));
+    assert!(text.contains(
+        "mayuri_frames_received_total{connection=\"feed \\\"a\\\"\",opcode=\"Text\"} 3\n"
+    ));
+    assert!(text.contains("# TYPE mayuri_ping_rtt_seconds gauge\n"));
+    assert!(text.contains("mayuri_ping_rtt_seconds{connection=\"feed \\\"a\\\"\"} 0.0015\n"));
+    assert!(text.contains("mayuri_connected_since_seconds{connection=\"feed \\\"a\\\"\"} 60\n"));
+    assert!(!text.contains("mayuri_last_activity_seconds"));
+    assert_eq!(
+        text.matches("# TYPE mayuri_frames_received_total counter")
+            .count(),
+        1
+    );
+    // Every sample follows the TYPE line of its family.
+    let mut family = "";
+    for line in text.lines() {
+        if let Some(declared) = line.strip_prefix("# TYPE ") {
+            family = declared.split(' ').next().unwrap();
+        } else if !line.starts_with('#') {
+            assert!(line.starts_with(&format!("{family}{{")), "{line}");
+        }
+    }
+}
+
+#[test]
+fn renders_several_connections_under_one_family() {
+    let feed = StatsSnapshot {
+        bytes_sent: 10,
+        ..StatsSnapshot::default()
+    };
+    let orders = StatsSnapshot {
+        bytes_sent: 20,
+        ping_rtt: Some(Duration::from_millis(250)),
+        ..StatsSnapshot::default()
+    };
+    let text = stats::to_prometheus([("feed", &feed), ("orders\nbook", &orders)]);
+
+    assert_eq!(text.matches("# HELP mayuri_bytes_sent_total ").count(), 1);
+    assert_eq!(
+        text.matches("# TYPE mayuri_bytes_sent_total counter")
+            .count(),
+        1
+    );
+    assert!(text.contains(concat!(
+        "mayuri_bytes_sent_total{connection=\"feed\"} 10\n",
+        "mayuri_bytes_sent_total{connection=\"orders\\nbook\"} 20\n",
+    )));
+    assert!(text.contains("mayuri_ping_rtt_seconds{connection=\"orders\\nbook\"} 0.25\n"));
+    assert!(!text.contains("mayuri_ping_rtt_seconds{connection=\"feed\"}"));
+    assert_eq!(text.lines().count(), text.matches('\n').count());
+    assert!(
+        text.lines()
+            .all(|line| line.starts_with("# ") || line.starts_with("mayuri_"))
+    );
+}
@@ -0,0 +1,161 @@
+use mayuri::{
+    Context, StreamBuilder, Transport, WebSocket, WebSocketProtocol,
+    async_trait::async_trait,
+    core::{
+        enums::Opcode,
+        testing::{MockServer, Script},
+        utils::get_uri,
+    },
+};
+use std::io;
+use tokio::{
+    io::AsyncReadExt,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+
+type Body = (Opcode, io::Result<Vec<u8>>);
+
+// Reads the body of every streamed message and reports what it got.
+struct Collect {
+    bodies: UnboundedSender<Body>,
+}
+
+impl Collect {
+    fn new() -> (Self, UnboundedReceiver<Body>) {
+        let (bodies, rx) = unbounded_channel();
+        (Self { bodies }, rx)
+    }
+}
+
+#[async_trait]
+impl WebSocketProtocol for Collect {
+    async fn on_connect(&mut self, _transport: Transport) {}
+
+    async fn on_message(&mut self, mut ctx: Context) {
+        let Some(mut body) = ctx.body() else {
+            return;
+        };
+        let mut data = Vec::new();
+        let result = body.read_to_end(&mut data).await.map(|_| data);
+        self.bodies
+            .send((ctx.frame.headers.opcode, result))
+            .unwrap();
+    }
+
+    async fn on_close(&mut self, _ctx: Context) {}
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn builder(server: &MockServer) -> StreamBuilder {
+    StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None)
+        .unwrap()
+        .stream_messages(true)
+}
+
+#[tokio::test]
+async fn streams_received_messages() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = builder(&server);
+    let data = payload(300_000);
+    let (first, rest) = data.split_at(200_000);
+    let handle = server.serve(
+        Script::new()
+            .send_frame(false, 0x2, first)
+            .send_ping(b"mid")
+            .send_frame(true, 0x0, rest)
+            .expect_frame(Some(Opcode::Pong))
+            .send_text("after")
+            .send_close(1000, "")
+            .expect_close(Some(1000)),
+    );
+
+    let (protocol, mut bodies) = Collect::new();
+    let mut ws = WebSocket::connect_with(builder, protocol).await.unwrap();
+    ws.run().await.unwrap();
+    handle.await.unwrap().unwrap();
+
+    let (opcode, body) = bodies.recv().await.unwrap();
+    assert_eq!(opcode, Opcode::Binary);
+    assert_eq!(body.unwrap(), data);
+    let (opcode, body) = bodies.recv().await.unwrap();
+    assert_eq!(opcode, Opcode::Text);
+    assert_eq!(body.unwrap(), b"after");
+}
+
+#[tokio::test]
+async fn fails_body_when_connection_ends_mid_message() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = builder(&server);
+    let handle = server.serve(Script::new().send_frame(false, 0x1, b"never ").disconnect());
+
+    let (protocol, mut bodies) = Collect::new();
+    let mut ws = WebSocket::connect_with(builder, protocol).await.unwrap();
+    assert!(ws.run().await.is_err());
+    handle.await.unwrap().unwrap();
+
+    let (_, body) = bodies.recv().await.unwrap();
+    assert_eq!(body.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[tokio::test]
+async fn fails_on_invalid_utf8_in_a_streamed_text() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = builder(&server);
+    // `é` split between two frames, then a byte that's never valid.
+    let handle = server.serve(
+        Script::new()
+            .send_frame(false, 0x1, b"caf\xC3")
+            .send_frame(false, 0x0, b"\xA9 ")
+            .send_frame(false, 0x0, b"\xFF")
+            .expect_close(Some(1007)),
+    );
+
+    let (protocol, mut bodies) = Collect::new();
+    let mut ws = WebSocket::connect_with(builder, protocol).await.unwrap();
+    assert!(ws.run().await.is_err());
+    handle.await.unwrap().unwrap();
+
+    let (_, body) = bodies.recv().await.unwrap();
+    assert_eq!(body.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[tokio::test]
+async fn sends_streams_in_fragments() {
+    let (io, acceptor) = MockServer::duplex();
+    let (protocol, _bodies) = Collect::new();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (ws, mut server) = (ws.unwrap(), server.unwrap());
+    let data = payload(150_000);
+
+    let mut transport = ws.transport();
+    let body = data.clone();
+    let sender =
+        tokio::spawn(async move { transport.send_stream(Opcode::Binary, &body[..]).await });
+
+    let mut received = Vec::new();
+    let mut frames = Vec::new();
+    loop {
+        let frame = server.recv().await.unwrap();
+        frames.push((frame.opcode, frame.fin, frame.payload.len()));
+        received.extend_from_slice(&frame.payload);
+        if frame.fin {
+            break;
+        }
+    }
+    sender.await.unwrap().unwrap();
+    assert_eq!(
+        frames,
+        [
+            (Opcode::Binary, false, 65_536),
+            (Opcode::Continuation, false, 65_536),
+            (Opcode::Continuation, true, 18_928),
+        ]
+    );
+    assert_eq!(received, data);
+}
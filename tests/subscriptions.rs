@@ -0,0 +1,170 @@
+use mayuri::{
+    Context, StreamBuilder, Transport, WebSocket, WebSocketProtocol,
+    async_trait::async_trait,
+    core::{
+        subscriptions::{JsonEncoder, Subscriptions},
+        testing::{MockConnection, MockServer},
+        utils::get_uri,
+    },
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+// Resubscribes on every connect, like the docs of `Subscriptions` suggest.
+struct Subscribed {
+    subscriptions: Subscriptions,
+    attached: UnboundedSender<()>,
+    unhandled: UnboundedSender<String>,
+}
+
+#[async_trait]
+impl WebSocketProtocol for Subscribed {
+    async fn on_connect(&mut self, transport: Transport) {
+        self.subscriptions.attach(transport).await.unwrap();
+        self.attached.send(()).unwrap();
+    }
+
+    async fn on_message(&mut self, ctx: Context) {
+        if !self.subscriptions.dispatch(&ctx) {
+            self.unhandled.send(ctx.read_text()).unwrap();
+        }
+    }
+
+    async fn on_close(&mut self, _ctx: Context) {}
+}
+
+struct Channels {
+    attached: UnboundedReceiver<()>,
+    unhandled: UnboundedReceiver<String>,
+}
+
+fn subscribed(subscriptions: &Subscriptions) -> (Subscribed, Channels) {
+    let (attached, attached_rx) = unbounded_channel();
+    let (unhandled, unhandled_rx) = unbounded_channel();
+    let protocol = Subscribed {
+        subscriptions: subscriptions.clone(),
+        attached,
+        unhandled,
+    };
+    let channels = Channels {
+        attached: attached_rx,
+        unhandled: unhandled_rx,
+    };
+    (protocol, channels)
+}
+
+fn subscribe(topic: &str) -> String {
+    format!(r#"{{"op":"subscribe","topic":"{topic}"}}"#)
+}
+
+fn unsubscribe(topic: &str) -> String {
+    format!(r#"{{"op":"unsubscribe","topic":"{topic}"}}"#)
+}
+
+async fn expect_texts(server: &mut MockConnection, count: usize) -> Vec<String> {
+    let mut texts = Vec::new();
+    for _ in 0..count {
+        texts.push(server.expect_text().await.unwrap());
+    }
+    texts.sort_unstable();
+    texts
+}
+
+#[tokio::test]
+async fn sends_subscriptions_made_before_connecting() {
+    let subscriptions = Subscriptions::new(JsonEncoder::default());
+    subscriptions.subscribe("trades", |_| {}).await.unwrap();
+    subscriptions.subscribe("book", |_| {}).await.unwrap();
+    let (protocol, mut channels) = subscribed(&subscriptions);
+
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (_ws, mut server) = (ws.unwrap(), server.unwrap());
+    channels.attached.recv().await.unwrap();
+    assert_eq!(
+        expect_texts(&mut server, 2).await,
+        [subscribe("book"), subscribe("trades")]
+    );
+    assert_eq!(subscriptions.topics(), ["book", "trades"]);
+}
+
+#[tokio::test]
+async fn subscribes_routes_and_unsubscribes() {
+    let subscriptions = Subscriptions::new(JsonEncoder::default());
+    let (protocol, mut channels) = subscribed(&subscriptions);
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", protocol),
+        acceptor.accept()
+    );
+    let (mut ws, mut server) = (ws.unwrap(), server.unwrap());
+    tokio::spawn(async move { ws.run().await });
+    channels.attached.recv().await.unwrap();
+
+    let (tx, mut routed) = unbounded_channel();
+    subscriptions
+        .subscribe("trades", move |ctx| tx.send(ctx.read_text()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(server.expect_text().await.unwrap(), subscribe("trades"));
+    // Only replaces the handler of an active topic.
+    let (tx, mut replaced) = unbounded_channel();
+    subscriptions
+        .subscribe("trades", move |ctx| tx.send(ctx.read_text()).unwrap())
+        .await
+        .unwrap();
+
+    let trade = r#"{"topic":"trades","price":1}"#;
+    server.send_text(trade).await.unwrap();
+    assert_eq!(replaced.recv().await.unwrap(), trade);
+    let other = r#"{"topic":"book"}"#;
+    server.send_text(other).await.unwrap();
+    assert_eq!(channels.unhandled.recv().await.unwrap(), other);
+    assert!(routed.try_recv().is_err());
+
+    assert!(subscriptions.unsubscribe("trades").await.unwrap());
+    assert!(!subscriptions.unsubscribe("trades").await.unwrap());
+    assert_eq!(server.expect_text().await.unwrap(), unsubscribe("trades"));
+    server.send_text(trade).await.unwrap();
+    assert_eq!(channels.unhandled.recv().await.unwrap(), trade);
+}
+
+#[tokio::test]
+async fn resubscribes_after_reconnecting() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None).unwrap();
+    let subscriptions = Subscriptions::new(JsonEncoder::default());
+    let (protocol, mut channels) = subscribed(&subscriptions);
+    let (ws, first) = tokio::join!(WebSocket::connect_with(builder, protocol), server.accept());
+    let (mut ws, mut first) = (ws.unwrap(), first.unwrap());
+    channels.attached.recv().await.unwrap();
+
+    subscriptions.subscribe("trades", |_| {}).await.unwrap();
+    subscriptions.subscribe("book", |_| {}).await.unwrap();
+    subscriptions.subscribe("ticker", |_| {}).await.unwrap();
+    assert!(subscriptions.unsubscribe("ticker").await.unwrap());
+    assert_eq!(
+        expect_texts(&mut first, 4).await,
+        [
+            subscribe("book"),
+            subscribe("ticker"),
+            subscribe("trades"),
+            unsubscribe("ticker")
+        ]
+    );
+    first.send_close(1000, "").await.unwrap();
+    ws.run().await.unwrap();
+
+    // Subscribing while disconnected is only recorded.
+    subscriptions.subscribe("news", |_| {}).await.unwrap();
+    let (reconnected, second) = tokio::join!(ws.reconnect(), server.accept());
+    reconnected.unwrap();
+    let mut second = second.unwrap();
+    channels.attached.recv().await.unwrap();
+    assert_eq!(
+        expect_texts(&mut second, 3).await,
+        [subscribe("book"), subscribe("news"), subscribe("trades")]
+    );
+}
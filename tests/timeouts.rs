@@ -0,0 +1,108 @@
+use mayuri::{
+    Resolver, StreamBuilder, WebSocket, WebSocketError,
+    async_trait::async_trait,
+    core::{
+        errors::TimeoutError,
+        testing::{Idle, MockServer, Script, encode_frame},
+        utils::get_uri,
+    },
+};
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+
+const LIMIT: Duration = Duration::from_millis(100);
+
+// Never answers.
+struct Hanging;
+
+#[async_trait]
+impl Resolver for Hanging {
+    async fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+        std::future::pending().await
+    }
+}
+
+fn builder(server: &MockServer) -> StreamBuilder {
+    StreamBuilder::new(get_uri(server.uri().unwrap()).unwrap(), None).unwrap()
+}
+
+#[tokio::test]
+async fn times_out_connecting() {
+    let uri = get_uri("ws://never.test/".into()).unwrap();
+    let builder = StreamBuilder::new(uri, None)
+        .unwrap()
+        .resolver(Hanging)
+        .connect_timeout(LIMIT);
+
+    let result = WebSocket::connect_with(builder, Idle).await;
+    assert!(matches!(
+        result,
+        Err(WebSocketError::Timeout(TimeoutError::Connect(LIMIT)))
+    ));
+}
+
+#[tokio::test]
+async fn times_out_waiting_for_the_handshake_response() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let uri = get_uri(format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+    let builder = StreamBuilder::new(uri, None)
+        .unwrap()
+        .handshake_timeout(LIMIT);
+    // Accepts the connection and never answers the upgrade request.
+    let server = tokio::spawn(async move { listener.accept().await });
+
+    let result = WebSocket::connect_with(builder, Idle).await;
+    assert!(matches!(
+        result,
+        Err(WebSocketError::Timeout(TimeoutError::Handshake(LIMIT)))
+    ));
+    drop(server.await.unwrap());
+}
+
+#[tokio::test]
+async fn times_out_when_no_frame_arrives() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = builder(&server).read_idle_timeout(LIMIT);
+    let handle = server.serve(Script::new().sleep(LIMIT * 10));
+
+    let mut ws = WebSocket::connect_with(builder, Idle).await.unwrap();
+    assert!(matches!(
+        ws.run().await,
+        Err(WebSocketError::Timeout(TimeoutError::ReadIdle(LIMIT)))
+    ));
+    handle.abort();
+}
+
+#[tokio::test]
+async fn times_out_when_the_server_stalls_mid_payload() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = builder(&server).read_idle_timeout(LIMIT);
+    // Announces 1000 bytes and sends 10 of them.
+    let frame = encode_frame(true, 0x2, &[0; 1000]);
+    let handle = server.serve(Script::new().send_raw(&frame[..14]).sleep(LIMIT * 10));
+
+    let mut ws = WebSocket::connect_with(builder, Idle).await.unwrap();
+    assert!(matches!(
+        ws.run().await,
+        Err(WebSocketError::Timeout(TimeoutError::ReadIdle(LIMIT)))
+    ));
+    handle.abort();
+}
+
+#[tokio::test]
+async fn slow_payloads_dont_time_out_while_data_keeps_coming() {
+    let server = MockServer::bind().await.unwrap();
+    let builder = builder(&server).read_idle_timeout(LIMIT);
+    let mut frame = encode_frame(true, 0x1, b"trickle");
+    frame.extend(encode_frame(true, 0x8, &1000u16.to_be_bytes()));
+    // A byte every 30ms, the frames take several times the limit to arrive.
+    let handle = server.serve(
+        Script::new()
+            .send_slowly(&frame, Duration::from_millis(30))
+            .expect_close(Some(1000)),
+    );
+
+    let mut ws = WebSocket::connect_with(builder, Idle).await.unwrap();
+    ws.run().await.unwrap();
+    handle.await.unwrap().unwrap();
+}
@@ -0,0 +1,106 @@
+use mayuri::{
+    CertificatePin, StreamBuilder, WebSocket,
+    core::{
+        testing::{Idle, MockConnection},
+        utils::get_uri,
+    },
+};
+use rcgen::PublicKeyData;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::{net::TcpListener, task::JoinHandle};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::aws_lc_rs::default_provider,
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    },
+};
+
+struct TlsServer {
+    port: u16,
+    cert_pem: String,
+    cert_pin: [u8; 32],
+    spki_pin: [u8; 32],
+    handle: JoinHandle<Option<MockConnection>>,
+}
+
+// Serves one TLS client with a fresh self-signed certificate for `localhost`.
+async fn tls_server() -> TlsServer {
+    let key = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert = key.cert.der().clone();
+    let cert_pin = Sha256::digest(&cert).into();
+    let spki_pin = Sha256::digest(key.signing_key.subject_public_key_info()).into();
+    let private_key =
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.signing_key.serialize_der()));
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], private_key)
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let handle = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.ok()?;
+        let stream = acceptor.accept(stream).await.ok()?;
+        MockConnection::accept(stream).await.ok()
+    });
+    TlsServer {
+        port,
+        cert_pem: key.cert.pem(),
+        cert_pin,
+        spki_pin,
+        handle,
+    }
+}
+
+fn builder(server: &TlsServer) -> StreamBuilder {
+    let uri = get_uri(format!("wss://localhost:{}/", server.port)).unwrap();
+    StreamBuilder::new(uri, None).unwrap()
+}
+
+#[tokio::test]
+async fn accepts_matching_certificate_pin() {
+    let server = tls_server().await;
+    let builder = builder(&server)
+        .danger_accept_invalid_certs(true)
+        .pin(CertificatePin::Certificate([0; 32]))
+        .pin(CertificatePin::Certificate(server.cert_pin));
+
+    let ws = WebSocket::connect_with(builder, Idle).await;
+    assert!(ws.is_ok());
+    assert!(server.handle.await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn accepts_matching_spki_pin_on_a_trusted_chain() {
+    let server = tls_server().await;
+    let cafile = std::env::temp_dir().join(format!("mayuri-tls-{}.pem", server.port));
+    std::fs::write(&cafile, &server.cert_pem).unwrap();
+    let builder = builder(&server)
+        .cafile(&cafile)
+        .pin(CertificatePin::Spki(server.spki_pin));
+
+    let ws = WebSocket::connect_with(builder, Idle).await;
+    std::fs::remove_file(&cafile).unwrap();
+    assert!(ws.is_ok());
+    assert!(server.handle.await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn rejects_mismatched_pins() {
+    for pin in [CertificatePin::Certificate, CertificatePin::Spki] {
+        let server = tls_server().await;
+        let builder = builder(&server)
+            .danger_accept_invalid_certs(true)
+            .pin(pin([0; 32]));
+
+        let ws = WebSocket::connect_with(builder, Idle).await;
+        assert!(ws.is_err());
+        assert!(server.handle.await.unwrap().is_none());
+    }
+}
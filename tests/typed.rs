@@ -0,0 +1,121 @@
+use mayuri::{
+    Context, Transport, WebSocket, WebSocketProtocol,
+    async_trait::async_trait,
+    core::{
+        enums::Opcode,
+        errors::ParseError,
+        testing::{MockConnection, MockServer},
+    },
+};
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+type Quote = BTreeMap<String, u32>;
+
+struct Collect(UnboundedSender<Context>);
+
+#[async_trait]
+impl WebSocketProtocol for Collect {
+    async fn on_connect(&mut self, _transport: Transport) {}
+
+    async fn on_message(&mut self, ctx: Context) {
+        self.0.send(ctx).unwrap();
+    }
+
+    async fn on_close(&mut self, _ctx: Context) {}
+}
+
+async fn connect() -> (Transport, MockConnection, UnboundedReceiver<Context>) {
+    let (tx, received) = unbounded_channel();
+    let (io, acceptor) = MockServer::duplex();
+    let (ws, server) = tokio::join!(
+        WebSocket::from_stream(io, "ws://mock/", Collect(tx)),
+        acceptor.accept()
+    );
+    let mut ws = ws.unwrap();
+    let transport = ws.transport();
+    tokio::spawn(async move { ws.run().await });
+    (transport, server.unwrap(), received)
+}
+
+fn quote() -> Quote {
+    BTreeMap::from([("ask".into(), 11), ("bid".into(), 10)])
+}
+
+#[tokio::test]
+async fn sends_and_receives_json() {
+    let (mut transport, mut server, mut received) = connect().await;
+
+    transport.send_json(&quote()).await.unwrap();
+    assert_eq!(
+        server.expect_text().await.unwrap(),
+        r#"{"ask":11,"bid":10}"#
+    );
+
+    server.send_text(r#"{"ask":11,"bid":10}"#).await.unwrap();
+    let ctx = received.recv().await.unwrap();
+    assert_eq!(ctx.json::<Quote>().unwrap(), quote());
+
+    server.send_text(r#"{"ask":"eleven"}"#).await.unwrap();
+    let ctx = received.recv().await.unwrap();
+    assert!(matches!(ctx.json::<Quote>(), Err(ParseError::JsonError(_))));
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn sends_and_receives_msgpack() {
+    let (mut transport, mut server, mut received) = connect().await;
+
+    transport.send_msgpack(&quote()).await.unwrap();
+    let frame = server.recv().await.unwrap();
+    assert_eq!(frame.opcode, Opcode::Binary);
+    assert_eq!(
+        rmp_serde::from_slice::<Quote>(&frame.payload).unwrap(),
+        quote()
+    );
+
+    server
+        .send_binary(&rmp_serde::to_vec_named(&quote()).unwrap())
+        .await
+        .unwrap();
+    let ctx = received.recv().await.unwrap();
+    assert_eq!(ctx.msgpack::<Quote>().unwrap(), quote());
+
+    server.send_binary(&[0xC1]).await.unwrap();
+    let ctx = received.recv().await.unwrap();
+    assert!(matches!(
+        ctx.msgpack::<Quote>(),
+        Err(ParseError::MsgPackDecodeError(_))
+    ));
+}
+
+#[cfg(feature = "cbor")]
+#[tokio::test]
+async fn sends_and_receives_cbor() {
+    let (mut transport, mut server, mut received) = connect().await;
+
+    transport.send_cbor(&quote()).await.unwrap();
+    let frame = server.recv().await.unwrap();
+    assert_eq!(frame.opcode, Opcode::Binary);
+    let sent: Quote = ciborium::from_reader(frame.payload.as_slice()).unwrap();
+    assert_eq!(sent, quote());
+
+    let mut payload = Vec::new();
+    ciborium::into_writer(&quote(), &mut payload).unwrap();
+    server.send_binary(&payload).await.unwrap();
+    let ctx = received.recv().await.unwrap();
+    assert_eq!(ctx.cbor::<Quote>().unwrap(), quote());
+
+    server.send_binary(&[0xFF]).await.unwrap();
+    let ctx = received.recv().await.unwrap();
+    assert!(matches!(ctx.cbor::<Quote>(), Err(ParseError::CborError(_))));
+}
+
+#[tokio::test]
+async fn json_messages_are_text_frames() {
+    let (mut transport, mut server, _received) = connect().await;
+    transport.send_json(&[1, 2, 3]).await.unwrap();
+    let frame = server.recv().await.unwrap();
+    assert_eq!(frame.opcode, Opcode::Text);
+    assert_eq!(frame.payload, b"[1,2,3]");
+}